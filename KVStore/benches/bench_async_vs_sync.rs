@@ -0,0 +1,142 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use crossbeam_utils::sync::WaitGroup;
+use kvs::{
+    async_client::AsyncClient, async_server::AsyncServer, client::Client, server::Server,
+    thread_pool::SharedQueueThreadPool, AsyncKvsEngine, KvStore, KvsEngine, ThreadPool,
+};
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    thread,
+};
+
+use tempfile::TempDir;
+
+const NUM_THREADS: u32 = 4;
+const NUM_REQUEST: usize = 100;
+
+// compares the threaded server and client with the async ones, serving the
+// same concurrent requests with as many threads
+fn criterion_benchmark_async_vs_sync(c: &mut Criterion) {
+    let mut group = c.benchmark_group("async_vs_sync");
+
+    let keys: Vec<String> = (0..NUM_REQUEST)
+        .map(|x| format!("randomeKey{}", x))
+        .collect();
+
+    let value = "randomValue:rustacean".to_owned();
+
+    for write in [true, false] {
+        let op = if write { "write" } else { "read" };
+
+        group.bench_function(format!("sync-{}", op), |b| {
+            let addr: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4005);
+            let temp = TempDir::new().expect("unable to create temp directory.");
+            let engine = KvStore::open(temp.path()).expect("unable to create a new storage.");
+            for key in keys.iter() {
+                engine.set(key.clone(), value.clone()).unwrap();
+            }
+
+            let pool =
+                SharedQueueThreadPool::new(NUM_THREADS).expect("unable to create thread pool.");
+            let killed = Arc::new(AtomicBool::new(false));
+            let mut server = Server::new(engine, addr, pool, Arc::clone(&killed))
+                .expect("unable to create server.");
+            let handle = thread::spawn(move || {
+                server.run().expect("unable to run the server");
+            });
+
+            let client_pool = SharedQueueThreadPool::new(NUM_REQUEST as u32)
+                .expect("unable to create client pool.");
+
+            b.iter(|| {
+                let wg = WaitGroup::new();
+                for key in keys.iter() {
+                    let key = key.clone();
+                    let value = value.clone();
+                    let wg = wg.clone();
+                    client_pool.spawn(move || {
+                        let result = Client::new(addr).and_then(|mut client| {
+                            if write {
+                                client.set(key, value)
+                            } else {
+                                client.get(key).map(drop)
+                            }
+                        });
+                        if let Err(e) = result {
+                            eprintln!("error in executing client request: {}", e);
+                        }
+                        drop(wg);
+                    });
+                }
+                wg.wait();
+            });
+
+            killed.store(true, Ordering::SeqCst);
+
+            // IMPORTANT: create a new client to unblock the server from listener.incoming() method.
+            let _ = Client::new(addr);
+
+            if let Err(e) = handle.join() {
+                eprintln!("unable to exit server: {:?}", e);
+            }
+        });
+
+        // criterion may run a benchmark more than once, and the server has
+        // to be bound only once for all of them
+        let addr: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4006);
+        let temp = TempDir::new().expect("unable to create temp directory.");
+        let engine = KvStore::open(temp.path()).expect("unable to create a new storage.");
+        for key in keys.iter() {
+            engine.set(key.clone(), value.clone()).unwrap();
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(NUM_THREADS as usize)
+            .max_blocking_threads(NUM_THREADS as usize)
+            .enable_all()
+            .build()
+            .expect("unable to create runtime.");
+        let server = runtime
+            .block_on(AsyncServer::new(AsyncKvsEngine::new(engine), addr))
+            .expect("unable to create server.");
+        runtime.spawn(server.run());
+
+        group.bench_function(format!("async-{}", op), |b| {
+            b.to_async(&runtime).iter(|| async {
+                let requests: Vec<_> = keys
+                    .iter()
+                    .map(|key| {
+                        let key = key.clone();
+                        let value = value.clone();
+                        tokio::spawn(async move {
+                            let mut client = AsyncClient::new(addr).await?;
+                            if write {
+                                client.set(key, value).await
+                            } else {
+                                client.get(key).await.map(drop)
+                            }
+                        })
+                    })
+                    .collect();
+                for request in requests {
+                    if let Err(e) = request.await.expect("client task panicked") {
+                        eprintln!("error in executing client request: {}", e);
+                    }
+                }
+            });
+        });
+
+        // dropping the runtime stops the server, along with the connections
+        // it was still serving
+        drop(runtime);
+    }
+}
+
+criterion_group!(benches, criterion_benchmark_async_vs_sync);
+criterion_main!(benches);
@@ -4,7 +4,7 @@ use kvs::{
     client::Client,
     server::Server,
     thread_pool::{RayonThreadPool, SharedQueueThreadPool},
-    KvStore, SledKvsEngine, ThreadPool,
+    KvStore, ThreadPool,
 };
 use log::LevelFilter;
 extern crate env_logger;
@@ -59,8 +59,8 @@ fn criterion_benchmark_shared_queue(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -124,8 +124,8 @@ fn criterion_benchmark_rayon_pool(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
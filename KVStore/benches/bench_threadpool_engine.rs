@@ -59,8 +59,8 @@ fn criterion_benchmark_kvs_thread_num(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -114,8 +114,8 @@ fn criterion_benchmark_kvs_thread_num(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
@@ -182,8 +182,8 @@ fn criterion_benchmark_sled_thread_num(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -237,8 +237,8 @@ fn criterion_benchmark_sled_thread_num(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..NUM_REQUEST {
-                    let key = keys[i].clone();
+                for key in keys.iter().take(NUM_REQUEST) {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
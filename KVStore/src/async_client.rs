@@ -0,0 +1,405 @@
+use crate::client::{decode_response, into_change, remote_error};
+use crate::common::{
+    AdminResponse, BeginResponse, Bytes, CasResponse, GetResponse, IncrResponse,
+    NamespacesResponse, Request, RmResponse, ScanResponse, SetResponse, TtlResponse, TxnResponse,
+    WatchResponse, WireFormat,
+};
+use crate::error::{KVError, Result};
+use crate::frame::{self, Frame, Opcode};
+use crate::{CasOutcome, Change, WatchFilter};
+
+use serde::de::DeserializeOwned;
+
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncWriteExt, BufReader, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+// AsyncClient is the async counterpart of Client: it speaks the same framed
+// protocol to Server or AsyncServer, and every request returns a future that
+// resolves to its response.
+pub struct AsyncClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    // the namespace requests run in, None for the default one
+    namespace: Option<String>,
+    // the id of the last request sent, which its response has to carry
+    last_id: u32,
+}
+
+impl AsyncClient {
+    pub async fn new(addr: SocketAddr) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        frame::client_handshake_async(&mut reader, &mut writer).await?;
+
+        Ok(Self {
+            reader,
+            writer,
+            namespace: None,
+            last_id: 0,
+        })
+    }
+
+    // with_namespace makes the client work on the keys of namespace instead
+    // of the default ones
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<String> {
+        match self.get_bytes(key.into_bytes()).await? {
+            Some(content) => Ok(String::from_utf8(content)?),
+            None => Ok(KVError::KeyNoExist.to_string()),
+        }
+    }
+
+    // get_bytes returns the value of a binary key, or None if it does not exist
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::Get { key: Bytes(key) }).await? {
+            GetResponse::Ok(content) => Ok(content.map(Vec::from)),
+            GetResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request_set(key, value, None).await
+    }
+
+    // set_with_ttl sets a key that expires once ttl has passed
+    pub async fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.request_set(
+            key.into_bytes(),
+            value.into_bytes(),
+            Some(ttl.as_millis() as u64),
+        )
+        .await
+    }
+
+    async fn request_set(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl_ms: Option<u64>,
+    ) -> Result<()> {
+        let request = Request::Set {
+            key: Bytes(key),
+            value: Bytes(value),
+            ttl_ms,
+        };
+
+        match self.request(request).await? {
+            SetResponse::Ok() => Ok(()),
+            SetResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // ttl returns the lifetime a key has left, or None if it never expires.
+    // A missing key is reported as KVError::KeyNoExist.
+    pub async fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        match self.request(Request::Ttl { key: key.into() }).await? {
+            TtlResponse::Ok(ttl) => Ok(ttl.map(Duration::from_millis)),
+            TtlResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes()).await
+    }
+
+    pub async fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.request(Request::Remove { key: Bytes(key) }).await? {
+            RmResponse::Ok() => Ok(()),
+            RmResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // scan lists the pairs with keys from start up to, but excluding, end
+    pub async fn scan(
+        &mut self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        self.request_scan(Request::Scan {
+            start: start.map(Bytes::from),
+            end: end.map(Bytes::from),
+            limit,
+            reverse,
+        })
+        .await
+    }
+
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: String,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        self.request_scan(Request::ScanPrefix {
+            prefix: prefix.into(),
+            limit,
+            reverse,
+        })
+        .await
+    }
+
+    // request_scan sends request, and a request for the rest of the scan
+    // for as long as the server answers with partial pages
+    async fn request_scan(&mut self, request: Request) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut next = Some(request);
+        while let Some(request) = next.take() {
+            let page = match self.request(request.clone()).await? {
+                ScanResponse::Ok(page) => page,
+                ScanResponse::Partial(page) => {
+                    if let Some((last, _)) = page.last() {
+                        next = request.resume_scan(&last.0, page.len());
+                    }
+                    page
+                }
+                ScanResponse::Err(e) => return Err(KVError::String(e)),
+            };
+            for (key, value) in page {
+                pairs.push((String::from_utf8(key.0)?, String::from_utf8(value.0)?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub async fn set_if_absent(&mut self, key: String, value: String) -> Result<CasOutcome> {
+        self.request_cas(Request::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        })
+        .await
+    }
+
+    pub async fn set_if_equals(
+        &mut self,
+        key: String,
+        expected: String,
+        value: String,
+    ) -> Result<CasOutcome> {
+        self.request_cas(Request::SetIfEquals {
+            key: key.into(),
+            expected: expected.into(),
+            value: value.into(),
+        })
+        .await
+    }
+
+    pub async fn remove_if_equals(&mut self, key: String, expected: String) -> Result<CasOutcome> {
+        self.request_cas(Request::RemoveIfEquals {
+            key: key.into(),
+            expected: expected.into(),
+        })
+        .await
+    }
+
+    async fn request_cas(&mut self, request: Request) -> Result<CasOutcome> {
+        match self.request(request).await? {
+            CasResponse::Swapped() => Ok(CasOutcome::Swapped),
+            CasResponse::Current(value) => Ok(CasOutcome::Current(
+                value.map(|value| String::from_utf8(value.0)).transpose()?,
+            )),
+            CasResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // incr adds delta to the counter held by key and returns its new value
+    pub async fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        let request = Request::Incr {
+            key: key.into(),
+            delta,
+        };
+
+        match self.request(request).await? {
+            IncrResponse::Ok(value) => Ok(value),
+            IncrResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // merge hands operand to the merge operator of the given name on the server
+    pub async fn merge(&mut self, key: String, operator: &str, operand: String) -> Result<()> {
+        let request = Request::Merge {
+            key: key.into(),
+            operator: operator.to_owned(),
+            operand: operand.into(),
+        };
+
+        match self.request(request).await? {
+            SetResponse::Ok() => Ok(()),
+            SetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // watch asks the server for the changes to the keys filter matches. The
+    // connection is then given over to the stream of changes.
+    pub async fn watch(mut self, filter: WatchFilter) -> Result<AsyncRemoteWatcher> {
+        match self.request(Request::Watch(filter.into())).await? {
+            WatchResponse::Watching() => Ok(AsyncRemoteWatcher {
+                reader: self.reader,
+                _writer: self.writer,
+                id: self.last_id,
+            }),
+            WatchResponse::Change { .. } => Err(KVError::RequestError),
+            WatchResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // begin starts a transaction on the server and returns its id, see
+    // Client::begin
+    pub async fn begin(&mut self) -> Result<u64> {
+        match self.request(Request::Begin).await? {
+            BeginResponse::Ok(id) => Ok(id),
+            BeginResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub async fn tx_get(&mut self, tx: u64, key: String) -> Result<Option<String>> {
+        let request = Request::TxGet {
+            tx,
+            key: key.into(),
+        };
+
+        match self.request(request).await? {
+            GetResponse::Ok(content) => Ok(content
+                .map(|value| String::from_utf8(value.0))
+                .transpose()?),
+            GetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub async fn tx_set(&mut self, tx: u64, key: String, value: String) -> Result<()> {
+        let request = Request::TxSet {
+            tx,
+            key: key.into(),
+            value: value.into(),
+        };
+
+        match self.request(request).await? {
+            SetResponse::Ok() => Ok(()),
+            SetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub async fn tx_remove(&mut self, tx: u64, key: String) -> Result<()> {
+        let request = Request::TxRemove {
+            tx,
+            key: key.into(),
+        };
+
+        match self.request(request).await? {
+            RmResponse::Ok() => Ok(()),
+            RmResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // commit fails with KVError::TransactionConflict when the transaction
+    // lost against a concurrent writer
+    pub async fn commit(&mut self, tx: u64) -> Result<()> {
+        match self.request(Request::Commit { tx }).await? {
+            TxnResponse::Ok() => Ok(()),
+            TxnResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub async fn abort(&mut self, tx: u64) -> Result<()> {
+        match self.request(Request::Abort { tx }).await? {
+            TxnResponse::Ok() => Ok(()),
+            TxnResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // create_namespace, namespaces and drop_namespace manage the namespaces
+    // of the server, whichever namespace the client works in
+    pub async fn create_namespace(&mut self, name: String) -> Result<()> {
+        match self
+            .request_as_is(&Request::CreateNamespace { name })
+            .await?
+        {
+            AdminResponse::Ok() => Ok(()),
+            AdminResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub async fn namespaces(&mut self) -> Result<Vec<String>> {
+        match self.request_as_is(&Request::ListNamespaces).await? {
+            NamespacesResponse::Ok(names) => Ok(names),
+            NamespacesResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub async fn drop_namespace(&mut self, name: String) -> Result<()> {
+        match self.request_as_is(&Request::DropNamespace { name }).await? {
+            AdminResponse::Ok() => Ok(()),
+            AdminResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // request sends request to run in the namespace of the client, and
+    // returns the response to it
+    async fn request<T: DeserializeOwned>(&mut self, request: Request) -> Result<T> {
+        match self.namespace.clone() {
+            Some(namespace) => {
+                let request = Request::InNamespace {
+                    namespace,
+                    request: Box::new(request),
+                };
+                self.request_as_is(&request).await
+            }
+            None => self.request_as_is(&request).await,
+        }
+    }
+
+    async fn request_as_is<T: DeserializeOwned>(&mut self, request: &Request) -> Result<T> {
+        let mut payload = Vec::new();
+        WireFormat::Cbor.encode(&mut payload, request)?;
+        self.last_id = self.last_id.wrapping_add(1);
+        Frame::new(Opcode::Request, self.last_id, payload)
+            .write_async(&mut self.writer)
+            .await?;
+        self.writer.flush().await?;
+
+        match Frame::read_async(&mut self.reader).await? {
+            Some(frame) => decode_response(frame, self.last_id),
+            // the server hung up before answering
+            None => Err(KVError::Io),
+        }
+    }
+}
+
+// AsyncRemoteWatcher yields the changes a server streams for a watch, until
+// the server closes the connection
+pub struct AsyncRemoteWatcher {
+    reader: BufReader<OwnedReadHalf>,
+    // dropping the write half shuts it down, which the server would take as
+    // hanging up
+    _writer: BufWriter<OwnedWriteHalf>,
+    // the id of the watch request, which every change is sent under
+    id: u32,
+}
+
+impl AsyncRemoteWatcher {
+    // next waits for the next change, or returns None once the server has
+    // closed the connection
+    pub async fn next(&mut self) -> Option<Result<Change>> {
+        let frame = match Frame::read_async(&mut self.reader).await {
+            Ok(frame) => frame?,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(decode_response(frame, self.id).and_then(into_change))
+    }
+}
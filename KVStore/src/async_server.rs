@@ -0,0 +1,248 @@
+use crate::{
+    common::{WatchResponse, WireFormat},
+    engines::{AsyncKvsEngine, KvsEngine},
+    error::{KVError, Result},
+    frame::{self, Frame, Opcode},
+    server::{
+        decode_request, is_hang_up, respond, OpenTransactions, ServerLimits, WATCH_HANGUP_CHECK,
+    },
+    Change, Watcher,
+};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+
+// changes a watch may have waiting to be sent before the engine is held up
+const WATCH_BUFFER: usize = 64;
+
+// AsyncServer serves the framed protocol of kvs-client on the tokio runtime.
+// Connections are tasks instead of pool threads, so idle ones cost next to
+// nothing, and engine calls are offloaded by the AsyncKvsEngine facade. The
+// legacy and foreign protocols are left to Server. The timeouts and request
+// size of its limits apply as they do for Server, but the number of
+// connections is not capped.
+pub struct AsyncServer<E: KvsEngine> {
+    engine: AsyncKvsEngine<E>,
+    listener: TcpListener,
+    limits: ServerLimits,
+}
+
+impl<E: KvsEngine> AsyncServer<E> {
+    // new binds the server to addr
+    pub async fn new(engine: AsyncKvsEngine<E>, addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(Self {
+            engine,
+            listener,
+            limits: ServerLimits::default(),
+        })
+    }
+
+    // with_limits sets the timeouts and the request size the server holds
+    // its clients to
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // local_addr returns the address the server listens on, which tells the
+    // port it was given when it was bound to port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // run accepts connections and serves each of them on a task of its own,
+    // until the future it returns is dropped
+    pub async fn run(self) -> Result<()> {
+        let Self {
+            engine,
+            listener,
+            limits,
+        } = self;
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("connection error: {}", e);
+                    continue;
+                }
+            };
+
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(engine, stream, limits).await {
+                    eprintln!("Error in request handling: {}", e);
+                }
+            });
+        }
+    }
+}
+
+// serve answers the request frames of a connection until the client hangs
+// up or goes idle, the way Server does
+async fn serve<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    stream: TcpStream,
+    limits: ServerLimits,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    within(
+        limits.read_timeout,
+        frame::server_handshake_async(&mut reader, &mut writer),
+    )
+    .await?;
+
+    let transactions = Arc::new(OpenTransactions::default());
+    loop {
+        if reader.buffer().is_empty() {
+            within(limits.write_timeout, writer.flush()).await?;
+        }
+        match within(limits.idle_timeout, reader.fill_buf()).await {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if is_hang_up(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let read = Frame::read_async_limited(&mut reader, limits.max_request_size);
+        let frame = match within(limits.read_timeout, read).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(e @ KVError::RequestTooLarge { id, .. }) => {
+                let error = Frame::error(id, &e.to_string());
+                within(limits.write_timeout, error.write_async(&mut writer)).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let req = match decode_request(&frame) {
+            Ok(req) => req,
+            Err(error) => {
+                within(limits.write_timeout, error.write_async(&mut writer)).await?;
+                continue;
+            }
+        };
+
+        let transactions = Arc::clone(&transactions);
+        let (response, watcher) = engine
+            .run(move |engine| {
+                let mut response = Vec::new();
+                let format = WireFormat::Cbor;
+                let max_scan = limits.max_scan_page;
+                let watcher = respond(engine, &transactions, req, format, max_scan, &mut response)?;
+                Ok((response, watcher))
+            })
+            .await?;
+        let written = Frame::new(Opcode::Response, frame.id, response);
+        let watcher = match watcher {
+            Some(watcher) => watcher,
+            None => {
+                within(limits.write_timeout, written.write_async(&mut writer)).await?;
+                continue;
+            }
+        };
+
+        // the changes follow the response to a watch right away
+        send_frame(&mut writer, &written, limits.write_timeout).await?;
+        return stream_changes(watcher, frame.id, reader, writer, limits.write_timeout).await;
+    }
+}
+
+// stream_changes sends every change the watcher sees as a response to the
+// watch request id, until the client hangs up or the engine is dropped. The
+// watcher blocks, so it is waited on by a thread of its own.
+async fn stream_changes<R, W>(
+    watcher: Watcher,
+    id: u32,
+    mut reader: R,
+    mut writer: W,
+    write_timeout: Duration,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (send, mut changes) = mpsc::channel::<Change>(WATCH_BUFFER);
+    thread::spawn(move || loop {
+        match watcher.next_timeout(WATCH_HANGUP_CHECK) {
+            Ok(change) => {
+                if send.blocking_send(change).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if send.is_closed() => return,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    });
+
+    // the client sends nothing once it watches, so reading from it only ends
+    // when it hangs up
+    let mut sink = tokio::io::sink();
+    let hung_up = tokio::io::copy(&mut reader, &mut sink);
+    tokio::pin!(hung_up);
+
+    loop {
+        tokio::select! {
+            change = changes.recv() => {
+                let change = match change {
+                    Some(change) => change,
+                    None => return Ok(()),
+                };
+                let mut payload = Vec::new();
+                WireFormat::Cbor.encode(&mut payload, &WatchResponse::from(change))?;
+                let written = Frame::new(Opcode::Response, id, payload);
+                send_frame(&mut writer, &written, write_timeout).await?;
+            }
+            _ = &mut hung_up => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// send_frame writes frame and flushes it, failing with a TimedOut error if the
+// client does not take all of it within timeout
+async fn send_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+    timeout: Duration,
+) -> Result<()> {
+    let sent = async {
+        frame.write_async(writer).await?;
+        writer.flush().await?;
+        Ok(())
+    };
+    within(timeout, sent).await
+}
+
+// within runs future, failing with a TimedOut error once timeout has passed
+// without it finishing. A zero timeout waits forever.
+async fn within<T, E, F>(timeout: Duration, future: F) -> std::result::Result<T, E>
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    E: From<io::Error>,
+{
+    if timeout.is_zero() {
+        return future.await;
+    }
+    match time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_elapsed) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
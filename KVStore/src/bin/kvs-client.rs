@@ -1,31 +1,135 @@
 use kvs::{
     client::Client,
-    common::{GetAction, Methods, RemoveAction, SetAction},
+    common::{
+        CasAction, GetAction, IncrAction, Methods, NamespaceAction, NamespaceCommand, RemoveAction,
+        ScanAction, SetAction, TtlAction, WatchAction,
+    },
     parser::client_parser,
-    Result,
+    CasOutcome, ChangeOp, KVError, Result, WatchFilter,
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, process, time::Duration};
 
 fn main() -> Result<()> {
     let cli = client_parser::Cli::parse_cli();
+    let namespace = cli.namespace;
+    let connect = |addr: &str| -> Result<Client> {
+        let socket: SocketAddr = addr.parse()?;
+        let client = Client::new(socket)?;
+        Ok(match &namespace {
+            Some(namespace) => client.with_namespace(namespace.clone()),
+            None => client,
+        })
+    };
 
     match cli.params {
         Methods::Get(GetAction { key, addr }) => {
-            let socket: SocketAddr = addr.parse()?;
-            let mut client = Client::new(socket)?;
+            let mut client = connect(&addr)?;
             let response = client.get(key)?;
             println!("{}", response);
         }
-        Methods::Set(SetAction { key, value, addr }) => {
-            let socket: SocketAddr = addr.parse()?;
-            let mut client = Client::new(socket)?;
-            client.set(key, value)?;
+        Methods::Set(SetAction {
+            key,
+            value,
+            ttl,
+            addr,
+        }) => {
+            let mut client = connect(&addr)?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl))?,
+                None => client.set(key, value)?,
+            }
+        }
+        Methods::Ttl(TtlAction { key, addr }) => {
+            let mut client = connect(&addr)?;
+            match client.ttl(key) {
+                // round up, so that a key shown with 0 seconds left is gone
+                Ok(Some(ttl)) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("no expiry"),
+                Err(KVError::KeyNoExist) => println!("{}", KVError::KeyNoExist),
+                Err(e) => return Err(e),
+            }
+        }
+        Methods::Incr(IncrAction { key, delta, addr }) => {
+            let mut client = connect(&addr)?;
+            println!("{}", client.incr(key, delta)?);
+        }
+        Methods::Watch(WatchAction { key, prefix, addr }) => {
+            let client = connect(&addr)?;
+            let filter = if prefix {
+                WatchFilter::Prefix(key.into_bytes())
+            } else {
+                WatchFilter::Key(key.into_bytes())
+            };
+            for change in client.watch(filter)? {
+                let change = change?;
+                let op = match change.op {
+                    ChangeOp::Set => "set",
+                    ChangeOp::Remove => "remove",
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    change.seq,
+                    op,
+                    String::from_utf8_lossy(&change.key),
+                    String::from_utf8_lossy(&change.value.unwrap_or_default())
+                );
+            }
         }
         Methods::Rm(RemoveAction { key, addr }) => {
-            let socket: SocketAddr = addr.parse()?;
-            let mut client = Client::new(socket)?;
+            let mut client = connect(&addr)?;
             client.remove(key)?;
         }
+        Methods::Scan(ScanAction {
+            start,
+            end,
+            prefix,
+            limit,
+            reverse,
+            addr,
+        }) => {
+            let mut client = connect(&addr)?;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(prefix, limit, reverse)?,
+                None => client.scan(start, end, limit, reverse)?,
+            };
+            for (key, value) in pairs {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Methods::Namespace(NamespaceAction { command, addr }) => {
+            let mut client = connect(&addr)?;
+            match command {
+                NamespaceCommand::Create { name } => client.create_namespace(name)?,
+                NamespaceCommand::List => {
+                    for name in client.namespaces()? {
+                        println!("{}", name);
+                    }
+                }
+                NamespaceCommand::Drop { name } => client.drop_namespace(name)?,
+            }
+        }
+        Methods::Cas(CasAction {
+            key,
+            value,
+            expected,
+            remove,
+            addr,
+        }) => {
+            let mut client = connect(&addr)?;
+            let outcome = match (expected, value) {
+                (Some(expected), _) if remove => client.remove_if_equals(key, expected)?,
+                (Some(expected), Some(value)) => client.set_if_equals(key, expected, value)?,
+                (None, Some(value)) => client.set_if_absent(key, value)?,
+                (_, None) => unreachable!("clap requires a value unless removing"),
+            };
+            if let CasOutcome::Current(current) = outcome {
+                println!(
+                    "{}",
+                    current.unwrap_or_else(|| KVError::KeyNoExist.to_string())
+                );
+                process::exit(1);
+            }
+        }
     }
 
     Ok(())
@@ -1,14 +1,24 @@
 use slog::Logger;
 
 use kvs::{
-    common::*, error::KVError, parser::server_parser, server::Server, thread_pool::RayonThreadPool,
-    KvStore, KvsEngine, Result, SledKvsEngine, ThreadPool,
+    common::*,
+    error::KVError,
+    parser::server_parser,
+    server::{Server, ServerLimits},
+    thread_pool::RayonThreadPool,
+    Durability, KvStoreOptions, KvsEngine, Result, SledKvsEngine, ThreadPool,
+};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
 };
 use std::{
     env::current_dir,
     fs,
     net::SocketAddr,
+    process,
     sync::{atomic::AtomicBool, Arc, Mutex},
+    thread,
 };
 
 extern crate slog;
@@ -23,6 +33,9 @@ const ENGINE_FILE: &str = "engine.rec";
 const ENGINE_DB_DI: &str = "database";
 
 fn main() -> Result<()> {
+    // the storage engines report recoverable problems through the log crate
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     let drain =
         slog_term::CompactFormat::new(slog_term::PlainSyncDecorator::new(std::io::stderr()))
             .build()
@@ -32,19 +45,36 @@ fn main() -> Result<()> {
 
     let cli = server_parser::Cli::parse_cli();
 
-    let engine = check_engine(cli.engine)?;
+    let engine = check_engine(cli.engine.clone())?;
 
     let socket: SocketAddr = cli.addr.parse()?;
 
-    run(engine, socket, root_logger)?;
+    run(
+        engine,
+        socket,
+        cli.protocol,
+        cli.kvs_options(),
+        cli.durability,
+        cli.server_limits(),
+        root_logger,
+    )?;
 
     Ok(())
 }
 
-fn run(engine: Engine, addr: SocketAddr, logger: Logger) -> Result<()> {
+fn run(
+    engine: Engine,
+    addr: SocketAddr,
+    protocol: Protocol,
+    options: KvStoreOptions,
+    durability: Option<Durability>,
+    limits: ServerLimits,
+    logger: Logger,
+) -> Result<()> {
     slog::info!(logger, ""; "kv server" => env!("CARGO_PKG_VERSION"));
     slog::info!(logger, ""; "ip" => format!("{}:{}", addr.ip(), addr.port()));
     slog::info!(logger, ""; "Engine" => format!("{}", engine));
+    slog::info!(logger, ""; "Protocol" => format!("{}", protocol));
 
     fs::write(current_dir()?.join(ENGINE_FILE), format!("{}", engine))?;
 
@@ -52,21 +82,51 @@ fn run(engine: Engine, addr: SocketAddr, logger: Logger) -> Result<()> {
 
     match engine {
         Engine::Kvs => {
-            let engine = KvStore::open(current_dir()?.join(ENGINE_DB_DI))?;
-            run_kv_server(engine, addr, pool)?;
+            let engine = options.open(current_dir()?.join(ENGINE_DB_DI))?;
+            run_kv_server(engine, addr, protocol, pool, limits, logger)?;
         }
         Engine::Sled => {
-            let engine = SledKvsEngine::open(current_dir()?.join(ENGINE_DB_DI))?;
-            run_kv_server(engine, addr, pool)?;
+            let path = current_dir()?.join(ENGINE_DB_DI);
+            let engine = match durability {
+                Some(durability) => SledKvsEngine::open_with_durability(path, durability)?,
+                None => SledKvsEngine::open(path)?,
+            };
+            run_kv_server(engine, addr, protocol, pool, limits, logger)?;
         }
     };
 
     Ok(())
 }
 
-fn run_kv_server<E: KvsEngine, P: ThreadPool>(engine: E, addr: SocketAddr, pool: P) -> Result<()> {
+fn run_kv_server<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    addr: SocketAddr,
+    protocol: Protocol,
+    pool: P,
+    limits: ServerLimits,
+    logger: Logger,
+) -> Result<()> {
     let killed = Arc::new(AtomicBool::new(false));
-    let mut server = Server::new(engine, addr, pool, killed)?;
+    let mut server = Server::new(engine, addr, pool, killed)?
+        .with_protocol(protocol)
+        .with_limits(limits);
+
+    // the first SIGINT or SIGTERM shuts the server down gracefully, and a
+    // second one exits without waiting for the connections to drain
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let shutdown = server.shutdown_handle()?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            slog::info!(logger, ""; "shutting down on signal" => signal);
+            shutdown.shutdown();
+        }
+        if signals.next().is_some() {
+            slog::warn!(logger, "exiting without draining connections");
+            process::exit(1);
+        }
+    });
+
     server.run()?;
     Ok(())
 }
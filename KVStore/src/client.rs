@@ -1,66 +1,461 @@
-use crate::common::{GetResponse, Request, RmResponse, SetResponse};
+use crate::common::{
+    AdminResponse, BeginResponse, Bytes, CasResponse, GetResponse, IncrResponse,
+    NamespacesResponse, Request, RmResponse, ScanResponse, SetResponse, TtlResponse, TxnResponse,
+    WatchResponse, WireFormat,
+};
 use crate::error::{KVError, Result};
+use crate::frame::{self, Frame, Opcode};
+use crate::{CasOutcome, Change, WatchFilter};
 
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
+use serde::de::DeserializeOwned;
 
 use std::{
     io::{BufReader, BufWriter, Write},
     net::{SocketAddr, TcpStream},
+    time::Duration,
 };
 
+// Client talks the framed protocol to the server, with requests and
+// responses in CBOR, so keys and values may hold any bytes. All requests of
+// a client go over the connection it opened.
 pub struct Client {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    // the namespace requests run in, None for the default one
+    namespace: Option<String>,
+    // the id of the last request sent, which its response has to carry
+    last_id: u32,
 }
 
 impl Client {
     pub fn new(addr: SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(addr).expect("error in connection!");
-        let reader = BufReader::new(stream.try_clone().expect("error in clone stream"));
+        let stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        frame::client_handshake(&mut reader, &mut writer)?;
 
         Ok(Self {
-            reader: Deserializer::from_reader(reader),
-            writer: BufWriter::new(stream),
+            reader,
+            writer,
+            namespace: None,
+            last_id: 0,
         })
     }
 
+    // with_namespace makes the client work on the keys of namespace instead
+    // of the default ones
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
     pub fn get(&mut self, key: String) -> Result<String> {
-        let serialized = serde_json::to_string_pretty(&Request::Get { key })?;
-        self.writer.write(serialized.as_bytes())?;
-        self.writer.flush()?;
+        match self.get_bytes(key.into_bytes())? {
+            Some(content) => Ok(String::from_utf8(content)?),
+            None => Ok(KVError::KeyNoExist.to_string()),
+        }
+    }
 
-        let response = GetResponse::deserialize(&mut self.reader)?;
+    // get_bytes returns the value of a binary key, or None if it does not exist
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.send(Request::Get { key: Bytes(key) })?;
 
-        match response {
-            GetResponse::Ok(content) => Ok(content.unwrap_or(KVError::KeyNoExist.to_string())),
+        match self.receive()? {
+            GetResponse::Ok(content) => Ok(content.map(Vec::from)),
             GetResponse::Err(e) => Err(KVError::String(e)),
         }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let serialized = serde_json::to_string_pretty(&Request::Set { key, value })?;
-        self.writer.write(serialized.as_bytes())?;
-        self.writer.flush()?;
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
-        let response = SetResponse::deserialize(&mut self.reader)?;
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request_set(key, value, None)
+    }
+
+    // set_with_ttl sets a key that expires once ttl has passed
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.request_set(
+            key.into_bytes(),
+            value.into_bytes(),
+            Some(ttl.as_millis() as u64),
+        )
+    }
+
+    fn request_set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl_ms: Option<u64>) -> Result<()> {
+        self.send(Request::Set {
+            key: Bytes(key),
+            value: Bytes(value),
+            ttl_ms,
+        })?;
 
-        match response {
+        match self.receive()? {
             SetResponse::Ok() => Ok(()),
             SetResponse::Err(e) => Err(KVError::String(e)),
         }
     }
 
+    // ttl returns the lifetime a key has left, or None if it never expires.
+    // A missing key is reported as KVError::KeyNoExist.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.send(Request::Ttl { key: key.into() })?;
+
+        match self.receive()? {
+            TtlResponse::Ok(ttl) => Ok(ttl.map(Duration::from_millis)),
+            TtlResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let serialized = serde_json::to_string_pretty(&Request::Remove { key })?;
-        self.writer.write(serialized.as_bytes())?;
-        self.writer.flush()?;
+        self.remove_bytes(key.into_bytes())
+    }
 
-        let response = RmResponse::deserialize(&mut self.reader)?;
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.send(Request::Remove { key: Bytes(key) })?;
 
-        match response {
+        match self.receive()? {
             RmResponse::Ok() => Ok(()),
             RmResponse::Err(e) => Err(KVError::String(e)),
         }
     }
+
+    // scan lists the pairs with keys from start up to, but excluding, end
+    pub fn scan(
+        &mut self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        self.request_scan(Request::Scan {
+            start: start.map(Bytes::from),
+            end: end.map(Bytes::from),
+            limit,
+            reverse,
+        })
+    }
+
+    pub fn scan_prefix(
+        &mut self,
+        prefix: String,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<(String, String)>> {
+        self.request_scan(Request::ScanPrefix {
+            prefix: prefix.into(),
+            limit,
+            reverse,
+        })
+    }
+
+    // request_scan sends request, and a request for the rest of the scan
+    // for as long as the server answers with partial pages
+    fn request_scan(&mut self, request: Request) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        let mut next = Some(request);
+        while let Some(request) = next.take() {
+            self.send(request.clone())?;
+            let page = match self.receive()? {
+                ScanResponse::Ok(page) => page,
+                ScanResponse::Partial(page) => {
+                    if let Some((last, _)) = page.last() {
+                        next = request.resume_scan(&last.0, page.len());
+                    }
+                    page
+                }
+                ScanResponse::Err(e) => return Err(KVError::String(e)),
+            };
+            for (key, value) in page {
+                pairs.push((String::from_utf8(key.0)?, String::from_utf8(value.0)?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<CasOutcome> {
+        self.request_cas(Request::SetIfAbsent {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn set_if_equals(
+        &mut self,
+        key: String,
+        expected: String,
+        value: String,
+    ) -> Result<CasOutcome> {
+        self.request_cas(Request::SetIfEquals {
+            key: key.into(),
+            expected: expected.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn remove_if_equals(&mut self, key: String, expected: String) -> Result<CasOutcome> {
+        self.request_cas(Request::RemoveIfEquals {
+            key: key.into(),
+            expected: expected.into(),
+        })
+    }
+
+    fn request_cas(&mut self, request: Request) -> Result<CasOutcome> {
+        self.send(request)?;
+
+        match self.receive()? {
+            CasResponse::Swapped() => Ok(CasOutcome::Swapped),
+            CasResponse::Current(value) => Ok(CasOutcome::Current(
+                value.map(|value| String::from_utf8(value.0)).transpose()?,
+            )),
+            CasResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // incr adds delta to the counter held by key and returns its new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.send(Request::Incr {
+            key: key.into(),
+            delta,
+        })?;
+
+        match self.receive()? {
+            IncrResponse::Ok(value) => Ok(value),
+            IncrResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // merge hands operand to the merge operator of the given name on the server
+    pub fn merge(&mut self, key: String, operator: &str, operand: String) -> Result<()> {
+        self.send(Request::Merge {
+            key: key.into(),
+            operator: operator.to_owned(),
+            operand: operand.into(),
+        })?;
+
+        match self.receive()? {
+            SetResponse::Ok() => Ok(()),
+            SetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // watch asks the server for the changes to the keys filter matches. The
+    // connection is then given over to the stream of changes.
+    pub fn watch(mut self, filter: WatchFilter) -> Result<RemoteWatcher> {
+        self.send(Request::Watch(filter.into()))?;
+
+        match self.receive()? {
+            WatchResponse::Watching() => Ok(RemoteWatcher {
+                reader: self.reader,
+                id: self.last_id,
+            }),
+            WatchResponse::Change { .. } => Err(KVError::RequestError),
+            WatchResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // begin starts a transaction on the server and returns its id, which the
+    // other transaction methods take. The transaction belongs to the
+    // connection of this client, and is aborted if it closes first.
+    pub fn begin(&mut self) -> Result<u64> {
+        self.send(Request::Begin)?;
+
+        match self.receive()? {
+            BeginResponse::Ok(id) => Ok(id),
+            BeginResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub fn tx_get(&mut self, tx: u64, key: String) -> Result<Option<String>> {
+        self.send(Request::TxGet {
+            tx,
+            key: key.into(),
+        })?;
+
+        match self.receive()? {
+            GetResponse::Ok(content) => Ok(content
+                .map(|value| String::from_utf8(value.0))
+                .transpose()?),
+            GetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub fn tx_set(&mut self, tx: u64, key: String, value: String) -> Result<()> {
+        self.send(Request::TxSet {
+            tx,
+            key: key.into(),
+            value: value.into(),
+        })?;
+
+        match self.receive()? {
+            SetResponse::Ok() => Ok(()),
+            SetResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub fn tx_remove(&mut self, tx: u64, key: String) -> Result<()> {
+        self.send(Request::TxRemove {
+            tx,
+            key: key.into(),
+        })?;
+
+        match self.receive()? {
+            RmResponse::Ok() => Ok(()),
+            RmResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    // commit fails with KVError::TransactionConflict when the transaction
+    // lost against a concurrent writer
+    pub fn commit(&mut self, tx: u64) -> Result<()> {
+        self.send(Request::Commit { tx })?;
+
+        match self.receive()? {
+            TxnResponse::Ok() => Ok(()),
+            TxnResponse::Err(e) => Err(remote_error(e)),
+        }
+    }
+
+    pub fn abort(&mut self, tx: u64) -> Result<()> {
+        self.send(Request::Abort { tx })?;
+
+        match self.receive()? {
+            TxnResponse::Ok() => Ok(()),
+            TxnResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // create_namespace, namespaces and drop_namespace manage the namespaces
+    // of the server, whichever namespace the client works in
+    pub fn create_namespace(&mut self, name: String) -> Result<()> {
+        self.send_as_is(&Request::CreateNamespace { name })?;
+
+        match self.receive()? {
+            AdminResponse::Ok() => Ok(()),
+            AdminResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub fn namespaces(&mut self) -> Result<Vec<String>> {
+        self.send_as_is(&Request::ListNamespaces)?;
+
+        match self.receive()? {
+            NamespacesResponse::Ok(names) => Ok(names),
+            NamespacesResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    pub fn drop_namespace(&mut self, name: String) -> Result<()> {
+        self.send_as_is(&Request::DropNamespace { name })?;
+
+        match self.receive()? {
+            AdminResponse::Ok() => Ok(()),
+            AdminResponse::Err(e) => Err(KVError::String(e)),
+        }
+    }
+
+    // send sends request to run in the namespace of the client
+    fn send(&mut self, request: Request) -> Result<()> {
+        match &self.namespace {
+            Some(namespace) => self.send_as_is(&Request::InNamespace {
+                namespace: namespace.clone(),
+                request: Box::new(request),
+            }),
+            None => self.send_as_is(&request),
+        }
+    }
+
+    fn send_as_is(&mut self, request: &Request) -> Result<()> {
+        let mut payload = Vec::new();
+        WireFormat::Cbor.encode(&mut payload, request)?;
+        self.last_id = self.last_id.wrapping_add(1);
+        Frame::new(Opcode::Request, self.last_id, payload).write(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        // the server hung up before answering
+        receive_frame(&mut self.reader, self.last_id)?.ok_or(KVError::Io)
+    }
+}
+
+// receive_frame reads the response to request id, or returns None once the
+// server has closed the connection
+fn receive_frame<T: DeserializeOwned>(
+    reader: &mut BufReader<TcpStream>,
+    id: u32,
+) -> Result<Option<T>> {
+    match Frame::read(reader)? {
+        Some(frame) => decode_response(frame, id).map(Some),
+        None => Ok(None),
+    }
+}
+
+// decode_response returns the response a frame carries to request id
+pub(crate) fn decode_response<T: DeserializeOwned>(frame: Frame, id: u32) -> Result<T> {
+    if frame.id != id {
+        return Err(KVError::RequestError);
+    }
+
+    match frame.opcode {
+        Opcode::Response => Ok(WireFormat::Cbor.decode(&frame.payload[..])?),
+        Opcode::Error => Err(KVError::String(
+            String::from_utf8_lossy(&frame.payload).into_owned(),
+        )),
+        _ => Err(KVError::RequestError),
+    }
+}
+
+// RemoteWatcher yields the changes a server streams for a watch, until the
+// server closes the connection
+pub struct RemoteWatcher {
+    reader: BufReader<TcpStream>,
+    // the id of the watch request, which every change is sent under
+    id: u32,
+}
+
+impl Iterator for RemoteWatcher {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        let response = receive_frame(&mut self.reader, self.id).transpose()?;
+        Some(response.and_then(into_change))
+    }
+}
+
+// into_change reads a change out of a response streamed for a watch
+pub(crate) fn into_change(response: WatchResponse) -> Result<Change> {
+    match response {
+        WatchResponse::Change {
+            key,
+            op,
+            value,
+            seq,
+        } => Ok(Change {
+            key: key.0,
+            op,
+            value: value.map(Vec::from),
+            seq,
+        }),
+        WatchResponse::Watching() => Err(KVError::RequestError),
+        WatchResponse::Err(e) => Err(KVError::String(e)),
+    }
+}
+
+// remote_error turns errors reported by the server back into the errors
+// they were where callers need to tell them apart, such as a conflict that
+// is worth retrying
+pub(crate) fn remote_error(message: String) -> KVError {
+    if message == KVError::TransactionConflict.to_string() {
+        KVError::TransactionConflict
+    } else if message == KVError::KeyNoExist.to_string() {
+        KVError::KeyNoExist
+    } else if message == KVError::Overflow.to_string() {
+        KVError::Overflow
+    } else if message == KVError::NotAnInteger.to_string() {
+        KVError::NotAnInteger
+    } else {
+        KVError::String(message)
+    }
 }
@@ -1,7 +1,13 @@
+use crate::{engines::scan, Change, ChangeOp, WatchFilter};
 use clap::{self, Parser, Subcommand};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, DeserializeOwned, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use std::{
     fmt::{self, Display},
+    io::{Read, Write},
+    ops::Bound,
     result::Result,
 };
 
@@ -12,6 +18,12 @@ pub enum Methods {
     Set(SetAction),
     Get(GetAction),
     Rm(RemoveAction),
+    Scan(ScanAction),
+    Cas(CasAction),
+    Ttl(TtlAction),
+    Incr(IncrAction),
+    Watch(WatchAction),
+    Namespace(NamespaceAction),
 }
 
 #[derive(Debug, Parser, Serialize, Deserialize)]
@@ -20,6 +32,9 @@ pub struct SetAction {
     pub key: String,
     #[clap(index = 2)]
     pub value: String,
+    /// Seconds after which the key expires
+    #[arg(short, long)]
+    pub ttl: Option<u64>,
     #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
     pub addr: String,
 }
@@ -40,16 +55,311 @@ pub struct RemoveAction {
     pub addr: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Prints the seconds left until a key expires
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct TtlAction {
+    #[clap(index = 1)]
+    pub key: String,
+    #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+/// Lists key/value pairs in key order, one "key<TAB>value" pair per line
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct ScanAction {
+    /// First key to list
+    #[clap(index = 1)]
+    pub start: Option<String>,
+    /// List keys before this one only
+    #[clap(index = 2)]
+    pub end: Option<String>,
+    /// List only keys starting with this prefix
+    #[arg(short, long, conflicts_with_all = ["start", "end"])]
+    pub prefix: Option<String>,
+    #[arg(short, long)]
+    pub limit: Option<usize>,
+    /// List keys in descending order
+    #[arg(short, long)]
+    pub reverse: bool,
+    #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+/// Writes a value only if the key currently holds the expected one. Prints
+/// the value found instead and fails if it does not
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct CasAction {
+    #[clap(index = 1)]
+    pub key: String,
+    /// New value, required unless the key is removed
+    #[clap(index = 2, required_unless_present = "remove")]
+    pub value: Option<String>,
+    /// Value the key must hold, leave out to require a missing key
+    #[arg(short, long)]
+    pub expected: Option<String>,
+    /// Remove the key instead of setting it
+    #[arg(short, long, conflicts_with = "value", requires = "expected")]
+    pub remove: bool,
+    #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+/// Adds to the counter held by a key and prints its new value. A missing key
+/// counts as 0
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct IncrAction {
+    #[clap(index = 1)]
+    pub key: String,
+    /// Amount to add, negative to decrement
+    #[clap(index = 2, default_value_t = 1, allow_negative_numbers = true)]
+    pub delta: i64,
+    #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+/// Prints every change to a key as it happens, one "seq<TAB>op<TAB>key<TAB>value"
+/// line per change, until interrupted
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct WatchAction {
+    #[clap(index = 1)]
+    pub key: String,
+    /// Watch every key starting with the given one
+    #[arg(short, long)]
+    pub prefix: bool,
+    #[arg(short, long, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+/// Creates, lists or drops namespaces, which keep keys apart from those of
+/// other namespaces
+#[derive(Debug, Parser, Serialize, Deserialize)]
+pub struct NamespaceAction {
+    #[clap(subcommand)]
+    pub command: NamespaceCommand,
+    #[arg(short, long, global = true, default_value_t = String::from(DEFAULT_LISTENING_ADDRESS))]
+    pub addr: String,
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize)]
+pub enum NamespaceCommand {
+    Create {
+        name: String,
+    },
+    /// Prints the name of every namespace, one per line
+    List,
+    /// Deletes a namespace along with all of its keys
+    Drop {
+        name: String,
+    },
+}
+
+/// A key or value sent over the wire. It is written as a CBOR byte string,
+/// so binary data travels as is. Legacy JSON clients send and receive keys
+/// and values as plain strings instead, and get bytes that are not UTF-8 back
+/// as an array of numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bytes(pub Vec<u8>);
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes(bytes)
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(s: String) -> Self {
+        Bytes(s.into_bytes())
+    }
+}
+
+impl From<Bytes> for Vec<u8> {
+    fn from(bytes: Bytes) -> Self {
+        bytes.0
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) if serializer.is_human_readable() => serializer.serialize_str(s),
+            _ => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte string or a string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+        Ok(Bytes(v.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Bytes, E> {
+        Ok(Bytes(v.into_bytes()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+        Ok(Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+        Ok(Bytes(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+/// The encoding of requests and responses on a connection. The server answers
+/// in the format a request came in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    // pretty-printed serde_json, spoken by older clients
+    Json,
+    // CBOR, which carries keys and values as raw byte strings
+    Cbor,
+}
+
+impl WireFormat {
+    // detect tells the format of a request from its first byte. A JSON
+    // request starts with a brace, a quote or whitespace, none of which can
+    // start a CBOR encoded request.
+    pub fn detect(first: u8) -> Self {
+        match first {
+            b'{' | b'"' | b' ' | b'\t' | b'\r' | b'\n' => WireFormat::Json,
+            _ => WireFormat::Cbor,
+        }
+    }
+
+    pub fn encode<T: Serialize, W: Write>(self, writer: W, value: &T) -> crate::Result<()> {
+        match self {
+            WireFormat::Json => serde_json::to_writer_pretty(writer, value)?,
+            WireFormat::Cbor => ciborium::ser::into_writer(value, writer)?,
+        }
+        Ok(())
+    }
+
+    // decode reads a single value, leaving whatever follows it in reader
+    pub fn decode<T: DeserializeOwned, R: Read>(self, reader: R) -> crate::Result<T> {
+        match self {
+            WireFormat::Json => Ok(T::deserialize(&mut serde_json::Deserializer::from_reader(
+                reader,
+            ))?),
+            WireFormat::Cbor => Ok(ciborium::de::from_reader(reader)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Bytes,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+        // milliseconds until the key expires, None if it never does
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+    },
+    Remove {
+        key: Bytes,
+    },
+    Ttl {
+        key: Bytes,
+    },
+    // start is inclusive and end exclusive, a missing bound leaves the range open
+    Scan {
+        start: Option<Bytes>,
+        end: Option<Bytes>,
+        limit: Option<usize>,
+        reverse: bool,
+    },
+    ScanPrefix {
+        prefix: Bytes,
+        limit: Option<usize>,
+        reverse: bool,
+    },
+    SetIfAbsent {
+        key: Bytes,
+        value: Bytes,
+    },
+    SetIfEquals {
+        key: Bytes,
+        expected: Bytes,
+        value: Bytes,
+    },
+    RemoveIfEquals {
+        key: Bytes,
+        expected: Bytes,
+    },
+    // adds delta to the counter held by key, answered with its new value
+    Incr {
+        key: Bytes,
+        delta: i64,
+    },
+    Merge {
+        key: Bytes,
+        operator: String,
+        operand: Bytes,
+    },
+    // keeps the connection open and streams the changes to the watched keys
+    Watch(Watched),
+    // Begin answers with the id that the other transaction requests refer to
+    Begin,
+    TxGet {
+        tx: u64,
+        key: Bytes,
+    },
+    TxSet {
+        tx: u64,
+        key: Bytes,
+        value: Bytes,
+    },
+    TxRemove {
+        tx: u64,
+        key: Bytes,
+    },
+    Commit {
+        tx: u64,
+    },
+    Abort {
+        tx: u64,
+    },
+    // runs request on the keys of a namespace instead of the default ones
+    InNamespace {
+        namespace: String,
+        request: Box<Request>,
+    },
+    CreateNamespace {
+        name: String,
+    },
+    ListNamespaces,
+    DropNamespace {
+        name: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(Option<Bytes>),
     Err(String),
 }
 
@@ -65,6 +375,170 @@ pub enum RmResponse {
     Err(String),
 }
 
+// the milliseconds a key has left, None if it never expires
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse {
+    Ok(Option<u64>),
+    Err(String),
+}
+
+// answers Scan and ScanPrefix requests. A scan longer than the server hands
+// out at once is answered with a Partial page, which the client resumes
+// after its last key, see Request::resume_scan.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(Vec<(Bytes, Bytes)>),
+    Partial(Vec<(Bytes, Bytes)>),
+    Err(String),
+}
+
+// answers conditional writes, Current holds the value that did not match
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CasResponse {
+    Swapped(),
+    Current(Option<Bytes>),
+    Err(String),
+}
+
+// the keys a Watch request follows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Watched {
+    Key(Bytes),
+    Prefix(Bytes),
+}
+
+impl Request {
+    // resume_scan returns the request for the rest of a scan, after taken
+    // pairs that ended with the key last, or None if its limit is used up.
+    // A prefix scan goes on as a range scan over the keys with the prefix.
+    pub(crate) fn resume_scan(&self, last: &[u8], taken: usize) -> Option<Request> {
+        let (start, end, limit, reverse) = match self {
+            Request::Scan {
+                start,
+                end,
+                limit,
+                reverse,
+            } => (
+                start.as_ref().map(|key| key.0.clone()),
+                end.as_ref().map(|key| key.0.clone()),
+                *limit,
+                *reverse,
+            ),
+            Request::ScanPrefix {
+                prefix,
+                limit,
+                reverse,
+            } => {
+                let end = match scan::prefix_range(&prefix.0).1 {
+                    Bound::Excluded(end) => Some(end),
+                    _ => None,
+                };
+                (Some(prefix.0.clone()), end, *limit, *reverse)
+            }
+            _ => return None,
+        };
+
+        let limit = match limit.map(|limit| limit.saturating_sub(taken)) {
+            Some(0) => return None,
+            limit => limit,
+        };
+        // the first key after last is last with a zero byte appended
+        let (start, end) = if reverse {
+            (start, Some(last.to_vec()))
+        } else {
+            (Some([last, &[0]].concat()), end)
+        };
+        Some(Request::Scan {
+            start: start.map(Bytes),
+            end: end.map(Bytes),
+            limit,
+            reverse,
+        })
+    }
+}
+
+impl From<Watched> for WatchFilter {
+    fn from(watched: Watched) -> Self {
+        match watched {
+            Watched::Key(key) => WatchFilter::Key(key.0),
+            Watched::Prefix(prefix) => WatchFilter::Prefix(prefix.0),
+        }
+    }
+}
+
+impl From<WatchFilter> for Watched {
+    fn from(filter: WatchFilter) -> Self {
+        match filter {
+            WatchFilter::Key(key) => Watched::Key(Bytes(key)),
+            WatchFilter::Prefix(prefix) => Watched::Prefix(Bytes(prefix)),
+        }
+    }
+}
+
+// answers a Watch request with Watching once the watch is in place, followed
+// by a Change for every change until the connection is closed
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Watching(),
+    Change {
+        key: Bytes,
+        op: ChangeOp,
+        value: Option<Bytes>,
+        seq: u64,
+    },
+    Err(String),
+}
+
+impl From<Change> for WatchResponse {
+    fn from(change: Change) -> Self {
+        WatchResponse::Change {
+            key: Bytes(change.key),
+            op: change.op,
+            value: change.value.map(Bytes),
+            seq: change.seq,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IncrResponse {
+    Ok(i64),
+    Err(String),
+}
+
+// answers CreateNamespace and DropNamespace requests
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Ok(),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NamespacesResponse {
+    Ok(Vec<String>),
+    Err(String),
+}
+
+// answers a request that failed before it could run, such as one for a
+// namespace that does not exist. It reads as the Err variant of any response.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrResponse {
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BeginResponse {
+    Ok(u64),
+    Err(String),
+}
+
+// answers Commit and Abort requests
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TxnResponse {
+    Ok(),
+    Err(String),
+}
+
 #[derive(clap::ValueEnum, Clone, Debug, PartialEq)]
 pub enum Engine {
     Kvs,
@@ -74,12 +548,32 @@ pub enum Engine {
 impl Display for Engine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
-            Engine::Kvs => {
-                return Ok(write!(f, "{}", "kvs")?);
-            }
-            Engine::Sled => {
-                return Ok(write!(f, "{}", "sled")?);
-            }
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+// the protocol kvs-server speaks on its listener
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    // the framed protocol of kvs-client, or the legacy JSON one
+    Kvs,
+    // Redis RESP2
+    Resp,
+    // HTTP/1.1 with JSON bodies
+    Http,
+    // the memcached text protocol
+    Memcached,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+            Protocol::Http => write!(f, "http"),
+            Protocol::Memcached => write!(f, "memcached"),
         }
     }
 }
@@ -91,3 +585,17 @@ macro_rules! logfile {
         $dir.join(format!("{}.log", $fgen))
     };
 }
+
+#[macro_export]
+macro_rules! compactfile {
+    ($dir: expr, $fgen: expr) => {
+        $dir.join(format!("{}.compact", $fgen))
+    };
+}
+
+#[macro_export]
+macro_rules! hintfile {
+    ($dir: expr, $fgen: expr) => {
+        $dir.join(format!("{}.hint", $fgen))
+    };
+}
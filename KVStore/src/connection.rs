@@ -0,0 +1,134 @@
+use crate::server::ServerLimits;
+use std::{
+    io::{self, BufRead, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
+// split returns the halves a connection is served through, which bound how
+// long the server waits on its client by the timeouts of limits. Reads wait
+// for up to the idle timeout from the moment a response is flushed until
+// more of a request arrives, and for up to the read timeout otherwise.
+pub(crate) fn split(
+    stream: TcpStream,
+    limits: &ServerLimits,
+) -> io::Result<(ConnectionReader, ConnectionWriter)> {
+    stream.set_read_timeout(timeout(limits.idle_timeout))?;
+    stream.set_write_timeout(timeout(limits.write_timeout))?;
+
+    let timeouts = Arc::new(Timeouts {
+        idle: AtomicBool::new(true),
+        idle_timeout: limits.idle_timeout,
+        read_timeout: limits.read_timeout,
+    });
+    let reader = ConnectionReader {
+        stream: stream.try_clone()?,
+        timeouts: Arc::clone(&timeouts),
+    };
+    Ok((reader, ConnectionWriter { stream, timeouts }))
+}
+
+// a zero timeout waits forever, as the socket would refuse it
+fn timeout(timeout: Duration) -> Option<Duration> {
+    (!timeout.is_zero()).then_some(timeout)
+}
+
+// Timeouts is shared by the halves of a connection, which switch the read
+// timeout of the socket whenever it goes idle or a request starts
+struct Timeouts {
+    idle: AtomicBool,
+    idle_timeout: Duration,
+    read_timeout: Duration,
+}
+
+pub(crate) struct ConnectionReader {
+    stream: TcpStream,
+    timeouts: Arc<Timeouts>,
+}
+
+impl Read for ConnectionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        if read > 0 && self.timeouts.idle.swap(false, SeqCst) {
+            self.stream
+                .set_read_timeout(timeout(self.timeouts.read_timeout))?;
+        }
+        Ok(read)
+    }
+}
+
+pub(crate) struct ConnectionWriter {
+    stream: TcpStream,
+    timeouts: Arc<Timeouts>,
+}
+
+impl ConnectionWriter {
+    pub(crate) fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Write for ConnectionWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()?;
+        if !self.timeouts.idle.swap(true, SeqCst) {
+            self.stream
+                .set_read_timeout(timeout(self.timeouts.idle_timeout))?;
+        }
+        Ok(())
+    }
+}
+
+// LineError is why read_line returned no line, which each text protocol
+// answers in its own way
+pub(crate) enum LineError {
+    // the line went on past the longest one allowed
+    TooLong,
+    // the stream failed, or ended partway through the line
+    Io(io::Error),
+}
+
+impl From<io::Error> for LineError {
+    fn from(e: io::Error) -> Self {
+        LineError::Io(e)
+    }
+}
+
+// read_line reads a line of at most max_len bytes without its line ending,
+// or returns None if the stream ends before it begins. No more than the
+// longest line allowed is read into memory.
+pub(crate) fn read_line<R: BufRead>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, LineError> {
+    let mut line = Vec::new();
+    reader
+        .take(max_len as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() > max_len {
+            return Err(LineError::TooLong);
+        }
+        return Err(LineError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed mid line",
+        )));
+    }
+
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
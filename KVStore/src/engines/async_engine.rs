@@ -0,0 +1,182 @@
+use crate::{CasOutcome, KVError, KvsEngine, Result, ThreadPool, WriteBatch};
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// An async facade over a `KvsEngine`.
+///
+/// Engine calls block on the disk, so they are run off the async runtime:
+/// on its blocking threads by default, or on a `ThreadPool` given with
+/// `with_pool`. Every method returns a future that resolves once the call
+/// has completed. Calls not covered by a method of their own can be made
+/// through `run`.
+///
+/// ```no_run
+/// use kvs::{AsyncKvsEngine, KvStore};
+///
+/// # async fn example() -> kvs::Result<()> {
+/// let engine = AsyncKvsEngine::new(KvStore::open("./database")?);
+/// engine.set("key".to_owned(), "value".to_owned()).await?;
+/// assert_eq!(engine.get("key".to_owned()).await?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsEngine<E: KvsEngine> {
+    engine: E,
+    // None to run calls on the blocking threads of the runtime
+    pool: Option<Arc<dyn Fn(Job) + Send + Sync>>,
+}
+
+impl<E: KvsEngine> Clone for AsyncKvsEngine<E> {
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine> fmt::Debug for AsyncKvsEngine<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncKvsEngine")
+            .field("pool", &self.pool.is_some())
+            .finish()
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    /// Wraps engine, whose calls then run on the blocking threads of the
+    /// tokio runtime they are awaited in.
+    pub fn new(engine: E) -> Self {
+        Self { engine, pool: None }
+    }
+
+    /// Wraps engine, whose calls then run on pool.
+    pub fn with_pool<P>(engine: E, pool: P) -> Self
+    where
+        P: ThreadPool + Send + Sync + 'static,
+    {
+        Self {
+            engine,
+            pool: Some(Arc::new(move |job| pool.spawn(job))),
+        }
+    }
+
+    /// Returns the engine the facade calls.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Runs f on the engine off the async runtime, and returns what it
+    /// returned. Return `EngineCallFailed` if f panicked, or its pool dropped
+    /// it without running it.
+    ///
+    /// The future does not borrow the facade, so it can be sent to other
+    /// tasks even though engines need not be `Sync`.
+    pub fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T>>
+    where
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let pool = self.pool.clone();
+        async move {
+            let pool = match pool {
+                Some(pool) => pool,
+                None => {
+                    return tokio::task::spawn_blocking(move || f(&engine))
+                        .await
+                        .map_err(|_| KVError::EngineCallFailed)?
+                }
+            };
+
+            let (done, result) = oneshot::channel();
+            pool(Box::new(move || {
+                // the caller may have stopped waiting
+                let _ = done.send(f(&engine));
+            }));
+            result.await.map_err(|_| KVError::EngineCallFailed)?
+        }
+    }
+
+    /// See `KvsEngine::set_bytes`.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set_bytes(key, value))
+    }
+
+    /// See `KvsEngine::set_bytes_with_ttl`.
+    pub fn set_bytes_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set_bytes_with_ttl(key, value, ttl))
+    }
+
+    /// See `KvsEngine::get_bytes`.
+    pub fn get_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>>> {
+        self.run(move |engine| engine.get_bytes(key))
+    }
+
+    /// See `KvsEngine::remove_bytes`.
+    pub fn remove_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.remove_bytes(key))
+    }
+
+    /// See `KvsEngine::set`.
+    pub fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.set(key, value))
+    }
+
+    /// See `KvsEngine::get`.
+    pub fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> {
+        self.run(move |engine| engine.get(key))
+    }
+
+    /// See `KvsEngine::remove`.
+    pub fn remove(&self, key: String) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.remove(key))
+    }
+
+    /// See `KvsEngine::ttl_bytes`.
+    pub fn ttl_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Duration>>> {
+        self.run(move |engine| engine.ttl_bytes(key))
+    }
+
+    /// See `KvsEngine::write_batch`.
+    pub fn write_batch(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> {
+        self.run(move |engine| engine.write_batch(batch))
+    }
+
+    /// See `KvsEngine::compare_and_swap_bytes`.
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<CasOutcome<Vec<u8>>>> {
+        self.run(move |engine| engine.compare_and_swap_bytes(key, expected, new))
+    }
+
+    /// See `KvsEngine::incr_bytes`.
+    pub fn incr_bytes(&self, key: Vec<u8>, delta: i64) -> impl Future<Output = Result<i64>> {
+        self.run(move |engine| engine.incr_bytes(key, delta))
+    }
+
+    /// See `KvsEngine::flush`.
+    pub fn flush(&self) -> impl Future<Output = Result<()>> {
+        self.run(|engine| engine.flush())
+    }
+
+    /// Returns the binary key/value pairs whose keys start with prefix, see
+    /// `KvsEngine::scan_prefix_bytes`.
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> impl Future<Output = Result<Vec<(Vec<u8>, Vec<u8>)>>> {
+        self.run(move |engine| engine.scan_prefix_bytes(&prefix, limit)?.collect())
+    }
+}
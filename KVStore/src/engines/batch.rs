@@ -0,0 +1,72 @@
+/// A group of writes that `KvsEngine::write_batch` applies atomically: after
+/// a crash either every operation in the batch is visible or none of them is.
+///
+/// Operations are applied in the order they were added. Removing a key that
+/// does not exist is not an error inside a batch.
+///
+/// ```no_run
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+///
+/// let store = KvStore::open("./database")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("user:1".to_owned(), "alice".to_owned());
+/// batch.set("user-by-name:alice".to_owned(), "1".to_owned());
+/// batch.remove("user-by-name:alicia".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok::<(), kvs::KVError>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl BatchOp {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+        }
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds setting key to value to the batch.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Adds setting the binary key to the binary value to the batch.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Adds removing key to the batch.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Adds removing the binary key to the batch.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,33 @@
+use crate::Result;
+
+/// The result of a conditional write such as `KvsEngine::set_if_equals`.
+///
+/// `KvsEngine::compare_and_swap_bytes` returns a `CasOutcome<Vec<u8>>`, which
+/// holds the value found as raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasOutcome<V = String> {
+    /// The key held the expected value and the write was applied.
+    Swapped,
+    /// The key did not hold the expected value and nothing was written. Holds
+    /// the value found instead, `None` if the key does not exist.
+    Current(Option<V>),
+}
+
+impl<V> CasOutcome<V> {
+    /// Returns true if the write was applied.
+    pub fn is_swapped(&self) -> bool {
+        matches!(self, CasOutcome::Swapped)
+    }
+}
+
+impl CasOutcome<Vec<u8>> {
+    // into_string decodes the value found as UTF-8
+    pub(crate) fn into_string(self) -> Result<CasOutcome> {
+        Ok(match self {
+            CasOutcome::Swapped => CasOutcome::Swapped,
+            CasOutcome::Current(value) => {
+                CasOutcome::Current(value.map(String::from_utf8).transpose()?)
+            }
+        })
+    }
+}
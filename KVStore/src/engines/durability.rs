@@ -0,0 +1,133 @@
+use crate::{KVError, Result};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+/// Durability decides when an acknowledged write is forced to stable storage.
+/// It applies to both `KvStore` and `SledKvsEngine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Every write is fsynced before it returns. An acknowledged write
+    /// survives a process crash, an OS crash and a power loss.
+    Always,
+    /// Writes from concurrent clients are batched into a single fsync. A write
+    /// returns only once an fsync that started after it was appended to the
+    /// log has completed, so an acknowledged write survives the same crashes
+    /// as under `Always`. Unlike `Always`, the write becomes visible to other
+    /// readers before that fsync, so a value that was read may still be lost
+    /// if the machine crashes before its writer has been acknowledged.
+    GroupCommit,
+    /// Writes are fsynced in the background at the given interval, which has
+    /// to be above zero. An interval of zero is treated as `Always`. A process
+    /// crash loses nothing for `KvStore`, while an OS crash or power loss may
+    /// lose the writes of the last interval. `SledKvsEngine` keeps unsynced
+    /// writes in its own cache, so there a process crash may lose them as well.
+    Interval(Duration),
+    /// Writes are never fsynced explicitly. `KvStore` hands every write to the
+    /// operating system, so only an OS crash or power loss can lose data.
+    /// `SledKvsEngine` may lose any write that it has not flushed on its own.
+    Never,
+}
+
+impl Durability {
+    // normalized turns an interval of zero, which would sync in a busy loop,
+    // into the per-write sync it amounts to
+    pub(crate) fn normalized(self) -> Self {
+        match self {
+            Durability::Interval(interval) if interval.is_zero() => Durability::Always,
+            durability => durability,
+        }
+    }
+}
+
+impl Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::Always => write!(f, "always"),
+            Durability::GroupCommit => write!(f, "group-commit"),
+            Durability::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+            Durability::Never => write!(f, "never"),
+        }
+    }
+}
+
+// Parses the same names Display prints: always, group-commit, interval:<ms>
+// or never. The interval has to be at least one millisecond.
+impl FromStr for Durability {
+    type Err = KVError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "group-commit" => Ok(Durability::GroupCommit),
+            "never" => Ok(Durability::Never),
+            _ => s
+                .strip_prefix("interval:")
+                .and_then(|ms| ms.parse().ok())
+                .filter(|&ms| ms > 0)
+                .map(|ms| Durability::Interval(Duration::from_millis(ms)))
+                .ok_or(KVError::ParseError),
+        }
+    }
+}
+
+/// GroupCommit lets many writers share a single sync. Every write is tagged
+/// with an increasing sequence number, and a writer waits until a sync has
+/// covered its number. Whoever finds no sync in flight runs the next one.
+pub(crate) struct GroupCommit {
+    progress: Mutex<SyncProgress>,
+    synced: Condvar,
+}
+
+struct SyncProgress {
+    // every write up to this sequence number is durable
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub(crate) fn new() -> Self {
+        Self {
+            progress: Mutex::new(SyncProgress {
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    // wait_for blocks until the write numbered seq is durable. sync must make
+    // every write issued so far durable and return the highest sequence
+    // number it covered.
+    pub(crate) fn wait_for<F>(&self, seq: u64, mut sync: F) -> Result<()>
+    where
+        F: FnMut() -> Result<u64>,
+    {
+        let mut progress = self.progress.lock().unwrap();
+
+        while progress.synced < seq {
+            if progress.syncing {
+                progress = self.synced.wait(progress).unwrap();
+                continue;
+            }
+
+            progress.syncing = true;
+            drop(progress);
+
+            let result = sync();
+
+            progress = self.progress.lock().unwrap();
+            progress.syncing = false;
+            if let Ok(covered) = result {
+                progress.synced = progress.synced.max(covered);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Expiry times are stored as milliseconds since the Unix epoch, so that they
+// keep their meaning across restarts.
+
+// how often expired keys are swept away by default
+pub(crate) const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// shorter sweep intervals are raised to this, so that the sweeper never spins
+pub(crate) const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(1);
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// expires_at returns when a key written now with the given ttl expires. Ttls
+// too long to count in milliseconds never run out.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    now_millis().saturating_add(ttl)
+}
+
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|at| at <= now)
+}
+
+// remaining returns the lifetime left until expires_at, which must not have
+// passed yet
+pub(crate) fn remaining(expires_at: u64, now: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now))
+}
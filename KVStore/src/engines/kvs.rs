@@ -1,132 +1,1395 @@
+use crate::engines::{
+    batch::BatchOp,
+    durability::GroupCommit,
+    expiry::{self, now_millis},
+    namespace::{self, NamespaceLink},
+    scan::range_is_empty,
+    transaction::{TxnSource, Version},
+    watch::Watchers,
+};
 use crate::error::{KVError, Result};
-use crate::logfile;
-use crate::KvsEngine;
+use crate::{compactfile, hintfile, logfile};
+use crate::{
+    CasOutcome, Durability, KvStoreOptions, KvsEngine, KvsSnapshot, MergeOperators, Scan,
+    Transaction, WatchFilter, Watcher, WriteBatch,
+};
 
 use crossbeam_skiplist::SkipMap;
-use serde::{Deserialize, Serialize};
+use log::{error, warn};
+use serde::Deserialize;
 use serde_json::Deserializer;
 use std::sync::atomic::AtomicU64;
-use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::sync::{atomic::Ordering, Arc, Mutex, RwLock, Weak};
 
 use std::{
     cell::RefCell,
-    collections::HashMap,
+    collections::{btree_map, hash_map::Entry, BTreeMap, HashMap},
     ffi::OsStr,
     fs::{self, File, OpenOptions},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::{Bound, RangeBounds},
     path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
+// Every log file written by this version starts with a small file header so
+// that older JSON logs can still be told apart and replayed:
+//
+//   file header: LOG_MAGIC (4 bytes) | LOG_VERSION (1 byte)
+//   record:      crc32 (u32 LE) | kind (1 byte) | key length (u32 LE) | value length (u32 LE) | key | value
+//
+// The checksum covers everything in the record after itself. Version 1 files
+// carry the same records without the leading checksum. Version 3 adds set
+// records for keys that expire, whose value starts with the expiry time in
+// milliseconds since the Unix epoch (u64 LE). Keys and values are stored as
+// the raw bytes they were given and need not be UTF-8. Version 4 adds merge
+// records, whose value is the length of the merge operator name (1 byte),
+// the name and the operand.
+//
+// The records of a write batch sit between an empty begin and an empty commit
+// record. Replay only applies them once it has read the commit record.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+const LOG_VERSION: u8 = 4;
+const LOG_HEADER_LEN: u64 = 5;
+const RECORD_HEADER_LEN: usize = 13;
+const CRC_LEN: usize = 4;
+
+const RECORD_SET: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+const RECORD_BATCH_BEGIN: u8 = 3;
+const RECORD_BATCH_COMMIT: u8 = 4;
+const RECORD_SET_EXPIRING: u8 = 5;
+const RECORD_MERGE: u8 = 6;
+
+// merge operands chained onto a key before the next merge folds them into a
+// plain value, which bounds the records a read has to visit
+const MAX_MERGE_OPERANDS: usize = 32;
+
+// A hint file sits next to every compacted generation and holds only its
+// index entries, so the generation can be loaded without reading any value:
+//
+//   hint file: HINT_MAGIC (4 bytes) | HINT_VERSION (1 byte) | entries | log length (u64 LE) | crc32 (u32 LE)
+//   entry:     key length (u32 LE) | gen (u64 LE) | pos (u64 LE) | len (u64 LE) | expires at (u64 LE, 0 if never) | key
+//
+// The log length ties the hint to the exact log it was written for, and the
+// checksum covers everything before it.
+const HINT_MAGIC: &[u8; 4] = b"KVSH";
+const HINT_VERSION: u8 = 2;
+const HINT_ENTRY_HEADER_LEN: usize = 36;
+const HINT_FOOTER_LEN: usize = 12;
+
+// every namespace is a store of its own in a directory named after it, inside
+// this directory of the store it belongs to
+const NAMESPACE_DIR: &str = "namespaces";
+// a dropped namespace is renamed with this prefix before it is deleted, so
+// that an interrupted drop cannot leave half of it behind
+const DROPPED_PREFIX: &str = ".dropped-";
 
 #[derive(Clone)]
 pub struct KvStore {
-    indexmap: Arc<SkipMap<String, DiskPos>>,
+    indexmap: Arc<SkipMap<Vec<u8>, DiskPos>>,
     reader: KvStoreReader,
     writer: Arc<Mutex<KvStoreWriter>>,
+    // held for writing while the index is updated. Replacing an entry in the
+    // skip map briefly removes it, and a batch has to show up all at once.
+    apply_lock: Arc<RwLock<()>>,
+    pins: Arc<GenerationPins>,
+    durability: Durability,
+    group: Arc<GroupCommit>,
+    watchers: Arc<Watchers>,
+    namespace: NamespaceLink<KvStore>,
+    namespace_dir: Arc<PathBuf>,
 }
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        Self::open_with_options(path, &KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<KvStore> {
+        let path = path.into();
+        let namespace_dir = Arc::new(path.join(NAMESPACE_DIR));
+        let store = Self::load(path, options, namespace_dir, NamespaceLink::root())?;
+        remove_dropped_namespaces(&store.namespace_dir)?;
+        Ok(store)
+    }
 
-        let indexmap: Arc<SkipMap<String, DiskPos>> = Arc::new(SkipMap::new());
+    // load opens the store of a single namespace at path
+    fn load(
+        path: PathBuf,
+        options: &KvStoreOptions,
+        namespace_dir: Arc<PathBuf>,
+        namespace: NamespaceLink<KvStore>,
+    ) -> Result<KvStore> {
+        let path = Arc::new(path);
+        if options.create_if_missing {
+            fs::create_dir_all(&*path)?;
+        } else if !path.is_dir() {
+            return Err(KVError::MissingDirectory {
+                path: path.display().to_string(),
+            });
+        }
+
+        let indexmap: Arc<SkipMap<Vec<u8>, DiskPos>> = Arc::new(SkipMap::new());
 
-        let gen_list = collect_file_identifiers(&path)?;
+        let gen_list = remove_compacted_generations(&path)?;
 
         let mut readers = HashMap::new();
 
         let mut need_compact = 0;
+        let mut disk_size = 0;
         for &gen in &gen_list {
-            let mut reader = KVDiskReader::new(File::open(logfile!(path, gen))?)?;
-            need_compact += reader.load_log_from_disk(&indexmap, gen)?;
+            let mut reader =
+                KVDiskReader::new(File::open(logfile!(path, gen))?, options.read_buffer_size)?;
+
+            if let Some(stale) = load_hint_file(&indexmap, &path, gen, reader.len)? {
+                need_compact += stale;
+            } else {
+                // only the newest generation was being appended to when the
+                // store was last closed, so only it may end in a torn write
+                let newest = Some(&gen) == gen_list.last();
+                need_compact += reader.load_log_from_disk(&indexmap, &path, gen, newest)?;
+            }
+            disk_size += reader.len;
             readers.insert(gen, reader);
         }
 
         let curr_gen = gen_list.last().unwrap_or(&0) + 1;
 
-        let writer = create_new_log(&path, curr_gen)?;
+        let writer = create_new_log(&path, curr_gen, options.write_buffer_size)?;
+        disk_size += writer.cursor;
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
             curr_compact: Arc::new(AtomicU64::new(0)),
             readers: RefCell::new(readers),
+            buffer_size: options.read_buffer_size,
+            operators: Arc::new(options.merge_operators.clone()),
         };
 
+        let apply_lock = Arc::new(RwLock::new(()));
+        let pins = Arc::new(GenerationPins::new(Arc::clone(&path)));
+        let watchers = Arc::new(Watchers::default());
+
         let writer = Arc::new(Mutex::new(KvStoreWriter {
             path: Arc::clone(&path),
             indexmap: Arc::clone(&indexmap),
             reader: reader.clone(),
+            apply_lock: Arc::clone(&apply_lock),
+            pins: Arc::clone(&pins),
+            watchers: Arc::clone(&watchers),
+            options: options.clone(),
             curr_gen,
             need_compact,
+            disk_size,
+            written_seq: 0,
+            compact_gen: 0,
+            compacting: false,
+            // replayed logs may hold keys that expire
+            may_expire: true,
             writer,
         }));
 
-        Ok(Self {
-            indexmap: indexmap,
-            reader,
-            writer,
-        })
+        if let Durability::Interval(interval) = options.durability {
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || sync_periodically(writer, interval));
+        }
+
+        let sweeper = Arc::downgrade(&writer);
+        let interval = options.sweep_interval;
+        thread::spawn(move || sweep_periodically(sweeper, interval));
+
+        Ok(Self {
+            indexmap,
+            reader,
+            writer,
+            apply_lock,
+            pins,
+            durability: options.durability,
+            group: Arc::new(GroupCommit::new()),
+            watchers,
+            namespace,
+            namespace_dir,
+        })
+    }
+
+    // lookup returns where the value of key is stored, unless it has expired
+    fn lookup(&self, key: &[u8]) -> Option<DiskPos> {
+        let _applied = self.apply_lock.read().unwrap();
+        self.indexmap
+            .get(key)
+            .map(|entry| entry.value().clone())
+            .filter(|pos| !pos.is_expired(now_millis()))
+    }
+
+    // version_of returns the current version of key. Callers must keep the
+    // index from changing, by holding either the writer or the apply lock.
+    // Compaction moves records too, so a transaction that spans a compaction
+    // conflicts even if none of its keys was written; retrying it succeeds.
+    fn version_of(&self, key: &[u8]) -> Version {
+        match self.indexmap.get(key) {
+            Some(entry) if entry.value().is_expired(now_millis()) => Version::Missing,
+            Some(entry) => Version::Log {
+                gen: entry.value().gen,
+                pos: entry.value().pos,
+            },
+            None => Version::Missing,
+        }
+    }
+
+    fn validate(&self, versions: &BTreeMap<Vec<u8>, Version>) -> Result<()> {
+        for (key, version) in versions {
+            if self.version_of(key) != *version {
+                return Err(KVError::TransactionConflict);
+            }
+        }
+        Ok(())
+    }
+
+    // wait_durable blocks until the write numbered seq is synced to disk
+    // together with whatever other writes are pending when group commit is on
+    fn wait_durable(&self, seq: u64) -> Result<()> {
+        if self.durability != Durability::GroupCommit {
+            return Ok(());
+        }
+
+        self.group.wait_for(seq, || {
+            let (file, covered) = self.writer.lock().unwrap().sync_handle()?;
+            file.sync_data()?;
+            Ok(covered)
+        })
+    }
+}
+
+// sweep_periodically writes tombstones for expired keys every interval until
+// the store has been dropped
+fn sweep_periodically(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Err(e) = KvStoreWriter::sweep_expired(&writer) {
+            error!("expiry sweep failed: {}", e);
+        }
+    }
+}
+
+// sync_periodically fsyncs the active log every interval until the store
+// has been dropped
+fn sync_periodically(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+
+        let handle = match writer.upgrade() {
+            Some(writer) => writer.lock().unwrap().sync_handle(),
+            None => return,
+        };
+
+        if let Err(e) = handle.and_then(|(file, _)| Ok(file.sync_data()?)) {
+            error!("periodic sync failed: {}", e);
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.remove(key)?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.set(key, val, None)?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.set(key, value, Some(expiry::expires_at(ttl)))?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.namespace.check_live()?;
+        let now = now_millis();
+        match self.lookup(&key) {
+            Some(pos) => Ok(pos.expires_at.map(|at| expiry::remaining(at, now))),
+            None => Err(KVError::KeyNoExist),
+        }
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.write_batch(batch)?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    // merge_bytes only appends the operand, which is folded in when the key
+    // is read or compacted
+    fn merge_bytes(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<()> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.merge(key, operator, operand)?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    // the writer lock keeps the value from changing between the comparison
+    // and the write
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        self.namespace.check_live()?;
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            let current = self.get_bytes(key.clone())?;
+            if current != expected {
+                return Ok(CasOutcome::Current(current));
+            }
+            match new {
+                Some(value) => writer.set(key, value, None)?,
+                None if current.is_some() => writer.remove(key)?,
+                None => return Ok(CasOutcome::Swapped),
+            }
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)?;
+        Ok(CasOutcome::Swapped)
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.namespace.check_live()?;
+        match self.lookup(&key) {
+            Some(pos) => self.read_value(&key, pos),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>> {
+        self.namespace.check_live()?;
+        Ok(Scan::new(KvStoreScan::new(self.clone(), range), limit))
+    }
+
+    fn begin(&self) -> Result<Transaction> {
+        self.namespace.check_live()?;
+        Ok(Transaction::new(self.clone()))
+    }
+
+    // a key that compaction moved counts as changed, since its version is
+    // the position of its record
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        self.namespace.check_live()?;
+        let (versions, batch) = tx.into_parts();
+        let seq = {
+            let mut writer = self.writer.lock().unwrap();
+            self.validate(&versions)?;
+            writer.write_batch(batch)?;
+            writer.maybe_compact(&self.writer)?;
+            writer.written_seq
+        };
+        self.wait_durable(seq)
+    }
+
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let _open = namespaces.lock();
+
+        let dir = self.namespace_dir.join(name);
+        if dir.exists() {
+            return Err(KVError::NamespaceExists {
+                name: name.to_owned(),
+            });
+        }
+        fs::create_dir_all(dir)?;
+        Ok(())
+    }
+
+    // namespaces are opened with the options of the store, and their handles
+    // are kept, since only one handle may write to a directory
+    fn open_namespace(&self, name: &str) -> Result<KvStore> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let mut open = namespaces.lock();
+        if let Some(store) = open.get(name) {
+            return Ok(store.clone());
+        }
+
+        let dir = self.namespace_dir.join(name);
+        if !dir.is_dir() {
+            return Err(KVError::NoNamespace {
+                name: name.to_owned(),
+            });
+        }
+        let options = self.writer.lock().unwrap().options.clone();
+        let store = Self::load(
+            dir,
+            &options,
+            Arc::clone(&self.namespace_dir),
+            self.namespace.child(name),
+        )?;
+        open.insert(name.to_owned(), store.clone());
+        Ok(store)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&*self.namespace_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if entry.file_type()?.is_dir() && namespace::check_name(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // drop_namespace waits for writes in progress before deleting the files,
+    // while later ones are turned away by the dropped handles
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let mut open = namespaces.lock();
+
+        let dir = self.namespace_dir.join(name);
+        if !dir.is_dir() {
+            return Err(KVError::NoNamespace {
+                name: name.to_owned(),
+            });
+        }
+        let store = open.remove(name);
+        if let Some(store) = &store {
+            store.namespace.mark_dropped();
+        }
+        let _writer = store.as_ref().map(|store| store.writer.lock().unwrap());
+
+        let dropped = self
+            .namespace_dir
+            .join(format!("{}{}", DROPPED_PREFIX, name));
+        fs::rename(&dir, &dropped)?;
+        fs::remove_dir_all(dropped)?;
+        Ok(())
+    }
+
+    fn watch(&self, filter: WatchFilter) -> Result<Watcher> {
+        self.namespace.check_live()?;
+        Ok(self.watchers.subscribe(filter))
+    }
+
+    type Snapshot = KvStoreSnapshot;
+
+    // snapshot copies the positions of all live keys, but none of the values.
+    // Keys that expire later stay visible in the snapshot.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        self.namespace.check_live()?;
+        let now = now_millis();
+        let applied = self.apply_lock.read().unwrap();
+        let index: BTreeMap<Vec<u8>, DiskPos> = self
+            .indexmap
+            .iter()
+            .filter(|entry| !entry.value().is_expired(now))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        // pin before the index can change, or a compaction finishing right
+        // now could delete generations the copy points into
+        let pinned = index.values().map(DiskPos::oldest_gen).min();
+        if let Some(gen) = pinned {
+            self.pins.pin(gen);
+        }
+        drop(applied);
+
+        let reader = KvStoreReader {
+            path: Arc::clone(&self.reader.path),
+            readers: RefCell::new(HashMap::new()),
+            // pinned generations are never stale for the snapshot
+            curr_compact: Arc::new(AtomicU64::new(0)),
+            buffer_size: self.reader.buffer_size,
+            operators: Arc::clone(&self.reader.operators),
+        };
+
+        Ok(KvStoreSnapshot {
+            frozen: Arc::new(FrozenIndex {
+                index,
+                pins: Arc::clone(&self.pins),
+                pinned,
+            }),
+            reader,
+        })
+    }
+
+    // flush syncs the active log of the store, then those of the namespaces
+    // opened through it, since no other log can hold unsynced writes
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().writer.sync()?;
+        let open: Vec<KvStore> = self
+            .namespace
+            .namespaces()?
+            .lock()
+            .values()
+            .cloned()
+            .collect();
+        for store in open {
+            store.writer.lock().unwrap().writer.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl TxnSource for KvStore {
+    fn read(
+        &self,
+        key: &[u8],
+        seen: &BTreeMap<Vec<u8>, Version>,
+    ) -> Result<(Option<Vec<u8>>, Version)> {
+        let (pos, version) = {
+            let _applied = self.apply_lock.read().unwrap();
+            self.validate(seen)?;
+            let pos = self
+                .indexmap
+                .get(key)
+                .map(|entry| entry.value().clone())
+                .filter(|pos| !pos.is_expired(now_millis()));
+            (pos, self.version_of(key))
+        };
+
+        match pos {
+            Some(pos) => Ok((self.read_value(key, pos)?, version)),
+            None => Ok((None, version)),
+        }
+    }
+}
+
+/// A read-only view of a `KvStore`, frozen at the moment it was taken. The log
+/// files it reads from are kept until the snapshot and all of its scans are
+/// dropped.
+pub struct KvStoreSnapshot {
+    frozen: Arc<FrozenIndex>,
+    reader: KvStoreReader,
+}
+
+struct FrozenIndex {
+    index: BTreeMap<Vec<u8>, DiskPos>,
+    pins: Arc<GenerationPins>,
+    // oldest generation the index points into
+    pinned: Option<u64>,
+}
+
+impl Drop for FrozenIndex {
+    fn drop(&mut self) {
+        if let Some(gen) = self.pinned {
+            self.pins.unpin(gen);
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.frozen.index.get(&key) {
+            Some(pos) => self.read_value(&key, pos.clone()),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>> {
+        let view = KvStoreSnapshot {
+            frozen: Arc::clone(&self.frozen),
+            reader: self.reader.clone(),
+        };
+        Ok(Scan::new(KvStoreScan::new(view, range), limit))
+    }
+}
+
+/// GenerationPins keeps the log files that snapshots read from. Compaction
+/// only marks the generations it replaced as obsolete, and their files are
+/// deleted once no snapshot points into them.
+struct GenerationPins {
+    path: Arc<PathBuf>,
+    state: Mutex<PinState>,
+}
+
+struct PinState {
+    // number of open snapshots by the oldest generation they read from
+    pinned: BTreeMap<u64, usize>,
+    // generations below obsolete_below have been compacted away
+    obsolete_below: u64,
+    // generations below removed_below have been deleted
+    removed_below: u64,
+}
+
+impl GenerationPins {
+    fn new(path: Arc<PathBuf>) -> Self {
+        Self {
+            path,
+            state: Mutex::new(PinState {
+                pinned: BTreeMap::new(),
+                obsolete_below: 0,
+                removed_below: 0,
+            }),
+        }
+    }
+
+    fn pin(&self, gen: u64) {
+        let mut state = self.state.lock().unwrap();
+        *state.pinned.entry(gen).or_insert(0) += 1;
+    }
+
+    fn unpin(&self, gen: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let btree_map::Entry::Occupied(mut count) = state.pinned.entry(gen) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+        self.remove_unpinned(&mut state);
+    }
+
+    // compacted marks every generation below gen as obsolete
+    fn compacted(&self, gen: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.obsolete_below = state.obsolete_below.max(gen);
+        self.remove_unpinned(&mut state);
+    }
+
+    fn remove_unpinned(&self, state: &mut PinState) {
+        let oldest_pinned = state.pinned.keys().next().copied().unwrap_or(u64::MAX);
+        let below = state.obsolete_below.min(oldest_pinned);
+        if below <= state.removed_below {
+            return;
+        }
+
+        // the hint file already marks these generations as obsolete, so a
+        // failed removal is finished on the next open
+        match remove_generations_before(&self.path, below) {
+            Ok(()) => state.removed_below = below,
+            Err(e) => error!("unable to remove compacted generations: {}", e),
+        }
+    }
+}
+
+// IndexView is an index that KvStoreScan can walk, together with the means
+// to read the values it points at
+trait IndexView {
+    // entry_in returns the first, or with back the last, entry in the range
+    fn entry_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        back: bool,
+    ) -> Option<(Vec<u8>, DiskPos)>;
+
+    // read_value reads the value of key, which the index placed at pos
+    fn read_value(&self, key: &[u8], pos: DiskPos) -> Result<Option<Vec<u8>>>;
+
+    // is_live tells whether the entry at pos has not expired for this view
+    fn is_live(&self, pos: &DiskPos) -> bool;
+}
+
+impl IndexView for KvStoreSnapshot {
+    fn entry_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        back: bool,
+    ) -> Option<(Vec<u8>, DiskPos)> {
+        let mut range = self
+            .frozen
+            .index
+            .range::<Vec<u8>, _>((lower.as_ref(), upper.as_ref()));
+        let (key, pos) = if back {
+            range.next_back()
+        } else {
+            range.next()
+        }?;
+        Some((key.clone(), pos.clone()))
+    }
+
+    fn read_value(&self, key: &[u8], pos: DiskPos) -> Result<Option<Vec<u8>>> {
+        Ok(Some(self.reader.read_value(key, &pos)?))
+    }
+
+    // expired keys were left out when the snapshot was taken
+    fn is_live(&self, _pos: &DiskPos) -> bool {
+        true
+    }
+}
+
+impl IndexView for KvStore {
+    fn entry_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        back: bool,
+    ) -> Option<(Vec<u8>, DiskPos)> {
+        let _applied = self.apply_lock.read().unwrap();
+        let mut range = self.indexmap.range((lower.clone(), upper.clone()));
+        let entry = if back {
+            range.next_back()
+        } else {
+            range.next()
+        }?;
+        Some((entry.key().clone(), entry.value().clone()))
+    }
+
+    fn read_value(&self, key: &[u8], mut pos: DiskPos) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.reader.read_value(key, &pos) {
+                Ok(value) => return Ok(Some(value)),
+                // a background compaction may have moved the entry and
+                // deleted its old generation since it was looked up
+                Err(e) => match self.lookup(key) {
+                    Some(new_pos) if new_pos != pos => pos = new_pos,
+                    Some(_) => return Err(e),
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+
+    fn is_live(&self, pos: &DiskPos) -> bool {
+        !pos.is_expired(now_millis())
+    }
+}
+
+/// KvStoreScan walks a range of an index from both ends. It looks up one key
+/// at a time and narrows the range past it, so it never holds on to the index.
+struct KvStoreScan<V> {
+    view: V,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+}
+
+impl<V: IndexView> KvStoreScan<V> {
+    fn new(view: V, range: impl RangeBounds<Vec<u8>>) -> Self {
+        Self {
+            view,
+            lower: range.start_bound().cloned(),
+            upper: range.end_bound().cloned(),
+        }
+    }
+
+    // step returns the next live pair from the front or the back of the range
+    fn step(&mut self, back: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            if range_is_empty(&self.lower, &self.upper) {
+                return None;
+            }
+
+            let (key, pos) = self.view.entry_in(&self.lower, &self.upper, back)?;
+
+            if back {
+                self.upper = Bound::Excluded(key.clone());
+            } else {
+                self.lower = Bound::Excluded(key.clone());
+            }
+            if !self.view.is_live(&pos) {
+                continue;
+            }
+
+            // a key removed since it was looked up is skipped
+            match self.view.read_value(&key, pos) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl<V: IndexView> Iterator for KvStoreScan<V> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+impl<V: IndexView> DoubleEndedIterator for KvStoreScan<V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+// remove_dropped_namespaces finishes deleting namespaces whose drop was
+// interrupted
+fn remove_dropped_namespaces(namespace_dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(namespace_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let dropped = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(DROPPED_PREFIX));
+        if dropped {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn collect_file_identifiers(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut fgen_list: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| res.map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+
+    fgen_list.sort_unstable();
+    Ok(fgen_list)
+}
+
+// remove_compacted_generations returns the generations that have to be loaded
+// on open. A hint file whose log is in place marks a finished compaction, so
+// every generation older than the newest hinted one is a leftover of an
+// interrupted cleanup and would resurrect removed keys if it were replayed.
+fn remove_compacted_generations(dir: &Path) -> Result<Vec<u64>> {
+    // output of a compaction that never finished
+    for gen in collect_file_identifiers(dir, "compact")? {
+        fs::remove_file(compactfile!(dir, gen))?;
+    }
+
+    let gen_list = collect_file_identifiers(dir, "log")?;
+    let mut hint_list = Vec::new();
+    for gen in collect_file_identifiers(dir, "hint")? {
+        if gen_list.binary_search(&gen).is_ok() {
+            hint_list.push(gen);
+        } else {
+            // hints are put in place before their logs are renamed
+            fs::remove_file(hintfile!(dir, gen))?;
+        }
+    }
+
+    let compacted = hint_list.last().copied().unwrap_or(0);
+
+    remove_generations_before(dir, compacted)?;
+    Ok(gen_list
+        .into_iter()
+        .filter(|&gen| gen >= compacted)
+        .collect())
+}
+
+// remove_generations_before deletes the log and hint files of every
+// generation older than gen. Files that are already gone are skipped, since
+// a store reopened on the same directory may be cleaning up concurrently.
+fn remove_generations_before(dir: &Path, gen: u64) -> Result<()> {
+    let remove = |path: PathBuf| match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    };
+
+    for old in collect_file_identifiers(dir, "log")? {
+        if old < gen {
+            remove(logfile!(dir, old))?;
+        }
+    }
+    for old in collect_file_identifiers(dir, "hint")? {
+        if old < gen {
+            remove(hintfile!(dir, old))?;
+        }
+    }
+    Ok(())
+}
+
+// insert_index points key at pos and returns the number of bytes made stale
+fn insert_index(map: &SkipMap<Vec<u8>, DiskPos>, key: Vec<u8>, pos: DiskPos) -> u64 {
+    let stale = map
+        .get(&key)
+        .map_or(0, |old_entry| old_entry.value().chain_len());
+    map.insert(key, pos);
+    stale
+}
+
+/// Struct representing a command
+#[derive(Debug)]
+enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // milliseconds since the Unix epoch, None if the key never expires
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    // an operand for the merge operator of the given name, which applies to
+    // the value the key had when it was written
+    Merge {
+        key: Vec<u8>,
+        operator: String,
+        operand: Vec<u8>,
+    },
+    BatchBegin,
+    BatchCommit,
+}
+
+/// A command as stored in the JSON logs of older versions, which only held
+/// string keys and values.
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    BatchBegin,
+    BatchCommit,
+}
+
+impl From<JsonCommand> for Command {
+    fn from(command: JsonCommand) -> Self {
+        match command {
+            JsonCommand::Set {
+                key,
+                value,
+                expires_at,
+            } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at,
+            },
+            JsonCommand::Remove { key } => Command::Remove {
+                key: key.into_bytes(),
+            },
+            JsonCommand::BatchBegin => Command::BatchBegin,
+            JsonCommand::BatchCommit => Command::BatchCommit,
+        }
+    }
+}
+
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Self {
+        match op {
+            BatchOp::Set { key, value } => Command::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            BatchOp::Remove { key } => Command::Remove { key },
+        }
+    }
+}
+
+impl Command {
+    fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } | Command::Merge { key, .. } => {
+                Some(key)
+            }
+            Command::BatchBegin | Command::BatchCommit => None,
+        }
+    }
+
+    // encode serializes the command into a single checksummed binary record
+    fn encode(&self) -> Vec<u8> {
+        let expiring;
+        let merging;
+        let (kind, key, value) = match self {
+            Command::Set {
+                key,
+                value,
+                expires_at: None,
+            } => (RECORD_SET, &key[..], &value[..]),
+            Command::Set {
+                key,
+                value,
+                expires_at: Some(at),
+            } => {
+                expiring = [&at.to_le_bytes()[..], value].concat();
+                (RECORD_SET_EXPIRING, &key[..], &expiring[..])
+            }
+            Command::Remove { key } => (RECORD_REMOVE, &key[..], &[][..]),
+            Command::Merge {
+                key,
+                operator,
+                operand,
+            } => {
+                merging = [&[operator.len() as u8][..], operator.as_bytes(), operand].concat();
+                (RECORD_MERGE, &key[..], &merging[..])
+            }
+            Command::BatchBegin => (RECORD_BATCH_BEGIN, &[][..], &[][..]),
+            Command::BatchCommit => (RECORD_BATCH_COMMIT, &[][..], &[][..]),
+        };
+
+        let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len());
+        buf.extend_from_slice(&[0u8; CRC_LEN]);
+        buf.push(kind);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+
+        let crc = crc32fast::hash(&buf[CRC_LEN..]);
+        buf[..CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // decode reads the next binary record, which may not extend past limit bytes.
+    // Checksums are only verified for logs that carry them.
+    fn decode<R: Read>(reader: &mut R, checksummed: bool, limit: u64) -> io::Result<Record> {
+        let skip = if checksummed { 0 } else { CRC_LEN };
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        let header_len = RECORD_HEADER_LEN - skip;
+
+        match read_full(reader, &mut header[skip..])? {
+            0 => return Ok(Record::End),
+            n if n < header_len => return Ok(Record::Torn),
+            _ => {}
+        }
+
+        let key_len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+        let value_len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        let len = (header_len + key_len + value_len) as u64;
+        if len > limit {
+            return Ok(Record::Torn);
+        }
+
+        let mut key = vec![0u8; key_len + value_len];
+        if read_full(reader, &mut key)? < key.len() {
+            return Ok(Record::Torn);
+        }
+
+        if checksummed {
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&header[CRC_LEN..]);
+            hasher.update(&key);
+            if hasher.finalize().to_le_bytes() != header[..CRC_LEN] {
+                return Ok(Record::Corrupt);
+            }
+        }
+
+        let mut value = key.split_off(key_len);
+
+        let expires_at = match header[CRC_LEN] {
+            RECORD_SET_EXPIRING if value.len() >= 8 => {
+                let rest = value.split_off(8);
+                Some(u64::from_le_bytes(
+                    std::mem::replace(&mut value, rest).try_into().unwrap(),
+                ))
+            }
+            RECORD_SET_EXPIRING => return Ok(Record::Corrupt),
+            _ => None,
+        };
+
+        let command = match header[CRC_LEN] {
+            RECORD_SET | RECORD_SET_EXPIRING => Command::Set {
+                key,
+                value,
+                expires_at,
+            },
+            RECORD_REMOVE => Command::Remove { key },
+            RECORD_MERGE => match decode_merge_value(value) {
+                Some((operator, operand)) => Command::Merge {
+                    key,
+                    operator,
+                    operand,
+                },
+                None => return Ok(Record::Corrupt),
+            },
+            RECORD_BATCH_BEGIN => Command::BatchBegin,
+            RECORD_BATCH_COMMIT => Command::BatchCommit,
+            _ => return Ok(Record::Corrupt),
+        };
+
+        Ok(Record::Command(command, len))
+    }
+}
+
+// decode_merge_value splits the value of a merge record into the name of its
+// operator and the operand
+fn decode_merge_value(mut value: Vec<u8>) -> Option<(String, Vec<u8>)> {
+    let name_len = *value.first()? as usize;
+    if value.len() <= name_len {
+        return None;
+    }
+    let operand = value.split_off(name_len + 1);
+    let operator = String::from_utf8(value.split_off(1)).ok()?;
+    Some((operator, operand))
+}
+
+/// The outcome of decoding a single log record.
+enum Record {
+    // a valid command and the number of bytes it occupies on disk
+    Command(Command, u64),
+    // the reader was exactly at the end of the log
+    End,
+    // the record runs past the end of the log, either because it was only
+    // partially written or because its length was corrupted
+    Torn,
+    // the record fails its checksum or cannot be parsed
+    Corrupt,
+}
+
+// read_full reads until buf is full or the reader is exhausted, returning
+// the number of bytes filled.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(filled)
 }
 
-impl KvsEngine for KvStore {
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+/// The encoding of a log file, detected from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LogFormat {
+    // pretty-printed serde_json stream written by older versions
+    Json,
+    // length-prefixed binary records behind a LOG_MAGIC header
+    Binary { checksummed: bool },
+}
+
+impl LogFormat {
+    fn read_command<R: Read>(self, mut reader: R, len: u64) -> io::Result<Record> {
+        match self {
+            LogFormat::Json => Ok(match serde_json::from_reader::<_, JsonCommand>(reader) {
+                Ok(command) => Record::Command(command.into(), len),
+                Err(e) if e.is_io() => return Err(e.into()),
+                Err(_) => Record::Corrupt,
+            }),
+            LogFormat::Binary { checksummed } => Command::decode(&mut reader, checksummed, len),
+        }
     }
+}
+
+// truncate_torn_tail cuts a partially written record off the end of a log
+fn truncate_torn_tail(dir: &Path, gen: u64, offset: u64) -> Result<()> {
+    warn!(
+        "discarding torn record at offset {} of {}",
+        offset,
+        logfile!(dir, gen).display()
+    );
+    truncate_log(dir, gen, offset)
+}
 
-    fn set(&self, key: String, val: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, val)
+fn truncate_log(dir: &Path, gen: u64, offset: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(logfile!(dir, gen))?;
+    file.set_len(offset)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Replay applies the records of a single log to the index in order. The
+/// records of a batch are held back until its commit record shows up.
+/// Values that have expired by now are dropped from the index.
+struct Replay<'a> {
+    map: &'a SkipMap<Vec<u8>, DiskPos>,
+    gen: u64,
+    now: u64,
+    need_compact: u64,
+    // offset of the open batch, if any, and the records read for it so far
+    batch_pos: Option<u64>,
+    batch: Vec<(Command, u64, u64)>,
+}
+
+impl<'a> Replay<'a> {
+    fn new(map: &'a SkipMap<Vec<u8>, DiskPos>, gen: u64) -> Self {
+        Self {
+            map,
+            gen,
+            now: now_millis(),
+            need_compact: 0,
+            batch_pos: None,
+            batch: Vec::new(),
+        }
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(pos) = self.indexmap.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KVError::GetMethodError)
+    // record replays the command read at pos, returning false when a batch
+    // marker shows up where no batch can begin or end
+    fn record(&mut self, command: Command, pos: u64, len: u64) -> bool {
+        match (command, self.batch_pos) {
+            (Command::BatchBegin, None) => {
+                self.batch_pos = Some(pos);
+                self.need_compact += len;
             }
-        } else {
-            Ok(None)
+            (Command::BatchCommit, Some(_)) => {
+                self.batch_pos = None;
+                for (command, pos, len) in std::mem::take(&mut self.batch) {
+                    self.apply(command, pos, len);
+                }
+                self.need_compact += len;
+            }
+            (Command::BatchBegin, Some(_)) | (Command::BatchCommit, None) => return false,
+            (command, Some(_)) => self.batch.push((command, pos, len)),
+            (command, None) => self.apply(command, pos, len),
         }
+        true
     }
-}
 
-fn collect_file_identifiers(dir: &Path) -> Result<Vec<u64>> {
-    let mut fgen_list: Vec<u64> = fs::read_dir(&dir)?
-        .flat_map(|res| res.and_then(|entry| Ok(entry.path())))
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
+    fn apply(&mut self, command: Command, pos: u64, len: u64) {
+        match command {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                let pos = DiskPos {
+                    gen: self.gen,
+                    pos,
+                    len,
+                    expires_at,
+                    base: None,
+                };
+                if pos.is_expired(self.now) {
+                    if let Some(old_entry) = self.map.remove(&key) {
+                        self.need_compact += old_entry.value().chain_len();
+                    }
+                    self.need_compact += len;
+                } else {
+                    self.need_compact += insert_index(self.map, key, pos);
+                }
+            }
+            Command::Remove { key } => {
+                if let Some(old_entry) = self.map.remove(&key) {
+                    self.need_compact += old_entry.value().chain_len();
+                }
+                self.need_compact += len;
+            }
+            // merge records are only written onto live values, so one without
+            // a value to apply to expired together with it
+            Command::Merge { key, .. } => match self.map.get(&key) {
+                Some(base) => {
+                    let pos = DiskPos::operand(self.gen, pos, len, base.value().clone());
+                    self.map.insert(key, pos);
+                }
+                None => self.need_compact += len,
+            },
+            Command::BatchBegin | Command::BatchCommit => self.need_compact += len,
+        }
+    }
 
-    fgen_list.sort_unstable();
-    Ok(fgen_list)
+    // uncommitted returns the offset of a batch that was never committed
+    fn uncommitted(&self) -> Option<u64> {
+        self.batch_pos
+    }
 }
 
-/// Struct representing a command
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+fn corruption(dir: &Path, gen: u64, offset: u64) -> KVError {
+    KVError::Corruption {
+        file: logfile!(dir, gen).display().to_string(),
+        offset,
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 struct DiskPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // copied from the record, so that expired keys are known without reading it
+    expires_at: Option<u64>,
+    // for a merge record, the entry its operand applies to
+    base: Option<Arc<DiskPos>>,
 }
 
 impl From<(u64, u64, u64)> for DiskPos {
     fn from((gen, pos, len): (u64, u64, u64)) -> Self {
-        Self { gen, pos, len }
+        Self {
+            gen,
+            pos,
+            len,
+            expires_at: None,
+            base: None,
+        }
+    }
+}
+
+impl DiskPos {
+    // operand places a merge record on top of base, whose expiry it shares
+    fn operand(gen: u64, pos: u64, len: u64, base: DiskPos) -> Self {
+        Self {
+            gen,
+            pos,
+            len,
+            expires_at: base.expires_at,
+            base: Some(Arc::new(base)),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        expiry::is_expired(self.expires_at, now)
+    }
+
+    // chain walks the records making up the value, newest first
+    fn chain(&self) -> impl Iterator<Item = &DiskPos> {
+        std::iter::successors(Some(self), |pos| pos.base.as_deref())
+    }
+
+    // chain_len is the number of bytes all records of the value take up
+    fn chain_len(&self) -> u64 {
+        self.chain().map(|pos| pos.len).sum()
+    }
+
+    fn oldest_gen(&self) -> u64 {
+        self.chain().map(|pos| pos.gen).min().unwrap_or(self.gen)
     }
 }
 
@@ -134,6 +1397,8 @@ struct KvStoreReader {
     path: Arc<PathBuf>,
     readers: RefCell<HashMap<u64, KVDiskReader<File>>>,
     curr_compact: Arc<AtomicU64>,
+    buffer_size: usize,
+    operators: Arc<MergeOperators>,
 }
 
 impl KvStoreReader {
@@ -142,8 +1407,8 @@ impl KvStoreReader {
         let mut readers = self.readers.borrow_mut();
 
         let delete_files: Vec<u64> = readers
-            .iter()
-            .map(|(key, _)| *key)
+            .keys()
+            .copied()
             .filter(|key| *key < curr_compact)
             .collect();
 
@@ -154,26 +1419,60 @@ impl KvStoreReader {
 
     fn read_entry_then<R, F>(&self, pos: &DiskPos, then: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut KVDiskReader<File>>) -> Result<R>,
+        F: FnOnce(LogFormat, io::Take<&mut KVDiskReader<File>>) -> Result<R>,
     {
         self.close_stale_read_handles();
 
         let mut readers = self.readers.borrow_mut();
 
-        if !readers.contains_key(&pos.gen) {
-            let r = KVDiskReader::new(File::open(logfile!(self.path, pos.gen))?)?;
-            readers.insert(pos.gen, r);
-        }
+        let r = match readers.entry(pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(KVDiskReader::new(
+                File::open(logfile!(self.path, pos.gen))?,
+                self.buffer_size,
+            )?),
+        };
 
-        let r = readers.get_mut(&pos.gen).unwrap();
         r.seek(SeekFrom::Start(pos.pos))?;
+        let format = r.format;
         let cmd_reader = r.take(pos.len);
 
-        then(cmd_reader)
+        then(format, cmd_reader)
     }
 
     fn read_command(&self, pos: &DiskPos) -> Result<Command> {
-        self.read_entry_then(pos, |take| Ok(serde_json::from_reader(take)?))
+        let record =
+            self.read_entry_then(pos, |format, take| Ok(format.read_command(take, pos.len)?))?;
+
+        match record {
+            Record::Command(command, _) => Ok(command),
+            _ => Err(corruption(&self.path, pos.gen, pos.pos)),
+        }
+    }
+
+    // read_value reads the value of key stored at pos, folding in the merge
+    // operands written on top of it from the oldest to the newest
+    fn read_value(&self, key: &[u8], pos: &DiskPos) -> Result<Vec<u8>> {
+        let mut operands = Vec::new();
+        let mut value = None;
+        for pos in pos.chain() {
+            match self.read_command(pos)? {
+                Command::Set { value: set, .. } => {
+                    value = Some(set);
+                    break;
+                }
+                Command::Merge {
+                    operator, operand, ..
+                } => operands.push((operator, operand)),
+                _ => return Err(KVError::GetMethodError),
+            }
+        }
+
+        for (operator, operand) in operands.into_iter().rev() {
+            let operator = self.operators.get(&operator)?;
+            value = Some(operator.merge(key, value.as_deref(), &operand)?);
+        }
+        value.ok_or(KVError::GetMethodError)
     }
 }
 
@@ -183,6 +1482,8 @@ impl Clone for KvStoreReader {
             path: Arc::clone(&self.path),
             curr_compact: Arc::clone(&self.curr_compact),
             readers: RefCell::new(HashMap::new()),
+            buffer_size: self.buffer_size,
+            operators: Arc::clone(&self.operators),
         }
     }
 }
@@ -190,6 +1491,8 @@ impl Clone for KvStoreReader {
 struct KVDiskReader<R: Read + Seek> {
     reader: BufReader<R>,
     cursor: u64,
+    format: LogFormat,
+    len: u64,
 }
 
 impl<R: Read + Seek> Seek for KVDiskReader<R> {
@@ -208,163 +1511,624 @@ impl<R: Read + Seek> Read for KVDiskReader<R> {
 }
 
 impl<R: Read + Seek> KVDiskReader<R> {
-    pub fn new(inner: R) -> Result<Self> {
-        let reader = BufReader::new(inner);
+    pub fn new(inner: R, buffer_size: usize) -> Result<Self> {
+        let mut reader = BufReader::with_capacity(buffer_size, inner);
+
+        let len = reader.seek(SeekFrom::End(0))?;
+
+        let mut header = [0u8; LOG_HEADER_LEN as usize];
+        reader.seek(SeekFrom::Start(0))?;
+        let format = match read_full(&mut reader, &mut header)? {
+            n if n == header.len() && &header[..4] == LOG_MAGIC => match header[4] {
+                1 => LogFormat::Binary { checksummed: false },
+                2..=LOG_VERSION => LogFormat::Binary { checksummed: true },
+                _ => return Err(KVError::LogInConsistency),
+            },
+            // the file header itself was torn, so the log holds no records
+            n if n > 0 && LOG_MAGIC.starts_with(&header[..n.min(4)]) => {
+                LogFormat::Binary { checksummed: true }
+            }
+            _ => LogFormat::Json,
+        };
+
         Ok(Self {
-            reader: reader,
+            reader,
             cursor: 0,
+            format,
+            len,
         })
     }
 
+    // load_log_from_disk replays the log of generation fgen, stored in dir,
+    // into map and returns the number of stale bytes it contains. When
+    // recover_tail is set, a torn final record and a batch that was never
+    // committed are cut off the file instead of failing the replay.
     pub fn load_log_from_disk(
         &mut self,
-        map: &Arc<SkipMap<String, DiskPos>>,
+        map: &Arc<SkipMap<Vec<u8>, DiskPos>>,
+        dir: &Path,
         fgen: u64,
+        recover_tail: bool,
     ) -> Result<u64> {
-        let ref_reader = self.reader.get_mut();
-        ref_reader.seek(SeekFrom::Start(0))?;
-        let mut stream = Deserializer::from_reader(ref_reader).into_iter::<Command>();
-        let mut pos;
-        let mut need_compact = 0;
-        loop {
-            pos = stream.byte_offset() as u64;
-            if let Some(entry) = stream.next() {
-                let len = stream.byte_offset() as u64 - pos;
-                match entry? {
-                    Command::Set { key: k, value: _v } => {
-                        if let Some(old_entry) = map.get(&k) {
-                            need_compact += old_entry.value().len;
+        let mut replay = Replay::new(map, fgen);
+
+        match self.format {
+            LogFormat::Json => {
+                self.seek(SeekFrom::Start(0))?;
+                let mut stream =
+                    Deserializer::from_reader(&mut self.reader).into_iter::<JsonCommand>();
+                loop {
+                    let pos = stream.byte_offset() as u64;
+                    match stream.next() {
+                        Some(Ok(command)) => {
+                            let len = stream.byte_offset() as u64 - pos;
+                            if !replay.record(command.into(), pos, len) {
+                                return Err(corruption(dir, fgen, pos));
+                            }
                         }
-                        map.insert(
-                            k,
-                            DiskPos {
-                                gen: fgen,
-                                pos,
-                                len,
-                            },
-                        );
+                        Some(Err(e)) if e.is_io() => return Err(e.into()),
+                        Some(Err(e)) if e.is_eof() && recover_tail => {
+                            truncate_torn_tail(dir, fgen, pos)?;
+                            break;
+                        }
+                        Some(Err(_)) => return Err(corruption(dir, fgen, pos)),
+                        None => break,
                     }
-                    Command::Remove { key: k } => {
-                        if let Some(old_entry) = map.remove(&k) {
-                            need_compact += old_entry.value().len;
+                }
+            }
+            // a log shorter than its header was torn as it was being created
+            LogFormat::Binary { .. } if self.len < LOG_HEADER_LEN => {
+                if !recover_tail {
+                    return Err(corruption(dir, fgen, 0));
+                }
+                truncate_torn_tail(dir, fgen, 0)?;
+                self.len = 0;
+            }
+            LogFormat::Binary { checksummed } => {
+                self.seek(SeekFrom::Start(LOG_HEADER_LEN))?;
+                loop {
+                    let pos = self.cursor;
+                    match Command::decode(self, checksummed, self.len - pos)? {
+                        Record::Command(command, len) => {
+                            if !replay.record(command, pos, len) {
+                                return Err(corruption(dir, fgen, pos));
+                            }
+                        }
+                        Record::End => break,
+                        Record::Torn if recover_tail && self.torn_at(pos)? => {
+                            truncate_torn_tail(dir, fgen, pos)?;
+                            self.len = pos;
+                            break;
                         }
-                        need_compact += len;
+                        Record::Torn | Record::Corrupt => return Err(corruption(dir, fgen, pos)),
                     }
                 }
-                continue;
             }
-            return Ok(need_compact);
         }
+
+        if let Some(pos) = replay.uncommitted() {
+            warn!(
+                "discarding uncommitted batch at offset {} of {}",
+                pos,
+                logfile!(dir, fgen).display()
+            );
+            if recover_tail {
+                truncate_log(dir, fgen, pos)?;
+                self.len = pos;
+            } else {
+                replay.need_compact += self.len - pos;
+            }
+        }
+
+        Ok(replay.need_compact)
+    }
+
+    // torn_at tells whether the record at pos, which runs past the end of the
+    // log, was cut short by a crash rather than given a corrupted length. A
+    // torn write can only be followed by more of itself, so any record that
+    // passes its checksum after pos shows the log to be corrupted. Logs
+    // without checksums cannot tell the two apart.
+    fn torn_at(&mut self, pos: u64) -> Result<bool> {
+        if self.format != (LogFormat::Binary { checksummed: true }) {
+            return Ok(true);
+        }
+
+        self.seek(SeekFrom::Start(pos))?;
+        let mut rest = Vec::new();
+        self.read_to_end(&mut rest)?;
+        let followed = (1..rest.len()).any(|offset| {
+            let mut record = &rest[offset..];
+            let limit = record.len() as u64;
+            matches!(
+                Command::decode(&mut record, true, limit),
+                Ok(Record::Command(..))
+            )
+        });
+        Ok(!followed)
     }
 }
 
 struct KvStoreWriter {
     // copy from KvStore
     path: Arc<PathBuf>,
-    indexmap: Arc<SkipMap<String, DiskPos>>,
+    indexmap: Arc<SkipMap<Vec<u8>, DiskPos>>,
     reader: KvStoreReader,
+    apply_lock: Arc<RwLock<()>>,
+    pins: Arc<GenerationPins>,
+    watchers: Arc<Watchers>,
+    options: KvStoreOptions,
     // writer fields
     writer: KVDiskWriter<File>,
     curr_gen: u64,
     need_compact: u64,
+    // total size of all log files
+    disk_size: u64,
+    // number of records written since open, used to match writes with syncs
+    written_seq: u64,
+    // generations below compact_gen are being, or have been, compacted away
+    compact_gen: u64,
+    compacting: bool,
+    // set when keys that expire may have been written since the last sweep
+    may_expire: bool,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
         let command = Command::Set {
-            key: key.clone(),
-            value: value,
+            key,
+            value,
+            expires_at,
         };
-        let serialized = serde_json::to_string_pretty(&command)?;
-        let (pos, len) = self.writer.write_entry(serialized)?;
-        self.writer.flush()?;
+        let (pos, len) = self.writer.write_entry(&command)?;
+        self.commit_entry()?;
 
-        let diskpos = DiskPos {
-            gen: self.curr_gen,
-            pos,
-            len,
+        self.index_commands(vec![(command, pos, len)]);
+        self.rotate_if_full()
+    }
+
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        let live = self
+            .indexmap
+            .get(&key)
+            .is_some_and(|entry| !entry.value().is_expired(now));
+        if live {
+            let command = Command::Remove { key };
+            let (pos, len) = self.writer.write_entry(&command)?;
+            self.commit_entry()?;
+
+            self.index_commands(vec![(command, pos, len)]);
+            self.rotate_if_full()
+        } else {
+            Err(KVError::KeyNoExist)
+        }
+    }
+
+    // merge appends a merge record on top of the current value of key. When
+    // there is none, or the operands chained onto it would get too many or
+    // reach into generations that are being compacted away, the operand is
+    // folded in right away and the result written as a plain value instead.
+    fn merge(&mut self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<()> {
+        let merge_operator = self.reader.operators.get(operator)?;
+        let now = now_millis();
+        let base = self
+            .indexmap
+            .get(&key)
+            .map(|entry| entry.value().clone())
+            .filter(|pos| !pos.is_expired(now));
+
+        let chainable = base.as_ref().is_some_and(|base| {
+            base.chain().count() < MAX_MERGE_OPERANDS
+                && base.chain().all(|pos| pos.gen >= self.compact_gen)
+        });
+        let command = match base {
+            Some(_) if chainable => {
+                // an operand the operator rejects would make the key unreadable
+                // once stored, so it is tried on its own first
+                merge_operator.merge(&key, None, &operand)?;
+                Command::Merge {
+                    key,
+                    operator: operator.to_owned(),
+                    operand,
+                }
+            }
+            Some(base) => {
+                let value = self.reader.read_value(&key, &base)?;
+                Command::Set {
+                    value: merge_operator.merge(&key, Some(&value), &operand)?,
+                    key,
+                    expires_at: base.expires_at,
+                }
+            }
+            None => Command::Set {
+                value: merge_operator.merge(&key, None, &operand)?,
+                key,
+                expires_at: None,
+            },
         };
 
-        if let Some(entry) = self.indexmap.get(&key) {
-            self.need_compact += entry.value().len;
+        let (pos, len) = self.writer.write_entry(&command)?;
+        self.commit_entry()?;
+
+        self.index_commands(vec![(command, pos, len)]);
+        self.rotate_if_full()
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
         }
 
-        self.indexmap.insert(key, diskpos);
+        let records = match self.append_batch(batch) {
+            Ok(records) => records,
+            Err(e) => {
+                // move on to a new generation, so that the unfinished batch
+                // is left at the tail of its log where replay discards it
+                self.curr_gen += 1;
+                if let Err(e) = self.new_active_log() {
+                    error!("unable to start a new log after a failed batch: {}", e);
+                }
+                return Err(e);
+            }
+        };
+        self.commit_entry()?;
+
+        self.index_commands(records);
+        self.rotate_if_full()
+    }
 
-        if self.need_compact > COMPACTION_THRESHOLD {
-            self.compact()?;
+    // append_batch writes the batch between its begin and commit records and
+    // returns every record written along with its position and length
+    fn append_batch(&mut self, batch: WriteBatch) -> Result<Vec<(Command, u64, u64)>> {
+        let mut records = Vec::with_capacity(batch.len() + 2);
+        let commands = std::iter::once(Command::BatchBegin)
+            .chain(batch.ops.into_iter().map(Command::from))
+            .chain(std::iter::once(Command::BatchCommit));
+
+        for command in commands {
+            let (pos, len) = self.writer.write_entry(&command)?;
+            records.push((command, pos, len));
         }
+        Ok(records)
+    }
 
-        Ok(())
+    // index_commands points the index at records that were just appended to
+    // the active log. Readers see either all of them or none of them. The
+    // changes are then published to whoever watches the keys.
+    fn index_commands(&mut self, records: Vec<(Command, u64, u64)>) {
+        let watchers = Arc::clone(&self.watchers);
+        let mut publisher = watchers.publisher();
+        let mut changed = Vec::new();
+
+        let apply_lock = Arc::clone(&self.apply_lock);
+        let applying = apply_lock.write().unwrap();
+        for (command, pos, len) in records {
+            self.disk_size += len;
+            let key = command.key().map(<[u8]>::to_vec);
+            self.index_command(command, pos, len);
+
+            // the new value is taken from the index, where merge operands
+            // have been chained onto the value they apply to
+            if let (Some(publisher), Some(key)) = (&publisher, key) {
+                if publisher.watches(&key) {
+                    let pos = self.indexmap.get(&key).map(|entry| entry.value().clone());
+                    changed.push((key, pos));
+                }
+            }
+        }
+        drop(applying);
+
+        if let Some(publisher) = &mut publisher {
+            for (key, pos) in changed {
+                match pos
+                    .map(|pos| self.reader.read_value(&key, &pos))
+                    .transpose()
+                {
+                    Ok(value) => publisher.publish(&key, value.as_deref()),
+                    Err(e) => error!("unable to read a changed value for watchers: {}", e),
+                }
+            }
+        }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.indexmap.contains_key(&key) {
-            let command = Command::Remove { key: key.clone() };
-            let serialized = serde_json::to_string_pretty(&command)?;
-            let (_pos, len) = self.writer.write_entry(serialized)?;
-            self.writer.flush()?;
+    // index_command accounts for the bytes a new record leaves stale
+    fn index_command(&mut self, command: Command, pos: u64, len: u64) {
+        match command {
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                if let Some(entry) = self.indexmap.get(&key) {
+                    self.need_compact += self.stale_len(entry.value());
+                }
+                self.may_expire |= expires_at.is_some();
+                let pos = DiskPos {
+                    gen: self.curr_gen,
+                    pos,
+                    len,
+                    expires_at,
+                    base: None,
+                };
+                self.indexmap.insert(key, pos);
+            }
+            Command::Merge { key, .. } => match self.indexmap.get(&key) {
+                Some(base) => {
+                    let pos = DiskPos::operand(self.curr_gen, pos, len, base.value().clone());
+                    self.indexmap.insert(key, pos);
+                }
+                None => self.need_compact += len,
+            },
+            Command::Remove { key } => {
+                if let Some(entry) = self.indexmap.remove(&key) {
+                    self.need_compact += self.stale_len(entry.value());
+                }
+                // the "remove" command itself can be deleted in the next compaction
+                self.need_compact += len;
+            }
+            Command::BatchBegin | Command::BatchCommit => self.need_compact += len,
+        }
+    }
 
-            let stale_len = self
-                .indexmap
-                .remove(&key)
-                .expect("Key not found!")
-                .value()
-                .len;
+    // sweep_expired writes a tombstone for every key that has expired, so
+    // that compaction reclaims its space. The index is searched without
+    // holding the writer lock, which handle must be.
+    fn sweep_expired(handle: &Arc<Mutex<KvStoreWriter>>) -> Result<()> {
+        let indexmap = {
+            let mut writer = handle.lock().unwrap();
+            if !writer.may_expire {
+                return Ok(());
+            }
+            writer.may_expire = false;
+            Arc::clone(&writer.indexmap)
+        };
 
-            self.need_compact += stale_len;
-            // the "remove" command itself can be deleted in the next compaction
-            self.need_compact += len;
+        let now = now_millis();
+        let mut may_expire = false;
+        let mut expired = Vec::new();
+        for entry in indexmap.iter() {
+            may_expire |= entry.value().expires_at.is_some();
+            if entry.value().is_expired(now) {
+                expired.push((entry.key().clone(), entry.value().clone()));
+            }
+        }
 
-            if self.need_compact > COMPACTION_THRESHOLD {
-                self.compact()?;
+        let mut writer = handle.lock().unwrap();
+        writer.may_expire |= may_expire;
+
+        let mut records = Vec::new();
+        for (key, pos) in expired {
+            // skip keys that were written again while searching
+            if writer.indexmap.get(&key).map(|e| e.value().clone()) != Some(pos) {
+                continue;
             }
+            let command = Command::Remove { key };
+            let (pos, len) = writer.writer.write_entry(&command)?;
+            records.push((command, pos, len));
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
 
-            Ok(())
-        } else {
-            Err(KVError::KeyNoExist)
+        writer.commit_entry()?;
+        writer.index_commands(records);
+        writer.rotate_if_full()?;
+        writer.maybe_compact(handle)
+    }
+
+    // commit_entry hands a freshly written record to the operating system, so
+    // that readers can see it, and syncs it when every write must be durable
+    fn commit_entry(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.written_seq += 1;
+        if self.options.durability == Durability::Always {
+            self.writer.sync()?;
+        }
+        Ok(())
+    }
+
+    // sync_handle returns a handle to the active log for syncing outside of
+    // the writer lock, along with the last write the sync will cover
+    fn sync_handle(&mut self) -> Result<(File, u64)> {
+        self.writer.flush()?;
+        Ok((self.writer.writer.get_ref().try_clone()?, self.written_seq))
+    }
+
+    // rotate_if_full moves writes on to a new generation once the active log
+    // has outgrown the configured file size
+    fn rotate_if_full(&mut self) -> Result<()> {
+        if self.writer.cursor >= self.options.max_file_size {
+            self.curr_gen += 1;
+            self.new_active_log()?;
+        }
+        Ok(())
+    }
+
+    fn new_active_log(&mut self) -> Result<()> {
+        // syncs of the new log will not cover what is still pending in the old one
+        if self.options.durability != Durability::Never {
+            self.writer.sync()?;
+        }
+
+        self.writer = create_new_log(&self.path, self.curr_gen, self.options.write_buffer_size)?;
+        self.disk_size += self.writer.cursor;
+
+        if self.options.durability != Durability::Never {
+            File::open(&*self.path)?.sync_all()?;
         }
+        Ok(())
+    }
+
+    // stale_len is the number of bytes that overwriting the entry at pos
+    // leaves behind. Entries in generations that are already being compacted
+    // are dropped together with their files and do not count.
+    fn stale_len(&self, pos: &DiskPos) -> u64 {
+        pos.chain()
+            .filter(|pos| pos.gen >= self.compact_gen)
+            .map(|pos| pos.len)
+            .sum()
     }
 
-    fn compact(&mut self) -> Result<()> {
+    // maybe_compact starts a background compaction once enough stale data
+    // has piled up and no other compaction is running. handle must be the
+    // mutex guarding self.
+    fn maybe_compact(&mut self, handle: &Arc<Mutex<KvStoreWriter>>) -> Result<()> {
+        let trigger = self.options.compaction_trigger;
+        if self.compacting || !trigger.should_compact(self.need_compact, self.disk_size) {
+            return Ok(());
+        }
+
+        // freeze everything written so far: new writes go to a fresh
+        // generation above the one the compaction writes into
+        let frozen_size = self.disk_size;
         let gen_compact = self.curr_gen + 1;
         self.curr_gen += 2;
+        self.new_active_log()?;
+
+        self.compact_gen = gen_compact;
+        self.compacting = true;
+        self.need_compact = 0;
 
-        self.writer = create_new_log(&self.path, self.curr_gen)?;
+        let compaction = Compaction {
+            path: Arc::clone(&self.path),
+            indexmap: Arc::clone(&self.indexmap),
+            reader: self.reader.clone(),
+            writer: Arc::clone(handle),
+            pins: Arc::clone(&self.pins),
+            gen: gen_compact,
+            frozen_size,
+            write_buffer_size: self.options.write_buffer_size,
+        };
 
-        let mut compact_writer = create_new_log(&self.path, gen_compact)?;
+        thread::spawn(move || compaction.run());
 
-        let mut pos = 0;
-        for entry in self.indexmap.iter() {
-            let len = self.reader.read_entry_then(&entry.value(), |mut take| {
-                Ok(io::copy(&mut take, &mut compact_writer)?)
-            })?;
+        Ok(())
+    }
+}
 
-            self.indexmap
-                .insert(entry.key().clone(), (gen_compact, pos, len).into());
+/// Compaction copies the live entries of all frozen generations into a
+/// single new generation on a background thread, while writers keep
+/// appending to the active generation.
+struct Compaction {
+    path: Arc<PathBuf>,
+    indexmap: Arc<SkipMap<Vec<u8>, DiskPos>>,
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    pins: Arc<GenerationPins>,
+    gen: u64,
+    // size of the frozen generations this compaction replaces
+    frozen_size: u64,
+    write_buffer_size: usize,
+}
 
-            pos += len;
+impl Compaction {
+    fn run(self) {
+        match self.copy_live_entries() {
+            Ok((moved, len)) => self.install(moved, len),
+            Err(e) => {
+                error!("compaction into generation {} failed: {}", self.gen, e);
+                let _ = fs::remove_file(compactfile!(self.path, self.gen));
+
+                // the frozen generations stay in place for the next attempt
+                let mut writer = self.writer.lock().unwrap();
+                writer.compact_gen = 0;
+                writer.compacting = false;
+            }
         }
+    }
+
+    // copy_live_entries writes every entry of the frozen generations into the
+    // compaction output and returns each key with its old and new position,
+    // along with the size of the new generation. Expired entries are left
+    // behind and have no new position.
+    #[allow(clippy::type_complexity)]
+    fn copy_live_entries(&self) -> Result<(Vec<(Vec<u8>, DiskPos, Option<DiskPos>)>, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(compactfile!(self.path, self.gen))?;
+        let mut compact_writer = KVDiskWriter::new(file, 0, self.write_buffer_size)?;
+        let mut hint_writer = HintWriter::create(&self.path, self.gen)?;
+        let mut moved = Vec::new();
+        let now = now_millis();
 
-        compact_writer.flush()?;
+        for entry in self.indexmap.iter() {
+            let old_pos = entry.value().clone();
+            if old_pos.gen >= self.gen {
+                continue;
+            }
+            if old_pos.is_expired(now) {
+                moved.push((entry.key().clone(), old_pos, None));
+                continue;
+            }
 
-        self.reader
-            .curr_compact
-            .store(gen_compact, Ordering::SeqCst);
-        self.reader.close_stale_read_handles();
+            // values are read and written out again rather than copied verbatim,
+            // so that values still living in legacy JSON logs move to the binary
+            // format and merge operands are folded into the value they apply to
+            let command = Command::Set {
+                key: entry.key().clone(),
+                value: self.reader.read_value(entry.key(), &old_pos)?,
+                expires_at: old_pos.expires_at,
+            };
+            let (pos, len) = compact_writer.write_entry(&command)?;
+            let new_pos = DiskPos {
+                gen: self.gen,
+                pos,
+                len,
+                expires_at: old_pos.expires_at,
+                base: None,
+            };
+
+            hint_writer.add(entry.key(), &new_pos)?;
+            moved.push((entry.key().clone(), old_pos, Some(new_pos)));
+        }
 
-        let old_files: Vec<u64> = collect_file_identifiers(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < gen_compact)
-            .collect();
+        // the hint goes in place first, as a hint only counts once its log
+        // exists, and the directory is synced before install removes the
+        // frozen generations so their copy is never lost with them
+        compact_writer.sync()?;
+        hint_writer.finish(compact_writer.cursor)?;
+        fs::rename(
+            compactfile!(self.path, self.gen),
+            logfile!(self.path, self.gen),
+        )?;
+        File::open(&*self.path)?.sync_all()?;
+
+        Ok((moved, compact_writer.cursor))
+    }
 
-        for gen in old_files {
-            fs::remove_file(logfile!(self.path, gen))?;
+    // install points the index at the compacted copies and drops the frozen
+    // generations, along with the expired entries that were not copied.
+    // Entries that were overwritten or removed while copying keep their
+    // newer position.
+    fn install(&self, moved: Vec<(Vec<u8>, DiskPos, Option<DiskPos>)>, len: u64) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.disk_size = writer.disk_size - self.frozen_size + len;
+
+        for (key, old_pos, new_pos) in moved {
+            let _applying = writer.apply_lock.write().unwrap();
+            if self.indexmap.get(&key).map(|e| e.value().clone()) == Some(old_pos) {
+                match new_pos {
+                    Some(new_pos) => {
+                        self.indexmap.insert(key, new_pos);
+                    }
+                    None => {
+                        self.indexmap.remove(&key);
+                    }
+                }
+            }
         }
 
-        self.need_compact = 0;
+        self.reader.curr_compact.store(self.gen, Ordering::SeqCst);
+        writer.compacting = false;
 
-        Ok(())
+        self.pins.compacted(self.gen);
+
+        // writes made while copying, such as expiry sweeps, may already have
+        // left enough stale data for another round, and nothing else would
+        // start it if no more writes follow
+        if let Err(e) = writer.maybe_compact(&self.writer) {
+            error!("unable to start the next compaction: {}", e);
+        }
     }
 }
 
@@ -386,29 +2150,182 @@ impl<W: Write + Seek> Write for KVDiskWriter<W> {
 }
 
 impl<W: Write + Seek> KVDiskWriter<W> {
-    pub fn new(inner: W) -> Result<Self> {
-        let writer = BufWriter::new(inner);
-        Ok(Self {
-            writer: writer,
-            cursor: 0,
-        })
+    // new wraps a log file whose current length is cursor. An empty file
+    // gets the binary log header before any record is appended.
+    pub fn new(inner: W, cursor: u64, buffer_size: usize) -> Result<Self> {
+        let mut writer = Self {
+            writer: BufWriter::with_capacity(buffer_size, inner),
+            cursor,
+        };
+
+        if cursor == 0 {
+            writer.write_all(LOG_MAGIC)?;
+            writer.write_all(&[LOG_VERSION])?;
+            writer.flush()?;
+        }
+
+        Ok(writer)
     }
 
-    pub fn write_entry(&mut self, serialized: String) -> Result<(u64, u64)> {
-        let len = self.writer.write(serialized.as_bytes())? as u64;
+    pub fn write_entry(&mut self, command: &Command) -> Result<(u64, u64)> {
+        let record = command.encode();
         let old_pos = self.cursor;
-        self.cursor = old_pos + len;
-        Ok((old_pos, len))
+        self.write_all(&record)?;
+        Ok((old_pos, record.len() as u64))
+    }
+}
+
+impl KVDiskWriter<File> {
+    // sync flushes buffered records and waits until they reach the disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
     }
 }
 
-fn create_new_log(path: &Path, curr_gen: u64) -> Result<KVDiskWriter<File>> {
+fn create_new_log(path: &Path, curr_gen: u64, buffer_size: usize) -> Result<KVDiskWriter<File>> {
     let file = OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(logfile!(path, curr_gen))?;
 
-    let writer = KVDiskWriter::new(file)?;
+    let cursor = file.metadata()?.len();
+    let writer = KVDiskWriter::new(file, cursor, buffer_size)?;
     Ok(writer)
 }
+
+/// HintWriter streams the index entries of a compacted generation into its
+/// hint file. The file only appears under its final name once it is complete.
+struct HintWriter {
+    writer: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl HintWriter {
+    fn create(dir: &Path, gen: u64) -> Result<Self> {
+        let path = hintfile!(dir, gen);
+        let tmp_path = path.with_extension("hint.tmp");
+
+        let mut writer = Self {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            hasher: crc32fast::Hasher::new(),
+            tmp_path,
+            path,
+        };
+        writer.write_all(HINT_MAGIC)?;
+        writer.write_all(&[HINT_VERSION])?;
+        Ok(writer)
+    }
+
+    fn add(&mut self, key: &[u8], pos: &DiskPos) -> Result<()> {
+        self.write_all(&(key.len() as u32).to_le_bytes())?;
+        self.write_all(&pos.gen.to_le_bytes())?;
+        self.write_all(&pos.pos.to_le_bytes())?;
+        self.write_all(&pos.len.to_le_bytes())?;
+        self.write_all(&pos.expires_at.unwrap_or(0).to_le_bytes())?;
+        self.write_all(key)
+    }
+
+    // finish seals the hint file for a log of log_len bytes
+    fn finish(mut self, log_len: u64) -> Result<()> {
+        self.write_all(&log_len.to_le_bytes())?;
+        let crc = self.hasher.clone().finalize();
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.hasher.update(buf);
+        self.writer.write_all(buf)?;
+        Ok(())
+    }
+}
+
+// load_hint_file fills map from the hint file of generation gen and returns
+// the number of stale bytes, or None when there is no usable hint file and
+// the log has to be replayed instead
+fn load_hint_file(
+    map: &SkipMap<Vec<u8>, DiskPos>,
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+) -> Result<Option<u64>> {
+    let content = match fs::read(hintfile!(dir, gen)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let entries = match parse_hint_file(&content, log_len) {
+        Some(entries) => entries,
+        None => {
+            warn!("ignoring stale hint file {}", hintfile!(dir, gen).display());
+            return Ok(None);
+        }
+    };
+
+    let now = now_millis();
+    let mut need_compact = 0;
+    for (key, pos) in entries {
+        if pos.is_expired(now) {
+            need_compact += pos.len;
+            if let Some(old_entry) = map.remove(&key) {
+                need_compact += old_entry.value().chain_len();
+            }
+        } else {
+            need_compact += insert_index(map, key, pos);
+        }
+    }
+    Ok(Some(need_compact))
+}
+
+// parse_hint_file decodes all entries of a hint file, or returns None when
+// the file is damaged or was written for a log of a different length
+fn parse_hint_file(content: &[u8], log_len: u64) -> Option<Vec<(Vec<u8>, DiskPos)>> {
+    let header_len = HINT_MAGIC.len() + 1;
+    if content.len() < header_len + HINT_FOOTER_LEN
+        || &content[..4] != HINT_MAGIC
+        || content[4] != HINT_VERSION
+    {
+        return None;
+    }
+
+    let (body, crc) = content.split_at(content.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return None;
+    }
+
+    let (mut entries, hinted_len) = body[header_len..].split_at(body.len() - header_len - 8);
+    if u64::from_le_bytes(hinted_len.try_into().unwrap()) != log_len {
+        return None;
+    }
+
+    let read_u64 = |buf: &[u8]| u64::from_le_bytes(buf.try_into().unwrap());
+    let mut index = Vec::new();
+    while !entries.is_empty() {
+        if entries.len() < HINT_ENTRY_HEADER_LEN {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(entries[..4].try_into().unwrap()) as usize;
+        let pos = DiskPos {
+            gen: read_u64(&entries[4..12]),
+            pos: read_u64(&entries[12..20]),
+            len: read_u64(&entries[20..28]),
+            expires_at: Some(read_u64(&entries[28..36])).filter(|&at| at != 0),
+            base: None,
+        };
+
+        let key = entries.get(HINT_ENTRY_HEADER_LEN..HINT_ENTRY_HEADER_LEN + key_len)?;
+        index.push((key.to_vec(), pos));
+        entries = &entries[HINT_ENTRY_HEADER_LEN + key_len..];
+    }
+
+    Some(index)
+}
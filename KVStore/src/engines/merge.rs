@@ -0,0 +1,120 @@
+use crate::{KVError, Result};
+use std::{collections::BTreeMap, fmt, str, sync::Arc};
+
+/// Folds a merge operand into the value of a key, for `KvsEngine::merge`.
+///
+/// Operands may be stored and folded in later, so merging has to give the
+/// same result whenever it runs: it may only depend on its arguments. Before
+/// an operand is stored, it is merged onto a missing value to check that the
+/// operator accepts it.
+pub trait MergeOperator: Send + Sync + 'static {
+    /// Returns the value of key after applying operand to existing, its
+    /// current value or None if the key does not exist.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Appends the operand to the value.
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        Ok([existing.unwrap_or_default(), operand].concat())
+    }
+}
+
+/// Keeps the larger of the value and the operand, both decimal integers like
+/// the counters of `KvsEngine::incr`.
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        let operand = parse_integer(operand)?;
+        let max = match existing {
+            Some(existing) => parse_integer(existing)?.max(operand),
+            None => operand,
+        };
+        Ok(max.to_string().into_bytes())
+    }
+}
+
+/// Treats the value as a set of newline separated elements and adds the
+/// elements of the operand to it. The set is kept sorted and free of
+/// duplicates.
+pub struct SetUnion;
+
+impl MergeOperator for SetUnion {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        let mut set: Vec<&[u8]> = existing
+            .into_iter()
+            .chain(Some(operand))
+            .flat_map(|elements| elements.split(|&b| b == b'\n'))
+            .filter(|element| !element.is_empty())
+            .collect();
+        set.sort_unstable();
+        set.dedup();
+        Ok(set.join(&b'\n'))
+    }
+}
+
+/// The merge operators an engine knows, by name. Every engine knows the
+/// built-in "append", "max" and "set-union" operators.
+#[derive(Clone)]
+pub struct MergeOperators {
+    operators: BTreeMap<String, Arc<dyn MergeOperator>>,
+}
+
+impl Default for MergeOperators {
+    fn default() -> Self {
+        let mut operators = Self {
+            operators: BTreeMap::new(),
+        };
+        operators
+            .register("append", Append)
+            .register("max", Max)
+            .register("set-union", SetUnion);
+        operators
+    }
+}
+
+impl fmt::Debug for MergeOperators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.operators.keys()).finish()
+    }
+}
+
+impl MergeOperators {
+    /// Adds operator under name, replacing any operator of the same name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if name is longer than 255 bytes.
+    pub fn register(&mut self, name: &str, operator: impl MergeOperator) -> &mut Self {
+        assert!(
+            name.len() <= MAX_OPERATOR_NAME_LEN,
+            "merge operator names are at most {} bytes",
+            MAX_OPERATOR_NAME_LEN
+        );
+        self.operators.insert(name.to_owned(), Arc::new(operator));
+        self
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<&dyn MergeOperator> {
+        match self.operators.get(name) {
+            Some(operator) => Ok(operator.as_ref()),
+            None => Err(KVError::UnknownMergeOperator {
+                name: name.to_owned(),
+            }),
+        }
+    }
+}
+
+// the name of an operator is stored in front of every operand in a single byte
+pub(crate) const MAX_OPERATOR_NAME_LEN: usize = u8::MAX as usize;
+
+// parse_integer reads a counter, which is stored as a decimal integer
+pub(crate) fn parse_integer(value: &[u8]) -> Result<i64> {
+    str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(KVError::NotAnInteger)
+}
@@ -1,21 +1,321 @@
-use crate::Result;
+use crate::{KVError, Result};
+use std::{ops::RangeBounds, time::Duration};
 
+/// A key-value storage engine.
+///
+/// Keys and values are arbitrary bytes. The `_bytes` methods work on them
+/// directly, while the methods taking `String` are conveniences for text,
+/// which fail with `InvalidUtf8` when they come across a key or value that is
+/// not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a binary key to a binary value.
+    /// Return an error if the value is not written successfully.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a binary key to a binary value that expires after
+    /// ttl. Once expired the key reads as if it did not exist. Writing the key
+    /// again without a ttl makes it permanent.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Returns the remaining lifetime of a binary key, or None if it never
+    /// expires. Return `KeyNoExist` if the key does not exist.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// Get the value of a binary key. If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a given binary key.
+    /// Return an error if the key does not exit or value is not read successfully.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
     /// Sets the value of a string key to a string.
     /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after ttl.
+    /// Once expired the key reads as if it did not exist. Writing the key
+    /// again without a ttl makes it permanent.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Returns the remaining lifetime of a key, or None if it never expires.
+    /// Return `KeyNoExist` if the key does not exist.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
 
     /// Get the string value of a string key. If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Return an error if the value is not read successfully, or
+    /// `InvalidUtf8` if it is not a string.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.into_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
 
     /// Removes a given key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Applies every operation in batch atomically.
+    /// Return an error if the batch is not written successfully, in which case
+    /// none of its operations take effect.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Atomically replaces the value of a binary key with new if it currently
+    /// is expected, where `None` stands for a missing key on either side.
+    /// Returns the value found instead if it is not.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>>;
+
+    /// Atomically replaces the value of key with new if it currently is
+    /// expected, where `None` stands for a missing key on either side.
+    /// Returns the value found instead if it is not.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<CasOutcome> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?
+        .into_string()
+    }
+
+    /// Sets key to value only if the key does not exist yet.
+    fn set_if_absent(&self, key: String, value: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Sets key to value only if its current value is expected.
+    fn set_if_equals(&self, key: String, expected: String, value: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, Some(expected), Some(value))
+    }
+
+    /// Removes key only if its current value is expected.
+    fn remove_if_equals(&self, key: String, expected: String) -> Result<CasOutcome> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Atomically adds delta to the counter stored at a binary key, as a
+    /// decimal integer, and returns its new value. A missing key counts as 0.
+    /// Return `NotAnInteger` if the key holds something else, or `Overflow`
+    /// if the result does not fit in an i64; the counter is left unchanged
+    /// either way. Like other conditional writes, this clears the ttl.
+    fn incr_bytes(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.get_bytes(key.clone())?;
+            let counter = match &current {
+                Some(value) => merge::parse_integer(value)?,
+                None => 0,
+            };
+            let counter = counter.checked_add(delta).ok_or(KVError::Overflow)?;
+            let new = Some(counter.to_string().into_bytes());
+            if self
+                .compare_and_swap_bytes(key.clone(), current, new)?
+                .is_swapped()
+            {
+                return Ok(counter);
+            }
+        }
+    }
+
+    /// Atomically adds delta to the counter stored at key and returns its new
+    /// value, see `incr_bytes`.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr_bytes(key.into_bytes(), delta)
+    }
+
+    /// Atomically subtracts delta from the counter stored at key and returns
+    /// its new value, see `incr_bytes`.
+    fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KVError::Overflow)?)
+    }
+
+    /// Merges operand into the value of a binary key with the merge operator
+    /// registered under the given name. The key keeps its ttl, if any.
+    /// Return `UnknownMergeOperator` if there is no such operator.
+    fn merge_bytes(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<()>;
+
+    /// Merges operand into the value of key with the merge operator
+    /// registered under the given name, see `merge_bytes`.
+    fn merge(&self, key: String, operator: &str, operand: String) -> Result<()> {
+        self.merge_bytes(key.into_bytes(), operator, operand.into_bytes())
+    }
+
+    /// Returns the binary key/value pairs whose keys fall in range, ordered
+    /// by key, stopping after limit pairs if one is given.
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>>;
+
+    /// Returns the binary key/value pairs whose keys start with prefix,
+    /// ordered by key, stopping after limit pairs if one is given.
+    fn scan_prefix_bytes(&self, prefix: &[u8], limit: Option<usize>) -> Result<Scan<Vec<u8>>> {
+        self.scan_bytes(scan::prefix_range(prefix), limit)
+    }
+
+    /// Returns the key/value pairs whose keys fall in range, ordered by key,
+    /// stopping after limit pairs if one is given.
+    fn scan(&self, range: impl RangeBounds<String>, limit: Option<usize>) -> Result<Scan> {
+        Ok(self
+            .scan_bytes(scan::byte_range(range), limit)?
+            .into_strings())
+    }
+
+    /// Returns the key/value pairs whose keys start with prefix, ordered by
+    /// key, stopping after limit pairs if one is given.
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Scan> {
+        Ok(self
+            .scan_prefix_bytes(prefix.as_bytes(), limit)?
+            .into_strings())
+    }
+
+    /// Creates an empty namespace called name, which can then be opened with
+    /// `open_namespace`. Names are made of up to 64 ASCII letters, digits,
+    /// '-', '_' or '.' and may not start with a '.'.
+    /// Return `NamespaceExists` if there already is one of that name.
+    fn create_namespace(&self, name: &str) -> Result<()>;
+
+    /// Returns a handle to the namespace called name. It offers the same
+    /// operations as the engine, on keys of its own that are kept apart from
+    /// those of the engine and of every other namespace. Namespaces are
+    /// shared by the whole store, so any handle can open any of them.
+    /// Return `NoNamespace` if it has not been created.
+    fn open_namespace(&self, name: &str) -> Result<Self>;
+
+    /// Returns the names of all namespaces of the store, in order.
+    fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// Deletes the namespace called name along with all of its keys. Handles
+    /// to it fail with `NoNamespace` from then on.
+    fn drop_namespace(&self, name: &str) -> Result<()>;
+
+    /// Returns a `Watcher` that is told about every change to the keys
+    /// filter matches from now on, once it has committed. Keys that are
+    /// removed when they expire are reported as removed too, whenever the
+    /// background sweep gets to them.
+    fn watch(&self, filter: WatchFilter) -> Result<Watcher>;
+
+    type Snapshot: KvsSnapshot;
+
+    /// Returns a read-only view of every key as it is right now. Writes made
+    /// after this call returns are not visible through the snapshot.
+    ///
+    /// The cost depends on the engine. `KvStore` copies where every key is
+    /// stored, but none of the values, and keeps the log files they point
+    /// into from being compacted away. `SledKvsEngine` has no point-in-time
+    /// reads, so it copies every live key and value into memory and blocks
+    /// all writers until the copy is done, which takes time and memory in
+    /// proportion to the whole data set.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Starts a transaction. Nothing it writes is visible until it is
+    /// passed to `commit`.
+    fn begin(&self) -> Result<Transaction>;
+
+    /// Applies the writes of tx atomically.
+    /// Return `TransactionConflict` without applying anything if a key the
+    /// transaction read or wrote has been changed since it first used it.
+    fn commit(&self, tx: Transaction) -> Result<()>;
+
+    /// Runs f in a new transaction and commits it, returning what f returned.
+    /// Nothing is written if f fails. On `TransactionConflict` f can simply
+    /// be run again.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        let mut tx = self.begin()?;
+        let value = f(&mut tx)?;
+        self.commit(tx)?;
+        Ok(value)
+    }
+
+    /// Forces every write acknowledged so far to stable storage, whatever
+    /// durability the store was opened with. Called on the handle the store
+    /// was opened with, it covers all of its namespaces.
+    fn flush(&self) -> Result<()>;
+}
+
+/// A read-only view of an engine, frozen at the moment it was taken.
+pub trait KvsSnapshot: Send + 'static {
+    /// Get the value of a binary key as it was when the snapshot was taken.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the binary key/value pairs whose keys fall in range, ordered
+    /// by key, stopping after limit pairs if one is given.
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>>;
+
+    /// Get the string value of a string key as it was when the snapshot was taken.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.into_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Returns the key/value pairs whose keys fall in range, ordered by key,
+    /// stopping after limit pairs if one is given.
+    fn scan(&self, range: impl RangeBounds<String>, limit: Option<usize>) -> Result<Scan> {
+        Ok(self
+            .scan_bytes(scan::byte_range(range), limit)?
+            .into_strings())
+    }
+
+    /// Returns the binary key/value pairs whose keys start with prefix,
+    /// ordered by key, stopping after limit pairs if one is given.
+    fn scan_prefix_bytes(&self, prefix: &[u8], limit: Option<usize>) -> Result<Scan<Vec<u8>>> {
+        self.scan_bytes(scan::prefix_range(prefix), limit)
+    }
+
+    /// Returns the key/value pairs whose keys start with prefix, ordered by
+    /// key, stopping after limit pairs if one is given.
+    fn scan_prefix(&self, prefix: &str, limit: Option<usize>) -> Result<Scan> {
+        Ok(self
+            .scan_prefix_bytes(prefix.as_bytes(), limit)?
+            .into_strings())
+    }
 }
 
+mod async_engine;
+mod batch;
+mod cas;
+mod durability;
+pub(crate) mod expiry;
 mod kvs;
+mod merge;
+mod namespace;
+mod options;
+pub(crate) mod scan;
 mod sled;
+mod transaction;
+mod watch;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::batch::WriteBatch;
+pub use self::cas::CasOutcome;
+pub use self::durability::Durability;
+pub use self::kvs::{KvStore, KvStoreSnapshot};
+pub use self::merge::{Append, Max, MergeOperator, MergeOperators, SetUnion};
+pub use self::options::{CompactionTrigger, KvStoreOptions};
+pub use self::scan::Scan;
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::transaction::Transaction;
+pub use self::watch::{Change, ChangeOp, WatchFilter, Watcher};
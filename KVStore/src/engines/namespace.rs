@@ -0,0 +1,101 @@
+use crate::{KVError, Result};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+};
+
+// longest namespace name, which has to fit in a file name
+const MAX_NAME_LEN: usize = 64;
+
+// check_name accepts names made of ASCII letters, digits, '-', '_' and '.',
+// which are safe to use as file and tree names. Names may not start with a
+// '.', which the engines keep for their own use.
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(KVError::InvalidNamespace {
+            name: name.to_owned(),
+        })
+    }
+}
+
+/// Namespaces holds a handle to every namespace of a store that has been
+/// opened, so that all handles to a namespace share one writer.
+pub(crate) struct Namespaces<E> {
+    open: Mutex<HashMap<String, E>>,
+}
+
+impl<E> Namespaces<E> {
+    // lock also keeps namespaces from being created or dropped concurrently
+    pub(crate) fn lock(&self) -> MutexGuard<'_, HashMap<String, E>> {
+        self.open.lock().unwrap()
+    }
+}
+
+/// NamespaceLink ties a handle to the namespaces of its store, and tells it
+/// whether its own namespace has been dropped.
+///
+/// The handles kept in `Namespaces` link back to it, so they only hold on to
+/// it weakly. The handle a store was opened with owns it, and its namespaces
+/// are closed along with it.
+#[derive(Clone)]
+pub(crate) struct NamespaceLink<E> {
+    namespaces: Weak<Namespaces<E>>,
+    _owned: Option<Arc<Namespaces<E>>>,
+    // None for the default namespace, which cannot be dropped
+    name: Option<Arc<str>>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl<E> NamespaceLink<E> {
+    // root creates the namespaces of a newly opened store
+    pub(crate) fn root() -> Self {
+        let namespaces = Arc::new(Namespaces {
+            open: Mutex::new(HashMap::new()),
+        });
+        Self {
+            namespaces: Arc::downgrade(&namespaces),
+            _owned: Some(namespaces),
+            name: None,
+            dropped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // child links the handle of namespace name to the same namespaces
+    pub(crate) fn child(&self, name: &str) -> Self {
+        Self {
+            namespaces: Weak::clone(&self.namespaces),
+            _owned: None,
+            name: Some(name.into()),
+            dropped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn namespaces(&self) -> Result<Arc<Namespaces<E>>> {
+        self.namespaces.upgrade().ok_or(KVError::StoreClosed)
+    }
+
+    // check_live fails once the namespace of the handle has been dropped
+    pub(crate) fn check_live(&self) -> Result<()> {
+        match &self.name {
+            Some(name) if self.dropped.load(Ordering::SeqCst) => Err(KVError::NoNamespace {
+                name: name.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn mark_dropped(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::engines::expiry::{DEFAULT_SWEEP_INTERVAL, MIN_SWEEP_INTERVAL};
+use crate::{Durability, KvStore, MergeOperator, MergeOperators, Result};
+use std::{path::PathBuf, time::Duration};
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024; // 8KB
+
+// a stale ratio alone would compact tiny stores on almost every overwrite
+const MIN_RATIO_COMPACTION_BYTES: u64 = 64 * 1024; // 64KB
+
+/// Decides when a `KvStore` compacts its logs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionTrigger {
+    /// Compact once more than this many bytes of the logs are stale.
+    StaleBytes(u64),
+    /// Compact once at least this fraction (0.0 to 1.0) of the logs is stale.
+    /// Stores with less than 64KB of stale data are never compacted.
+    StaleRatio(f64),
+}
+
+impl CompactionTrigger {
+    pub(crate) fn should_compact(&self, stale: u64, total: u64) -> bool {
+        match *self {
+            CompactionTrigger::StaleBytes(threshold) => stale > threshold,
+            CompactionTrigger::StaleRatio(ratio) => {
+                stale >= MIN_RATIO_COMPACTION_BYTES && stale as f64 >= ratio * total as f64
+            }
+        }
+    }
+}
+
+/// Options and flags to configure how a `KvStore` is opened, similar to
+/// `std::fs::OpenOptions`.
+///
+/// ```no_run
+/// use kvs::{CompactionTrigger, KvStoreOptions};
+///
+/// let store = KvStoreOptions::new()
+///     .compaction_trigger(CompactionTrigger::StaleRatio(0.5))
+///     .max_file_size(16 * 1024 * 1024)
+///     .open("./database")?;
+/// # Ok::<(), kvs::KVError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) compaction_trigger: CompactionTrigger,
+    pub(crate) max_file_size: u64,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) create_if_missing: bool,
+    pub(crate) durability: Durability,
+    pub(crate) sweep_interval: Duration,
+    pub(crate) merge_operators: MergeOperators,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_trigger: CompactionTrigger::StaleBytes(DEFAULT_COMPACTION_THRESHOLD),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            create_if_missing: true,
+            durability: Durability::Never,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            merge_operators: MergeOperators::default(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets when stale log data is compacted away. Defaults to 1MB of stale bytes.
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// Sets the size in bytes after which the active log file is closed and
+    /// writes move on to a new generation. Defaults to 64MB.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Sets the buffer size used for every log file reader. Defaults to 8KB.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Sets the buffer size used for log file writers. Defaults to 8KB.
+    pub fn write_buffer_size(mut self, bytes: usize) -> Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// Sets whether a missing store directory is created on open. When
+    /// disabled, opening a missing directory fails. Defaults to true.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Sets when writes are synced to disk. Defaults to `Durability::Never`,
+    /// which still hands every write to the operating system before returning.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability.normalized();
+        self
+    }
+
+    /// Sets how often keys that have expired are looked for and removed from
+    /// the logs. Expired keys read as absent either way. Defaults to 1s, and
+    /// intervals under 1ms are raised to 1ms.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval.max(MIN_SWEEP_INTERVAL);
+        self
+    }
+
+    /// Registers a merge operator under name, next to the built-in ones. A
+    /// store has to be opened with every operator its logs refer to, since
+    /// merge operands are only folded in when they are read or compacted.
+    pub fn merge_operator(mut self, name: &str, operator: impl MergeOperator) -> Self {
+        self.merge_operators.register(name, operator);
+        self
+    }
+
+    /// Opens the store at path with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
+}
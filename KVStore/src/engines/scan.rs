@@ -0,0 +1,107 @@
+use crate::Result;
+use std::ops::{Bound, RangeBounds};
+
+type Pairs<T> = Box<dyn DoubleEndedIterator<Item = Result<(T, T)>> + Send>;
+
+/// An iterator over the key/value pairs returned by `KvsEngine::scan`, in
+/// ascending key order. Call `rev()` to walk the keys in descending order.
+///
+/// A scan limited to `n` pairs stops after `n` pairs taken from whichever end
+/// it is consumed from, so `scan(.., Some(10))?.rev()` yields the ten largest
+/// keys of the range. Writes made while a scan is in progress may or may not
+/// be seen by it.
+///
+/// `KvsEngine::scan_bytes` returns a `Scan<Vec<u8>>`, which yields keys and
+/// values as raw bytes.
+pub struct Scan<T = String> {
+    pairs: Pairs<T>,
+    remaining: Option<usize>,
+}
+
+impl<T: 'static> Scan<T> {
+    pub(crate) fn new<I>(pairs: I, limit: Option<usize>) -> Self
+    where
+        I: DoubleEndedIterator<Item = Result<(T, T)>> + Send + 'static,
+    {
+        Self {
+            pairs: Box::new(pairs),
+            remaining: limit,
+        }
+    }
+
+    // take_one counts a pair against the limit, returning false once it is used up
+    fn take_one(&mut self) -> bool {
+        match &mut self.remaining {
+            Some(0) => false,
+            Some(n) => {
+                *n -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl Scan<Vec<u8>> {
+    // into_strings decodes every pair as UTF-8, failing on the first pair
+    // that is not
+    pub(crate) fn into_strings(self) -> Scan<String> {
+        let pairs = self.pairs.map(|pair| {
+            let (key, value) = pair?;
+            Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+        });
+        Scan::new(pairs, self.remaining)
+    }
+}
+
+impl<T: 'static> Iterator for Scan<T> {
+    type Item = Result<(T, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.take_one() {
+            return None;
+        }
+        self.pairs.next()
+    }
+}
+
+impl<T: 'static> DoubleEndedIterator for Scan<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if !self.take_one() {
+            return None;
+        }
+        self.pairs.next_back()
+    }
+}
+
+// prefix_range returns the range holding exactly the keys that start with
+// prefix. The first key past the range is the prefix with its last byte
+// incremented, once any trailing 0xff bytes, which cannot be, are dropped.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+// byte_range turns a range of string keys into the same range of their
+// bytes. UTF-8 strings order like their bytes, so both hold the same keys.
+pub(crate) fn byte_range(range: impl RangeBounds<String>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let to_bytes = |bound: Bound<&String>| bound.map(|key| key.as_bytes().to_vec());
+    (to_bytes(range.start_bound()), to_bytes(range.end_bound()))
+}
+
+// range_is_empty tells whether no key can lie between lower and upper
+pub(crate) fn range_is_empty<T: Ord>(lower: &Bound<T>, upper: &Bound<T>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l), Bound::Excluded(u))
+        | (Bound::Excluded(l), Bound::Included(u))
+        | (Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+        _ => false,
+    }
+}
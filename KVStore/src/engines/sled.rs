@@ -1,46 +1,750 @@
-use sled::Db;
+use sled::{Db, IVec, Tree};
 
-use crate::{error::Result, KVError, KvsEngine};
-use std::{path::PathBuf, str};
+use crate::engines::{
+    batch::BatchOp,
+    durability::GroupCommit,
+    expiry::{self, now_millis, DEFAULT_SWEEP_INTERVAL},
+    namespace::{self, NamespaceLink},
+    scan::range_is_empty,
+    transaction::{TxnSource, Version},
+    watch::{Publisher, Watchers},
+};
+use crate::{
+    error::Result, CasOutcome, Durability, KVError, KvsEngine, KvsSnapshot, MergeOperator,
+    MergeOperators, Scan, Transaction, WatchFilter, Watcher, WriteBatch,
+};
+use log::error;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional,
+    TransactionalTree,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{File, TryLockError},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// name of the tree holding the expiry time of every key that expires
+const EXPIRY_TREE: &str = "kvs-expiry";
+
+// the trees of a namespace are named after it, behind these prefixes
+const NAMESPACE_TREE_PREFIX: &str = "kvs-ns/";
+const NAMESPACE_EXPIRY_TREE_PREFIX: &str = "kvs-ns-expiry/";
+
+// how long opening waits for a database that was just closed to be unlocked
+const OPEN_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    // the values of the namespace of the handle, the default tree of the
+    // database for the default namespace
+    data: Tree,
+    // expiry time in milliseconds since the Unix epoch (u64 BE) by key. Every
+    // write updates it in the same sled transaction as the value.
+    expiries: Tree,
+    durability: Durability,
+    // number of writes applied so far, used to match writes with flushes
+    written: Arc<AtomicU64>,
+    group: Arc<GroupCommit>,
+    // writes hold it for reading, snapshots hold it for writing while they
+    // copy the database, since sled has no snapshots of its own
+    snapshot_lock: Arc<RwLock<()>>,
+    merge_operators: Arc<MergeOperators>,
+    watchers: Arc<Watchers>,
+    namespace: NamespaceLink<SledKvsEngine>,
+    // stops the sweeper when the last clone is dropped
+    _sweeper: Arc<Sweeper>,
 }
 
 impl SledKvsEngine {
+    /// Opens the database at path, flushing every write before it returns.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let db = sled::open(path.into())?;
+        Self::open_with_durability(path, Durability::Always)
+    }
+
+    /// Opens the database at path, flushing writes as durability asks for.
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<SledKvsEngine> {
+        let durability = durability.normalized();
+        let flush_every_ms = match durability {
+            Durability::Interval(interval) => Some(interval.as_millis() as u64),
+            Durability::Never => None,
+            // sled's own periodic flush is redundant when every write is flushed
+            Durability::Always | Durability::GroupCommit => None,
+        };
+        let path = path.into();
+        let config = sled::Config::new()
+            .path(&path)
+            .flush_every_ms(flush_every_ms);
+        let db = open_db(&path, &config)?;
+        let data = Tree::clone(&db);
+        let expiries = db.open_tree(EXPIRY_TREE)?;
+        let root = Self {
+            db,
+            data: data.clone(),
+            expiries: expiries.clone(),
+            durability,
+            written: Arc::new(AtomicU64::new(0)),
+            group: Arc::new(GroupCommit::new()),
+            snapshot_lock: Arc::new(RwLock::new(())),
+            merge_operators: Arc::new(MergeOperators::default()),
+            watchers: Arc::new(Watchers::default()),
+            namespace: NamespaceLink::root(),
+            _sweeper: Arc::new(Sweeper::idle()),
+        };
+        let namespace = root.namespace.clone();
+        Ok(root.with_trees(data, expiries, namespace))
+    }
+
+    // with_trees makes a handle to the values in data, sharing the database
+    // and its settings with self. The handle has watchers and a sweeper of
+    // its own.
+    fn with_trees(&self, data: Tree, expiries: Tree, namespace: NamespaceLink<Self>) -> Self {
+        let watchers = Arc::new(Watchers::default());
+        let sweeper = Sweeper::start(
+            data.clone(),
+            expiries.clone(),
+            Arc::clone(&self.snapshot_lock),
+            Arc::clone(&watchers),
+        );
+
+        Self {
+            db: self.db.clone(),
+            data,
+            expiries,
+            durability: self.durability,
+            written: Arc::clone(&self.written),
+            group: Arc::clone(&self.group),
+            snapshot_lock: Arc::clone(&self.snapshot_lock),
+            merge_operators: Arc::clone(&self.merge_operators),
+            watchers,
+            namespace,
+            _sweeper: Arc::new(sweeper),
+        }
+    }
+
+    /// Adds a merge operator for `KvsEngine::merge` under name, next to the
+    /// built-in ones.
+    pub fn with_merge_operator(mut self, name: &str, operator: impl MergeOperator) -> Self {
+        Arc::make_mut(&mut self.merge_operators).register(name, operator);
+        self
+    }
+
+    // make_durable flushes a write that was just applied, or shares a flush
+    // with concurrent writers under group commit
+    fn make_durable(&self) -> Result<()> {
+        match self.durability {
+            Durability::Always => {
+                self.db.flush()?;
+            }
+            Durability::GroupCommit => {
+                let seq = self.written.fetch_add(1, Ordering::SeqCst) + 1;
+                self.group.wait_for(seq, || {
+                    let covered = self.written.load(Ordering::SeqCst);
+                    self.db.flush()?;
+                    Ok(covered)
+                })?;
+            }
+            Durability::Interval(_) | Durability::Never => {}
+        }
+        Ok(())
+    }
+
+    // write applies f to the values and expiry times in one sled transaction
+    // and makes the result durable. When anyone watches, publish is handed
+    // the result to publish the changes the transaction made, before other
+    // writes can commit.
+    fn write<T, F, P>(&self, f: F, publish: P) -> Result<T>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KVError>,
+        P: FnOnce(&T, &mut Publisher),
+    {
+        self.namespace.check_live()?;
+        let writing = self.snapshot_lock.read().unwrap();
+        let mut publisher = self.watchers.publisher();
+        let value = run_transaction(&self.data, &self.expiries, f)?;
+        if let Some(publisher) = &mut publisher {
+            publish(&value, publisher);
+        }
+        drop(publisher);
+        drop(writing);
+        self.make_durable()?;
+        Ok(value)
+    }
 
-        Ok(Self { db })
+    fn has_namespace(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .db
+            .tree_names()
+            .contains(&IVec::from(namespace_tree(name).as_bytes())))
+    }
+
+    // is_expired tells whether key has expired by now. The value may have
+    // been read just before it was replaced, so a value that expires while
+    // it is being read can still be returned.
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(expiry::is_expired(
+            decode_expiry(self.expiries.get(key)?),
+            now,
+        ))
+    }
+
+    // live_pair copies a pair read from the database, or returns None if it
+    // has expired
+    fn live_pair(&self, key: &[u8], value: &[u8], now: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.is_expired(key, now)? {
+            return Ok(None);
+        }
+        Ok(Some((key.to_vec(), value.to_vec())))
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Db = &self.db;
-        tree.insert(key.as_bytes(), value.as_bytes())?;
-        tree.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(
+            |data, expiries| {
+                data.insert(&key[..], &value[..])?;
+                expiries.remove(&key[..])?;
+                Ok(())
+            },
+            |_, publisher| publisher.publish(&key, Some(&value)),
+        )
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry::expires_at(ttl).to_be_bytes();
+        self.write(
+            |data, expiries| {
+                data.insert(&key[..], &value[..])?;
+                expiries.insert(&key[..], &expires_at)?;
+                Ok(())
+            },
+            |_, publisher| publisher.publish(&key, Some(&value)),
+        )
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        self.namespace.check_live()?;
+        let now = now_millis();
+        if self.data.get(&key)?.is_none() {
+            return Err(KVError::KeyNoExist);
+        }
+
+        match decode_expiry(self.expiries.get(&key)?) {
+            Some(at) if at <= now => Err(KVError::KeyNoExist),
+            Some(at) => Ok(Some(expiry::remaining(at, now))),
+            None => Ok(None),
+        }
     }
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a given binary key.
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Db = &self.db;
-        if let Some(res) = tree.get(key.as_bytes())? {
-            let s = String::from(str::from_utf8(&res)?);
-            return Ok(Some(s));
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.namespace.check_live()?;
+        if let Some(res) = self.data.get(&key)? {
+            if self.is_expired(&key, now_millis())? {
+                return Ok(None);
+            }
+            return Ok(Some(res.to_vec()));
         }
         Ok(None)
     }
 
     /// Removes a given key.
     /// It returns `KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Db = &self.db;
-        tree.remove(key.as_bytes())?.ok_or(KVError::KeyNoExist)?;
-        tree.flush()?;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        self.write(
+            |data, expiries| {
+                if live_value(data, expiries, &key, now)?.is_none() {
+                    return Err(ConflictableTransactionError::Abort(KVError::KeyNoExist));
+                }
+                data.remove(&key[..])?;
+                expiries.remove(&key[..])?;
+                Ok(())
+            },
+            |_, publisher| publisher.publish(&key, None),
+        )
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write(
+            |data, expiries| apply_ops(data, expiries, &batch.ops),
+            |_, publisher| publish_ops(publisher, &batch.ops),
+        )
+    }
+
+    // merge_bytes folds the operand in right away, as sled has no merge
+    // operators that could be told apart by name
+    fn merge_bytes(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge_operators.get(operator)?;
+        let now = now_millis();
+        self.write(
+            |data, expiries| {
+                let current = live_value(data, expiries, &key, now)?;
+                let value = operator
+                    .merge(&key, current.as_deref(), &operand)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                data.insert(&key[..], &value[..])?;
+                // a key that expired starts over without an expiry time
+                if current.is_none() {
+                    expiries.remove(&key[..])?;
+                }
+                Ok(value)
+            },
+            |value, publisher| publisher.publish(&key, Some(value)),
+        )?;
         Ok(())
     }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<CasOutcome<Vec<u8>>> {
+        let now = now_millis();
+        let key = &key[..];
+        // the value found instead of the expected one, if any
+        let mismatch = self.write(
+            |data, expiries| {
+                let current = live_value(data, expiries, key, now)?;
+                if current.as_deref() != expected.as_deref() {
+                    return Ok(Some(current));
+                }
+                match &new {
+                    Some(value) => data.insert(key, &value[..])?,
+                    None => data.remove(key)?,
+                };
+                expiries.remove(key)?;
+                Ok(None)
+            },
+            |mismatch, publisher| {
+                if mismatch.is_none() {
+                    publisher.publish(key, new.as_deref());
+                }
+            },
+        )?;
+
+        match mismatch {
+            None => Ok(CasOutcome::Swapped),
+            Some(current) => Ok(CasOutcome::Current(current.map(|value| value.to_vec()))),
+        }
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>> {
+        self.namespace.check_live()?;
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if range_is_empty(&range.0, &range.1) {
+            return Ok(Scan::new(std::iter::empty(), limit));
+        }
+
+        let now = now_millis();
+        let engine = self.clone();
+        let pairs = self.data.range(range).filter_map(move |pair| match pair {
+            Ok((key, value)) => engine.live_pair(&key, &value, now).transpose(),
+            Err(e) => Some(Err(e.into())),
+        });
+        Ok(Scan::new(pairs, limit))
+    }
+
+    fn begin(&self) -> Result<Transaction> {
+        self.namespace.check_live()?;
+        Ok(Transaction::new(self.clone()))
+    }
+
+    fn commit(&self, tx: Transaction) -> Result<()> {
+        let (versions, batch) = tx.into_parts();
+        let now = now_millis();
+        self.write(
+            |data, expiries| {
+                validate(data, expiries, &versions, now)?;
+                apply_ops(data, expiries, &batch.ops)
+            },
+            |_, publisher| publish_ops(publisher, &batch.ops),
+        )
+    }
+
+    fn create_namespace(&self, name: &str) -> Result<()> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let _open = namespaces.lock();
+
+        if self.has_namespace(name)? {
+            return Err(KVError::NamespaceExists {
+                name: name.to_owned(),
+            });
+        }
+        self.db.open_tree(namespace_tree(name))?;
+        self.db.open_tree(namespace_expiry_tree(name))?;
+        Ok(())
+    }
+
+    // handles are kept so that all of them tell the same watchers about
+    // changes
+    fn open_namespace(&self, name: &str) -> Result<SledKvsEngine> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let mut open = namespaces.lock();
+        if let Some(engine) = open.get(name) {
+            return Ok(engine.clone());
+        }
+
+        if !self.has_namespace(name)? {
+            return Err(KVError::NoNamespace {
+                name: name.to_owned(),
+            });
+        }
+        let engine = self.with_trees(
+            self.db.open_tree(namespace_tree(name))?,
+            self.db.open_tree(namespace_expiry_tree(name))?,
+            self.namespace.child(name),
+        );
+        open.insert(name.to_owned(), engine.clone());
+        Ok(engine)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|tree| tree.strip_prefix(NAMESPACE_TREE_PREFIX.as_bytes()))
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .collect();
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // drop_namespace waits for writes in progress before dropping the trees,
+    // while later ones are turned away by the dropped handles
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        namespace::check_name(name)?;
+        let namespaces = self.namespace.namespaces()?;
+        let mut open = namespaces.lock();
+
+        if !self.has_namespace(name)? {
+            return Err(KVError::NoNamespace {
+                name: name.to_owned(),
+            });
+        }
+        if let Some(engine) = open.remove(name) {
+            engine.namespace.mark_dropped();
+        }
+        let _writing = self.snapshot_lock.write().unwrap();
+        self.db.drop_tree(namespace_tree(name))?;
+        self.db.drop_tree(namespace_expiry_tree(name))?;
+        Ok(())
+    }
+
+    fn watch(&self, filter: WatchFilter) -> Result<Watcher> {
+        self.namespace.check_live()?;
+        Ok(self.watchers.subscribe(filter))
+    }
+
+    type Snapshot = SledSnapshot;
+
+    // snapshot copies every key and value, blocking writes while it does,
+    // as sled cannot iterate a tree as of a point in time. Keys that expire
+    // later stay visible in the snapshot.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        self.namespace.check_live()?;
+        let _copying = self.snapshot_lock.write().unwrap();
+        let now = now_millis();
+        let mut pairs = BTreeMap::new();
+        for pair in self.data.iter() {
+            let (key, value) = pair?;
+            if let Some((key, value)) = self.live_pair(&key, &value, now)? {
+                pairs.insert(key, value);
+            }
+        }
+
+        Ok(SledSnapshot {
+            pairs: Arc::new(pairs),
+        })
+    }
+
+    // flush covers the namespaces as well, which are trees of the same db
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl TxnSource for SledKvsEngine {
+    fn read(
+        &self,
+        key: &[u8],
+        seen: &BTreeMap<Vec<u8>, Version>,
+    ) -> Result<(Option<Vec<u8>>, Version)> {
+        let now = now_millis();
+        self.namespace.check_live()?;
+        let value = run_transaction(&self.data, &self.expiries, |data, expiries| {
+            validate(data, expiries, seen, now)?;
+            live_value(data, expiries, key, now)
+        })?;
+
+        match value {
+            Some(value) => Ok((Some(value.to_vec()), Version::Value(value.to_vec()))),
+            None => Ok((None, Version::Missing)),
+        }
+    }
+}
+
+// open_db opens the database, waiting a moment if it is still locked. The
+// background threads of sled can hold on to the database file for a short
+// while after the last handle to it has been dropped, so reopening it right
+// away would fail otherwise.
+fn open_db(path: &Path, config: &sled::Config) -> Result<Db> {
+    let deadline = Instant::now() + OPEN_LOCK_TIMEOUT;
+    loop {
+        match config.open() {
+            Err(sled::Error::Io(_)) if Instant::now() < deadline && is_locked(path) => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            db => return Ok(db?),
+        }
+    }
+}
+
+// is_locked tells whether the database at path is locked by another handle.
+// sled reports a held lock as an error of kind Other, which can stand for
+// anything, so the lock it takes on its db file is probed directly.
+fn is_locked(path: &Path) -> bool {
+    match File::open(path.join("db")) {
+        Ok(file) => matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+        Err(_) => false,
+    }
+}
+
+// run_transaction runs f over the values and expiry times in a sled
+// transaction, which sled retries until it does not conflict with any other
+// sled transaction
+fn run_transaction<T, F>(data: &Tree, expiries: &Tree, f: F) -> Result<T>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KVError>,
+{
+    match (data, expiries).transaction(|(data, expiries)| f(data, expiries)) {
+        Ok(value) => Ok(value),
+        Err(TransactionError::Abort(e)) => Err(e),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+// apply_ops writes the operations of a batch, which leave the keys they
+// touch without an expiry time
+fn apply_ops(
+    data: &TransactionalTree,
+    expiries: &TransactionalTree,
+    ops: &[BatchOp],
+) -> ConflictableTransactionResult<(), KVError> {
+    for op in ops {
+        match op {
+            BatchOp::Set { key, value } => {
+                data.insert(&key[..], &value[..])?;
+            }
+            BatchOp::Remove { key } => {
+                data.remove(&key[..])?;
+            }
+        }
+        expiries.remove(op.key())?;
+    }
+    Ok(())
+}
+
+// publish_ops publishes the changes the operations of a batch made
+fn publish_ops(publisher: &mut Publisher, ops: &[BatchOp]) {
+    for op in ops {
+        match op {
+            BatchOp::Set { key, value } => publisher.publish(key, Some(value)),
+            BatchOp::Remove { key } => publisher.publish(key, None),
+        }
+    }
+}
+
+// live_value returns the value of key inside a sled transaction, or None if
+// it does not exist or has expired by now
+fn live_value(
+    data: &TransactionalTree,
+    expiries: &TransactionalTree,
+    key: &[u8],
+    now: u64,
+) -> ConflictableTransactionResult<Option<IVec>, KVError> {
+    let value = data.get(key)?;
+    if value.is_some() && expiry::is_expired(decode_expiry(expiries.get(key)?), now) {
+        return Ok(None);
+    }
+    Ok(value)
+}
+
+// validate aborts the sled transaction unless every key still holds the
+// value that the version recorded
+fn validate(
+    data: &TransactionalTree,
+    expiries: &TransactionalTree,
+    versions: &BTreeMap<Vec<u8>, Version>,
+    now: u64,
+) -> ConflictableTransactionResult<(), KVError> {
+    for (key, version) in versions {
+        let current = match live_value(data, expiries, key, now)? {
+            Some(value) => Version::Value(value.to_vec()),
+            None => Version::Missing,
+        };
+        if current != *version {
+            return Err(ConflictableTransactionError::Abort(
+                KVError::TransactionConflict,
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn namespace_tree(name: &str) -> String {
+    format!("{}{}", NAMESPACE_TREE_PREFIX, name)
+}
+
+fn namespace_expiry_tree(name: &str) -> String {
+    format!("{}{}", NAMESPACE_EXPIRY_TREE_PREFIX, name)
+}
+
+fn decode_expiry(value: Option<IVec>) -> Option<u64> {
+    value
+        .and_then(|value| value.as_ref().try_into().ok())
+        .map(u64::from_be_bytes)
+}
+
+/// Sweeper removes expired keys on a background thread. The thread is
+/// stopped and joined once the last clone of the engine is dropped, so that
+/// the database can be opened again right away.
+struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    // idle makes a sweeper without a thread, for handles that are only used
+    // to make other handles
+    fn idle() -> Self {
+        Self {
+            stop: None,
+            handle: None,
+        }
+    }
+
+    fn start(
+        data: Tree,
+        expiries: Tree,
+        snapshot_lock: Arc<RwLock<()>>,
+        watchers: Arc<Watchers>,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            sweep_periodically(data, expiries, snapshot_lock, watchers, stopped);
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        // hanging up wakes the thread
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn sweep_periodically(
+    data: Tree,
+    expiries: Tree,
+    snapshot_lock: Arc<RwLock<()>>,
+    watchers: Arc<Watchers>,
+    stopped: Receiver<()>,
+) {
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(DEFAULT_SWEEP_INTERVAL) {
+        if let Err(e) = sweep_expired(&data, &expiries, &snapshot_lock, &watchers) {
+            error!("expiry sweep failed: {}", e);
+        }
+    }
+}
+
+// sweep_expired removes every key that has expired, unless it was written
+// again since it was found, and tells watchers that it is gone
+fn sweep_expired(
+    data: &Tree,
+    expiries: &Tree,
+    snapshot_lock: &RwLock<()>,
+    watchers: &Watchers,
+) -> Result<()> {
+    let now = now_millis();
+    for entry in expiries.iter() {
+        let (key, expires_at) = entry?;
+        if !expiry::is_expired(decode_expiry(Some(expires_at)), now) {
+            continue;
+        }
+
+        let _writing = snapshot_lock.read().unwrap();
+        let mut publisher = watchers.publisher();
+        let removed = run_transaction(data, expiries, |data, expiries| {
+            let expired = expiry::is_expired(decode_expiry(expiries.get(&key)?), now);
+            if expired {
+                data.remove(&key)?;
+                expiries.remove(&key)?;
+            }
+            Ok(expired)
+        })?;
+        if let (true, Some(publisher)) = (removed, &mut publisher) {
+            publisher.publish(&key, None);
+        }
+    }
+    Ok(())
+}
+
+/// A read-only copy of a `SledKvsEngine`, taken at the moment it was created.
+pub struct SledSnapshot {
+    pairs: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(&key).cloned())
+    }
+
+    fn scan_bytes(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Scan<Vec<u8>>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if range_is_empty(&range.0, &range.1) {
+            return Ok(Scan::new(std::iter::empty(), limit));
+        }
+
+        let pairs: Vec<_> = self
+            .pairs
+            .range(range)
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Scan::new(pairs.into_iter(), limit))
+    }
 }
@@ -0,0 +1,111 @@
+use crate::{KVError, Result, WriteBatch};
+use std::collections::BTreeMap;
+
+/// Version identifies the state of a key as a transaction first saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Version {
+    Missing,
+    // position of the record that wrote the value, used by `KvStore`
+    Log { gen: u64, pos: u64 },
+    // the value itself, used by `SledKvsEngine`
+    Value(Vec<u8>),
+}
+
+// TxnSource reads keys on behalf of a transaction
+pub(crate) trait TxnSource: Send {
+    // read returns the current value of key and its version, but fails with
+    // a conflict unless every version in seen is still current, so that all
+    // reads of a transaction observe a single point in time
+    fn read(
+        &self,
+        key: &[u8],
+        seen: &BTreeMap<Vec<u8>, Version>,
+    ) -> Result<(Option<Vec<u8>>, Version)>;
+}
+
+/// A read-modify-write transaction over several keys, started with
+/// `KvsEngine::begin` or run through `KvsEngine::transaction`.
+///
+/// Reads see the writes the transaction made itself and otherwise a single
+/// consistent state of the engine. Writes are buffered until commit, which
+/// fails with `KVError::TransactionConflict` if any key the transaction read
+/// or wrote was changed by someone else in the meantime. Dropping a
+/// transaction without committing it aborts it.
+pub struct Transaction {
+    source: Box<dyn TxnSource>,
+    // version of every key read or written, as of the first time it was used
+    versions: BTreeMap<Vec<u8>, Version>,
+    // buffered writes, where None removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(source: impl TxnSource + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            versions: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of key as seen by this transaction.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.get_bytes(key.into_bytes())?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Gets the value of a binary key as seen by this transaction.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(write) = self.writes.get(&key) {
+            return Ok(write.clone());
+        }
+
+        let (value, version) = self.source.read(&key, &self.versions)?;
+        self.versions.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    /// Sets key to value when the transaction commits.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the binary key to the binary value when the transaction commits.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        if !self.versions.contains_key(&key) {
+            let (_, version) = self.source.read(&key, &self.versions)?;
+            self.versions.insert(key.clone(), version);
+        }
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Removes key when the transaction commits.
+    /// Returns `KeyNoExist` if the transaction does not see the key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Removes the binary key when the transaction commits.
+    /// Returns `KeyNoExist` if the transaction does not see the key.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KVError::KeyNoExist);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    // into_parts returns the versions the commit has to validate and the
+    // writes it has to apply
+    pub(crate) fn into_parts(self) -> (BTreeMap<Vec<u8>, Version>, WriteBatch) {
+        let mut batch = WriteBatch::new();
+        for (key, write) in self.writes {
+            match write {
+                Some(value) => batch.set_bytes(key, value),
+                None => batch.remove_bytes(key),
+            };
+        }
+        (self.versions, batch)
+    }
+}
@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+// changes a watcher may fall behind by before it is dropped
+const WATCH_BUFFER: usize = 1024;
+
+/// The keys a `Watcher` is told about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchFilter {
+    /// Exactly this key.
+    Key(Vec<u8>),
+    /// Every key starting with this prefix.
+    Prefix(Vec<u8>),
+}
+
+impl WatchFilter {
+    fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            WatchFilter::Key(watched) => watched == key,
+            WatchFilter::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// What a change did to its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeOp {
+    Set,
+    Remove,
+}
+
+/// A committed write to a watched key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub key: Vec<u8>,
+    pub op: ChangeOp,
+    /// The value the key was set to, `None` if it was removed.
+    pub value: Option<Vec<u8>>,
+    /// Numbers the changes of an engine in the order they were committed,
+    /// counting from 1 when it was opened. Changes nobody watched are not
+    /// numbered, so a watcher sees gaps where other watchers were told about
+    /// changes to their keys.
+    pub seq: u64,
+}
+
+/// The changes to the keys watched through `KvsEngine::watch`, in the order
+/// they were committed.
+///
+/// Iterating blocks until the next change. The iterator ends once the engine
+/// is dropped, or if the watcher falls too far behind the writes it watches.
+pub struct Watcher {
+    changes: Receiver<Change>,
+}
+
+impl Watcher {
+    /// Waits up to timeout for the next change. Fails with `Timeout` if
+    /// there was none, or with `Disconnected` once the watcher has ended.
+    pub fn next_timeout(&self, timeout: Duration) -> Result<Change, RecvTimeoutError> {
+        self.changes.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        self.changes.recv().ok()
+    }
+}
+
+/// Watchers hands the changes an engine commits to the watchers whose filter
+/// they match.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    state: Mutex<WatchState>,
+    // lets writes skip publishing while nobody watches
+    watching: AtomicBool,
+}
+
+#[derive(Default)]
+pub(crate) struct WatchState {
+    last_seq: u64,
+    subscribers: Vec<(WatchFilter, SyncSender<Change>)>,
+}
+
+impl Watchers {
+    pub(crate) fn subscribe(&self, filter: WatchFilter) -> Watcher {
+        let (sender, changes) = mpsc::sync_channel(WATCH_BUFFER);
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.push((filter, sender));
+        self.watching.store(true, Ordering::SeqCst);
+        Watcher { changes }
+    }
+
+    // publisher returns a handle for publishing changes, or None if nobody
+    // watches. Changes get their sequence numbers in the order they are
+    // published, so writers hold on to it until their write has committed.
+    pub(crate) fn publisher(&self) -> Option<Publisher<'_>> {
+        if !self.watching.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(Publisher {
+            state: self.state.lock().unwrap(),
+            watching: &self.watching,
+        })
+    }
+}
+
+pub(crate) struct Publisher<'a> {
+    state: MutexGuard<'a, WatchState>,
+    watching: &'a AtomicBool,
+}
+
+impl Publisher<'_> {
+    // watches tells whether changes to key have anyone to go to
+    pub(crate) fn watches(&self, key: &[u8]) -> bool {
+        self.state
+            .subscribers
+            .iter()
+            .any(|(filter, _)| filter.matches(key))
+    }
+
+    // publish numbers a change and sends it to every watcher of key. Watchers
+    // that went away or fell too far behind are dropped.
+    pub(crate) fn publish(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if !self.watches(key) {
+            return;
+        }
+
+        let state = &mut *self.state;
+        state.last_seq += 1;
+        let change = Change {
+            key: key.to_vec(),
+            op: match value {
+                Some(_) => ChangeOp::Set,
+                None => ChangeOp::Remove,
+            },
+            value: value.map(<[u8]>::to_vec),
+            seq: state.last_seq,
+        };
+        state.subscribers.retain(|(filter, sender)| {
+            !filter.matches(key) || sender.try_send(change.clone()).is_ok()
+        });
+        if state.subscribers.is_empty() {
+            self.watching.store(false, Ordering::SeqCst);
+        }
+    }
+}
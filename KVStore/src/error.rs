@@ -1,5 +1,8 @@
+// failure_derive expands into impl blocks nested in an anonymous const
+#![allow(non_local_definitions)]
+
 use failure::Fail;
-use std::{fmt, io, net, str::Utf8Error};
+use std::{fmt, io, net, str::Utf8Error, string::FromUtf8Error};
 
 // error handling. Any error will be converted to the same type: KVError
 // to facilitate the development
@@ -46,6 +49,69 @@ pub enum KVError {
 
     #[fail(display = "Error: get method error!")]
     GetMethodError,
+
+    #[fail(
+        display = "Error: corrupted log record in {} at offset {}",
+        file, offset
+    )]
+    Corruption { file: String, offset: u64 },
+
+    #[fail(display = "Error: store directory {} does not exist", path)]
+    MissingDirectory { path: String },
+
+    #[fail(display = "Error: transaction conflict, a key it used was changed concurrently")]
+    TransactionConflict,
+
+    #[fail(display = "Error: no open transaction with id {}", id)]
+    NoTransaction { id: u64 },
+
+    #[fail(display = "Error: value is not valid UTF-8, read it as bytes instead")]
+    InvalidUtf8,
+
+    #[fail(display = "Error: value is not an integer")]
+    NotAnInteger,
+
+    #[fail(display = "Error: counter overflow")]
+    Overflow,
+
+    #[fail(display = "Error: unknown merge operator {}", name)]
+    UnknownMergeOperator { name: String },
+
+    #[fail(display = "Error: namespace {} does not exist", name)]
+    NoNamespace { name: String },
+
+    #[fail(display = "Error: namespace {} already exists", name)]
+    NamespaceExists { name: String },
+
+    #[fail(
+        display = "Error: invalid namespace name {:?}, use up to 64 letters, digits, '-', '_' or '.'",
+        name
+    )]
+    InvalidNamespace { name: String },
+
+    #[fail(display = "Error: the store this namespace belongs to has been closed")]
+    StoreClosed,
+
+    #[fail(display = "Error: invalid frame length {}", len)]
+    InvalidFrame { len: u32 },
+
+    #[fail(display = "Error: protocol version {} is not supported", version)]
+    UnsupportedVersion { version: u16 },
+
+    #[fail(display = "Error: the peer does not speak the framed kvs protocol")]
+    BadHandshake,
+
+    #[fail(display = "Error: the engine call panicked or was dropped by its thread pool")]
+    EngineCallFailed,
+
+    #[fail(display = "Error: the server is busy, try again later")]
+    ServerBusy,
+
+    #[fail(
+        display = "Error: request of {} bytes is over the limit of {} bytes",
+        len, limit
+    )]
+    RequestTooLarge { id: u32, len: usize, limit: usize },
 }
 
 impl From<serde_json::Error> for KVError {
@@ -80,7 +146,31 @@ impl From<sled::Error> for KVError {
 
 impl From<Utf8Error> for KVError {
     fn from(_err: Utf8Error) -> KVError {
-        KVError::SledError
+        KVError::InvalidUtf8
+    }
+}
+
+impl From<FromUtf8Error> for KVError {
+    fn from(_err: FromUtf8Error) -> KVError {
+        KVError::InvalidUtf8
+    }
+}
+
+impl<T> From<ciborium::de::Error<T>> for KVError {
+    fn from(err: ciborium::de::Error<T>) -> KVError {
+        match err {
+            ciborium::de::Error::Io(_) => KVError::Io,
+            _ => KVError::Serde,
+        }
+    }
+}
+
+impl<T> From<ciborium::ser::Error<T>> for KVError {
+    fn from(err: ciborium::ser::Error<T>) -> KVError {
+        match err {
+            ciborium::ser::Error::Io(_) => KVError::Io,
+            _ => KVError::Serde,
+        }
     }
 }
 
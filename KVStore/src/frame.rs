@@ -0,0 +1,325 @@
+use crate::error::{KVError, Result};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The bytes a client opens a framed connection with, followed by the
+/// highest protocol version it speaks as a u16 BE. The server answers with
+/// the same magic and the version both sides then speak, or 0 if it speaks
+/// none of the versions the client does, and closes the connection. A server
+/// that is serving all the connections it can answers with 0xFFFF instead,
+/// before the client has said anything.
+///
+/// No legacy JSON or CBOR request starts with a 'K', so the server tells
+/// framed connections apart from legacy ones by the first byte.
+pub const MAGIC: [u8; 4] = *b"KVSF";
+
+/// The protocol version this crate speaks.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// the oldest protocol version the server still answers
+const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// the version a server answers with when it refuses the handshake
+const REFUSED: u16 = 0;
+
+// the version a server answers with when it has no room for the connection
+const BUSY: u16 = u16::MAX;
+
+/// Frames larger than this are refused, as they cannot be told apart from a
+/// corrupted length.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// opcode and request id
+const FRAME_HEADER_LEN: u32 = 5;
+
+/// What a frame carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    /// A CBOR encoded `Request`.
+    Request,
+    /// The CBOR encoded response to the request of the same id. A Watch
+    /// request is answered with a response for every change.
+    Response,
+    /// A frame that could not be answered, with a UTF-8 message saying why.
+    Error,
+    /// Any opcode this version does not know, which is answered with an
+    /// error frame.
+    Unknown(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(byte: u8) -> Self {
+        match byte {
+            1 => Opcode::Request,
+            2 => Opcode::Response,
+            3 => Opcode::Error,
+            byte => Opcode::Unknown(byte),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        match opcode {
+            Opcode::Request => 1,
+            Opcode::Response => 2,
+            Opcode::Error => 3,
+            Opcode::Unknown(byte) => byte,
+        }
+    }
+}
+
+/// A frame of the binary protocol, which is written as
+///
+/// ```text
+/// [length u32 BE][opcode u8][request id u32 BE][payload]
+/// ```
+///
+/// where the length counts the opcode, the request id and the payload.
+/// Responses and error frames carry the id of the request they answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: Opcode,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            opcode,
+            id,
+            payload,
+        }
+    }
+
+    pub fn error(id: u32, message: &str) -> Self {
+        Self::new(Opcode::Error, id, message.as_bytes().to_vec())
+    }
+
+    /// Reads the next frame, or returns None if the connection was closed
+    /// before it began.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+        Self::read_limited(reader, MAX_FRAME_LEN as usize)
+    }
+
+    /// Reads the next frame like `read`, but skips the payload of a frame
+    /// whose length is over limit and fails with `RequestTooLarge`, which
+    /// leaves the reader at the start of the next frame.
+    pub fn read_limited<R: Read>(reader: &mut R, limit: usize) -> Result<Option<Frame>> {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let payload_len = payload_len(len)?;
+
+        let mut header = [0; FRAME_HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let frame_len = u32::from_be_bytes(len) as usize;
+        if frame_len > limit {
+            let skipped = io::copy(&mut reader.take(payload_len as u64), &mut io::sink())?;
+            if skipped < payload_len as u64 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            return Err(KVError::RequestTooLarge {
+                id: Frame::from_parts(header, Vec::new()).id,
+                len: frame_len,
+                limit,
+            });
+        }
+        let mut payload = vec![0; payload_len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Frame::from_parts(header, payload)))
+    }
+
+    /// Reads the next frame from an async reader, see `read`.
+    pub async fn read_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        Self::read_async_limited(reader, MAX_FRAME_LEN as usize).await
+    }
+
+    /// Reads the next frame from an async reader, see `read_limited`.
+    pub async fn read_async_limited<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        limit: usize,
+    ) -> Result<Option<Frame>> {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let payload_len = payload_len(len)?;
+
+        let mut header = [0; FRAME_HEADER_LEN as usize];
+        reader.read_exact(&mut header).await?;
+        let frame_len = u32::from_be_bytes(len) as usize;
+        if frame_len > limit {
+            let mut rest = reader.take(payload_len as u64);
+            let skipped = tokio::io::copy(&mut rest, &mut tokio::io::sink()).await?;
+            if skipped < payload_len as u64 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            return Err(KVError::RequestTooLarge {
+                id: Frame::from_parts(header, Vec::new()).id,
+                len: frame_len,
+                limit,
+            });
+        }
+        let mut payload = vec![0; payload_len];
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Frame::from_parts(header, payload)))
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header()?)?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header()?).await?;
+        writer.write_all(&self.payload).await?;
+        Ok(())
+    }
+
+    fn from_parts(header: [u8; FRAME_HEADER_LEN as usize], payload: Vec<u8>) -> Self {
+        Self {
+            opcode: header[0].into(),
+            id: u32::from_be_bytes(header[1..].try_into().unwrap()),
+            payload,
+        }
+    }
+
+    // header returns the length, opcode and request id the payload is
+    // written after
+    fn header(&self) -> Result<[u8; 9]> {
+        let len = FRAME_HEADER_LEN as usize + self.payload.len();
+        if len > MAX_FRAME_LEN as usize {
+            return Err(KVError::InvalidFrame { len: len as u32 });
+        }
+        let mut header = [0; 9];
+        header[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        header[4] = self.opcode.into();
+        header[5..].copy_from_slice(&self.id.to_be_bytes());
+        Ok(header)
+    }
+}
+
+// payload_len checks the length a frame starts with, and returns that of
+// its payload
+fn payload_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len);
+    if !(FRAME_HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+        return Err(KVError::InvalidFrame { len });
+    }
+    Ok((len - FRAME_HEADER_LEN) as usize)
+}
+
+/// Opens a framed connection from the client side, and returns the protocol
+/// version the server agreed to.
+pub fn client_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u16> {
+    writer.write_all(&hello(PROTOCOL_VERSION))?;
+    writer.flush()?;
+
+    let mut answer = [0; 6];
+    reader.read_exact(&mut answer)?;
+    accepted(answer)
+}
+
+/// Opens a framed connection from the client side over an async stream, see
+/// `client_handshake`.
+pub async fn client_handshake_async<R, W>(reader: &mut R, writer: &mut W) -> Result<u16>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&hello(PROTOCOL_VERSION)).await?;
+    writer.flush().await?;
+
+    let mut answer = [0; 6];
+    reader.read_exact(&mut answer).await?;
+    accepted(answer)
+}
+
+/// Answers the handshake of a client, once its first byte has shown it to
+/// be framed. Returns the version agreed on, or fails after telling the
+/// client that none of its versions are spoken.
+pub fn server_handshake<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u16> {
+    let mut offer = [0; 6];
+    reader.read_exact(&mut offer)?;
+    let offered = parse_hello(offer)?;
+    let version = agreed_version(offered);
+
+    writer.write_all(&hello(version))?;
+    writer.flush()?;
+    check_agreed(offered, version)
+}
+
+/// Answers the handshake of a client over an async stream, see
+/// `server_handshake`.
+pub async fn server_handshake_async<R, W>(reader: &mut R, writer: &mut W) -> Result<u16>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut offer = [0; 6];
+    reader.read_exact(&mut offer).await?;
+    let offered = parse_hello(offer)?;
+    let version = agreed_version(offered);
+
+    writer.write_all(&hello(version)).await?;
+    writer.flush().await?;
+    check_agreed(offered, version)
+}
+
+/// Tells a client that connected to a server with no room for it to try
+/// again later. The client gets `ServerBusy` from its handshake.
+pub fn refuse_busy<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(&hello(BUSY))?;
+    writer.flush()?;
+    Ok(())
+}
+
+// hello is the magic and version either side opens with
+fn hello(version: u16) -> [u8; 6] {
+    let mut hello = [0; 6];
+    hello[..4].copy_from_slice(&MAGIC);
+    hello[4..].copy_from_slice(&version.to_be_bytes());
+    hello
+}
+
+fn parse_hello(hello: [u8; 6]) -> Result<u16> {
+    if hello[..4] != MAGIC {
+        return Err(KVError::BadHandshake);
+    }
+    Ok(u16::from_be_bytes([hello[4], hello[5]]))
+}
+
+// agreed_version is the version a server answers an offer with
+fn agreed_version(offered: u16) -> u16 {
+    if offered < MIN_PROTOCOL_VERSION {
+        REFUSED
+    } else {
+        offered.min(PROTOCOL_VERSION)
+    }
+}
+
+fn check_agreed(offered: u16, version: u16) -> Result<u16> {
+    if version == REFUSED {
+        return Err(KVError::UnsupportedVersion { version: offered });
+    }
+    Ok(version)
+}
+
+// accepted reads the answer of a server to the handshake of a client
+fn accepted(answer: [u8; 6]) -> Result<u16> {
+    match parse_hello(answer)? {
+        REFUSED => Err(KVError::UnsupportedVersion {
+            version: PROTOCOL_VERSION,
+        }),
+        BUSY => Err(KVError::ServerBusy),
+        version => Ok(version),
+    }
+}
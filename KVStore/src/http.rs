@@ -0,0 +1,454 @@
+// an HTTP/1.1 gateway, which `kvs-server --protocol http` speaks so that
+// services in any language can use the store through a JSON API:
+//
+//   GET    /health                    200 once the server answers
+//   GET    /v1/keys/{key}             200 with the key and its value
+//   PUT    /v1/keys/{key}             204, with a {"value", "ttl_ms"} body
+//   DELETE /v1/keys/{key}             204
+//   GET    /v1/keys?prefix=&limit=    200 with the pairs, in key order
+//
+// Keys in paths and queries are percent-encoded, so they may hold any bytes.
+// Missing keys are answered with a 404, failures with a JSON error body.
+
+use crate::{
+    common::Bytes,
+    connection::{self, ConnectionReader, ConnectionWriter, LineError},
+    engines::KvsEngine,
+    error::{KVError, Result},
+    server::is_hang_up,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    str,
+    time::Duration,
+};
+
+// longest request or header line, and most headers a request may have
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+const KEYS_PATH: &str = "/v1/keys";
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: Bytes,
+    // milliseconds until the key expires, None if it never does
+    #[serde(default)]
+    ttl_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: Bytes,
+    value: Bytes,
+}
+
+#[derive(Serialize)]
+struct Pairs {
+    pairs: Vec<Pair>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    keep_alive: bool,
+    body: Vec<u8>,
+}
+
+/// A response, whose body is JSON if it has one.
+struct HttpResponse {
+    status: u16,
+    body: Option<Vec<u8>>,
+    // methods allowed on the path, for a 405
+    allow: Option<&'static str>,
+}
+
+impl HttpResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        Self {
+            status,
+            body: Some(serde_json::to_vec(body).expect("responses always serialize")),
+            allow: None,
+        }
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(
+            status,
+            &ErrorBody {
+                error: message.into(),
+            },
+        )
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::error(405, "Error: method not allowed")
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        match &self.body {
+            Some(body) => {
+                write!(
+                    writer,
+                    "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                )?;
+                writer.write_all(body)
+            }
+            None => writer.write_all(b"Content-Length: 0\r\n\r\n"),
+        }
+    }
+}
+
+// engine errors are answered with a 404 for missing keys, and a 500 otherwise
+impl From<KVError> for HttpResponse {
+    fn from(e: KVError) -> Self {
+        match e {
+            KVError::KeyNoExist => HttpResponse::error(404, e.to_string()),
+            e => HttpResponse::error(500, e.to_string()),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+// a request that could not be read. The connection is closed after the
+// response, since what follows it can no longer be told apart.
+enum ReadError {
+    Io(io::Error),
+    Http(u16, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+// serve answers the requests of a connection until the client hangs up,
+// goes idle or asks for the connection to be closed. Requests may be
+// pipelined, their responses are sent together once every request that has
+// arrived is answered. Bodies longer than max_body_len are refused.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: &E,
+    mut reader: BufReader<ConnectionReader>,
+    mut writer: BufWriter<ConnectionWriter>,
+    max_body_len: usize,
+) -> Result<()> {
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        let request = match read_request(&mut reader, &mut writer, max_body_len) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(ReadError::Io(e)) if is_hang_up(&e) => return Ok(()),
+            Err(ReadError::Io(e)) => return Err(e.into()),
+            Err(ReadError::Http(status, message)) => {
+                HttpResponse::error(status, message).write(&mut writer, false)?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+
+        respond(engine, &request).write(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            writer.flush()?;
+            return Ok(());
+        }
+    }
+}
+
+// busy tells a client the server has no room for it
+pub(crate) fn busy<W: Write>(writer: &mut W) -> io::Result<()> {
+    HttpResponse::error(503, "Error: the server is busy, try again later").write(writer, false)?;
+    writer.flush()
+}
+
+// read_request reads the next request, or returns None once the client has
+// hung up. Clients waiting for a 100 Continue are sent one before the body
+// is read.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    max_body_len: usize,
+) -> std::result::Result<Option<HttpRequest>, ReadError> {
+    // empty lines may come before a request
+    let line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(None),
+        }
+    };
+
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ReadError::Http(400, "Error: malformed request line")),
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(ReadError::Http(505, "Error: only HTTP/1.x is supported")),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let mut request = HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        keep_alive,
+        body: Vec::new(),
+    };
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for headers in 0.. {
+        let line = read_line(reader)?.ok_or_else(|| ReadError::Io(unexpected_eof()))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers == MAX_HEADERS {
+            return Err(ReadError::Http(431, "Error: too many headers"));
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or(ReadError::Http(400, "Error: malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| ReadError::Http(400, "Error: invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(ReadError::Http(
+                    411,
+                    "Error: send the body with a Content-Length",
+                ));
+            }
+            "connection" => {
+                let value = value.to_ascii_lowercase();
+                if value == "close" {
+                    keep_alive = false;
+                } else if value == "keep-alive" {
+                    keep_alive = true;
+                }
+            }
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+    request.keep_alive = keep_alive;
+
+    if content_length > max_body_len {
+        return Err(ReadError::Http(413, "Error: request body too large"));
+    }
+    if expect_continue && content_length > 0 {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    request.body = vec![0; content_length];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+// read_line reads a line of the request head, see connection::read_line
+fn read_line<R: BufRead>(reader: &mut R) -> std::result::Result<Option<String>, ReadError> {
+    let line = match connection::read_line(reader, MAX_LINE_LEN) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Ok(None),
+        Err(LineError::TooLong) => {
+            return Err(ReadError::Http(431, "Error: request line too long"))
+        }
+        Err(LineError::Io(e)) => return Err(ReadError::Io(e)),
+    };
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ReadError::Http(400, "Error: request lines must be ASCII"))
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed mid request",
+    )
+}
+
+// respond routes a request to the engine
+fn respond<E: KvsEngine>(engine: &E, request: &HttpRequest) -> HttpResponse {
+    let method = request.method.as_str();
+    let response = if request.path == "/health" {
+        match method {
+            "GET" => Ok(HttpResponse::json(200, &Health { status: "ok" })),
+            _ => Ok(HttpResponse::method_not_allowed("GET")),
+        }
+    } else if request.path == KEYS_PATH {
+        match method {
+            "GET" => scan(engine, request.query.as_deref().unwrap_or_default()),
+            _ => Ok(HttpResponse::method_not_allowed("GET")),
+        }
+    } else if let Some(key) = request
+        .path
+        .strip_prefix(KEYS_PATH)
+        .and_then(|key| key.strip_prefix('/'))
+    {
+        match percent_decode(key) {
+            Some(key) => key_request(engine, method, key, &request.body),
+            None => Ok(HttpResponse::error(400, "Error: invalid percent-encoding")),
+        }
+    } else {
+        Ok(HttpResponse::error(404, "Error: no such endpoint"))
+    };
+    response.unwrap_or_else(HttpResponse::from)
+}
+
+fn key_request<E: KvsEngine>(
+    engine: &E,
+    method: &str,
+    key: Vec<u8>,
+    body: &[u8],
+) -> Result<HttpResponse> {
+    match method {
+        "GET" => match engine.get_bytes(key.clone())? {
+            Some(value) => Ok(HttpResponse::json(
+                200,
+                &Pair {
+                    key: Bytes(key),
+                    value: Bytes(value),
+                },
+            )),
+            None => Err(KVError::KeyNoExist),
+        },
+        "PUT" => {
+            let body: PutBody = match serde_json::from_slice(body) {
+                Ok(body) => body,
+                Err(e) => {
+                    return Ok(HttpResponse::error(
+                        400,
+                        format!("Error: invalid body, expected {{\"value\": ...}}: {}", e),
+                    ))
+                }
+            };
+            match body.ttl_ms {
+                Some(ttl) => {
+                    engine.set_bytes_with_ttl(key, body.value.0, Duration::from_millis(ttl))?
+                }
+                None => engine.set_bytes(key, body.value.0)?,
+            }
+            Ok(HttpResponse::no_content())
+        }
+        "DELETE" => {
+            engine.remove_bytes(key)?;
+            Ok(HttpResponse::no_content())
+        }
+        _ => Ok(HttpResponse::method_not_allowed("GET, PUT, DELETE")),
+    }
+}
+
+fn scan<E: KvsEngine>(engine: &E, query: &str) -> Result<HttpResponse> {
+    let mut prefix = Vec::new();
+    let mut limit = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = match percent_decode(&value.replace('+', " ")) {
+            Some(value) => value,
+            None => return Ok(HttpResponse::error(400, "Error: invalid percent-encoding")),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "limit" => match str::from_utf8(&value).ok().and_then(|n| n.parse().ok()) {
+                Some(n) => limit = Some(n),
+                None => return Ok(HttpResponse::error(400, "Error: invalid limit")),
+            },
+            _ => {}
+        }
+    }
+
+    let pairs = engine
+        .scan_prefix_bytes(&prefix, limit)?
+        .map(|pair| {
+            pair.map(|(key, value)| Pair {
+                key: Bytes(key),
+                value: Bytes(value),
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(HttpResponse::json(200, &Pairs { pairs }))
+}
+
+// percent_decode decodes the %XX escapes of a path segment or query value,
+// or returns None if one is malformed
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
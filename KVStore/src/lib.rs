@@ -1,11 +1,22 @@
+pub mod async_client;
+pub mod async_server;
 pub mod client;
 pub mod common;
+mod connection;
 pub mod engines;
 pub mod error;
+pub mod frame;
+mod http;
+mod memcached;
 pub mod parser;
+mod resp;
 pub mod server;
 pub mod thread_pool;
 
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    Append, AsyncKvsEngine, CasOutcome, Change, ChangeOp, CompactionTrigger, Durability, KvStore,
+    KvStoreOptions, KvStoreSnapshot, KvsEngine, KvsSnapshot, Max, MergeOperator, MergeOperators,
+    Scan, SetUnion, SledKvsEngine, SledSnapshot, Transaction, WatchFilter, Watcher, WriteBatch,
+};
 pub use error::{KVError, Result};
 pub use thread_pool::ThreadPool;
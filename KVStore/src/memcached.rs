@@ -0,0 +1,616 @@
+// the memcached text protocol, which `kvs-server --protocol memcached`
+// speaks so that services written against memcached can use the store. It
+// answers get, gets, set, add, replace, cas, delete, incr, decr, flush_all,
+// version and quit, and replies ERROR to any other command.
+//
+// Items are kept in the memcached namespace of the store, so flush_all only
+// empties them. Each one is stored with a header holding what memcached
+// keeps along with the data:
+//
+//   [flags u32 BE][cas unique u64 BE][expiry u64 BE][data]
+//
+// where the expiry is in milliseconds since the Unix epoch, or 0 for items
+// that never expire. Items set with an expiry are also given a ttl, so that
+// the store removes them once they expire. Conditional writes cannot set a
+// ttl, so the expired items they wrote are removed when they are next read.
+
+use crate::{
+    connection::{self, ConnectionReader, ConnectionWriter, LineError},
+    engines::{
+        expiry::{self, now_millis},
+        KvsEngine,
+    },
+    error::{KVError, Result},
+    server::is_hang_up,
+    WriteBatch,
+};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    str,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// the namespace items are kept in
+const NAMESPACE: &str = "memcached";
+
+// longest command line, which leaves room for a get of many keys
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// the limits memcached puts on keys and on the data of an item
+const MAX_KEY_LEN: usize = 250;
+const MAX_DATA_LEN: usize = 1024 * 1024;
+
+// longest data block that is skipped past when an item is too large, longer
+// ones are taken to be garbage and end the connection
+const MAX_SKIPPED_LEN: usize = 64 * MAX_DATA_LEN;
+
+// exptimes up to 30 days are relative to now, larger ones are Unix times
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+// flags, cas unique and expiry
+const HEADER_LEN: usize = 20;
+
+// keys flush_all removes in one batch
+const FLUSH_BATCH_LEN: usize = 1000;
+
+// the last cas unique handed out, which is moved up to the current time in
+// microseconds so that uniques are not reused after a restart
+static LAST_CAS: AtomicU64 = AtomicU64::new(0);
+
+/// An item, as stored in the memcached namespace.
+#[derive(Debug, Clone)]
+struct Item {
+    flags: u32,
+    cas: u64,
+    // milliseconds since the Unix epoch, 0 if the item never expires
+    expires: u64,
+    data: Vec<u8>,
+}
+
+impl Item {
+    fn new(flags: u32, expires: u64, data: Vec<u8>) -> Self {
+        Self {
+            flags,
+            cas: next_cas(),
+            expires,
+            data,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(HEADER_LEN + self.data.len());
+        raw.extend_from_slice(&self.flags.to_be_bytes());
+        raw.extend_from_slice(&self.cas.to_be_bytes());
+        raw.extend_from_slice(&self.expires.to_be_bytes());
+        raw.extend_from_slice(&self.data);
+        raw
+    }
+
+    // decode returns None for values too short to be an item, which were
+    // not written through this protocol
+    fn decode(raw: &[u8]) -> Option<Self> {
+        if raw.len() < HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            flags: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+            cas: u64::from_be_bytes(raw[4..12].try_into().unwrap()),
+            expires: u64::from_be_bytes(raw[12..20].try_into().unwrap()),
+            data: raw[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+/// A reply to a command.
+#[derive(Debug)]
+enum Reply {
+    // a status line, such as STORED or NOT_FOUND
+    Status(&'static str),
+    // an ERROR, CLIENT_ERROR or SERVER_ERROR line
+    Error(String),
+    // the new value of a counter
+    Counter(u64),
+    // the items a get found, with their cas uniques for a gets
+    Values {
+        items: Vec<(Vec<u8>, Item)>,
+        with_cas: bool,
+    },
+    Version,
+}
+
+impl Reply {
+    fn unknown_command() -> Self {
+        Reply::Error("ERROR".to_owned())
+    }
+
+    fn client_error(message: impl Into<String>) -> Self {
+        Reply::Error(format!("CLIENT_ERROR {}", message.into()))
+    }
+
+    fn bad_format() -> Self {
+        Reply::client_error("bad command line format")
+    }
+
+    fn is_error(&self) -> bool {
+        matches!(self, Reply::Error(_))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Status(status) => write!(writer, "{}\r\n", status),
+            // a message may not span lines
+            Reply::Error(message) => write!(writer, "{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Counter(n) => write!(writer, "{}\r\n", n),
+            Reply::Values { items, with_cas } => {
+                for (key, item) in items {
+                    writer.write_all(b"VALUE ")?;
+                    writer.write_all(key)?;
+                    write!(writer, " {} {}", item.flags, item.data.len())?;
+                    if *with_cas {
+                        write!(writer, " {}", item.cas)?;
+                    }
+                    writer.write_all(b"\r\n")?;
+                    writer.write_all(&item.data)?;
+                    writer.write_all(b"\r\n")?;
+                }
+                writer.write_all(b"END\r\n")
+            }
+            Reply::Version => write!(writer, "VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+// engine errors are reported as server errors
+impl From<KVError> for Reply {
+    fn from(e: KVError) -> Self {
+        Reply::Error(format!("SERVER_ERROR {}", e))
+    }
+}
+
+// the way a storage command writes its item
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    // only if the item still has this cas unique
+    Cas(u64),
+}
+
+// a storage command, whose data follows its command line
+struct Storage {
+    mode: Mode,
+    key: Vec<u8>,
+    flags: u32,
+    exptime: i64,
+    len: usize,
+    noreply: bool,
+}
+
+// serve answers the commands of a connection until the client hangs up,
+// goes idle or sends quit. Commands may be pipelined, their replies are sent
+// together once every command that has arrived is answered. Items are held
+// to max_data_len as well as to the limit of memcached itself.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: &E,
+    mut reader: BufReader<ConnectionReader>,
+    mut writer: BufWriter<ConnectionWriter>,
+    max_data_len: usize,
+) -> Result<()> {
+    let max_data_len = max_data_len.min(MAX_DATA_LEN);
+    let items = open_items(engine)?;
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        let line = match read_line(&mut reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(e) if is_hang_up(&e) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::client_error(e.to_string()).write(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let args: Vec<&[u8]> = line
+            .split(|&b| b == b' ')
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        let (command, args) = match args.split_first() {
+            Some((&command, args)) => (command, args),
+            None => {
+                Reply::unknown_command().write(&mut writer)?;
+                continue;
+            }
+        };
+
+        let (reply, noreply) = match command {
+            b"quit" => {
+                writer.flush()?;
+                return Ok(());
+            }
+            b"set" | b"add" | b"replace" | b"cas" => {
+                let storage = match parse_storage(command, args) {
+                    Ok(storage) => storage,
+                    Err(reply) => {
+                        reply.write(&mut writer)?;
+                        continue;
+                    }
+                };
+                if storage.len > MAX_SKIPPED_LEN {
+                    Reply::client_error("bad data chunk").write(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                // the data of an item that is too large is skipped, so that
+                // the next command can be read
+                if storage.len > max_data_len {
+                    let skipped = storage.len as u64 + 2;
+                    if io::copy(&mut (&mut reader).take(skipped), &mut io::sink())? < skipped {
+                        return Ok(());
+                    }
+                    Reply::Error("SERVER_ERROR object too large for cache".to_owned())
+                        .write(&mut writer)?;
+                    continue;
+                }
+
+                let data = match read_data(&mut reader, storage.len) {
+                    Ok(data) => data,
+                    Err(e) if is_hang_up(&e) || e.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Ok(())
+                    }
+                    // the stream can no longer be followed, so the
+                    // connection ends
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        Reply::client_error(e.to_string()).write(&mut writer)?;
+                        writer.flush()?;
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let noreply = storage.noreply;
+                (store(&items, storage, data), noreply)
+            }
+            _ => execute(&items, command, args),
+        };
+        if !noreply || reply.is_error() {
+            reply.write(&mut writer)?;
+        }
+    }
+}
+
+// busy tells a client the server has no room for it
+pub(crate) fn busy<W: Write>(writer: &mut W) -> io::Result<()> {
+    Reply::Error("SERVER_ERROR too many open connections".to_owned()).write(writer)?;
+    writer.flush()
+}
+
+// open_items returns the namespace items are kept in, creating it the first
+// time the protocol is served
+fn open_items<E: KvsEngine>(engine: &E) -> Result<E> {
+    match engine.create_namespace(NAMESPACE) {
+        Ok(()) | Err(KVError::NamespaceExists { .. }) => {}
+        Err(e) => return Err(e),
+    }
+    engine.open_namespace(NAMESPACE)
+}
+
+// read_line reads a command line, see connection::read_line
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    connection::read_line(reader, MAX_LINE_LEN).map_err(|e| match e {
+        LineError::TooLong => protocol_error("line too long"),
+        LineError::Io(e) => e,
+    })
+}
+
+// read_data reads the data block of a storage command, which ends with a
+// line ending of its own
+fn read_data<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Err(protocol_error("bad data chunk"));
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// parse_storage reads the command line of a storage command:
+//
+//   <command> <key> <flags> <exptime> <bytes> [noreply]
+//   cas <key> <flags> <exptime> <bytes> <cas unique> [noreply]
+fn parse_storage(command: &[u8], args: &[&[u8]]) -> std::result::Result<Storage, Reply> {
+    let (args, noreply) = split_noreply(args);
+    let (key, flags, exptime, len, mode) = match (command, args) {
+        (b"cas", &[key, flags, exptime, len, unique]) => {
+            let unique = parse(unique).ok_or_else(Reply::bad_format)?;
+            (key, flags, exptime, len, Mode::Cas(unique))
+        }
+        (b"cas", _) => return Err(Reply::unknown_command()),
+        (_, &[key, flags, exptime, len]) => {
+            let mode = match command {
+                b"set" => Mode::Set,
+                b"add" => Mode::Add,
+                _ => Mode::Replace,
+            };
+            (key, flags, exptime, len, mode)
+        }
+        _ => return Err(Reply::unknown_command()),
+    };
+
+    Ok(Storage {
+        mode,
+        key: check_key(key)?.to_vec(),
+        flags: parse(flags).ok_or_else(Reply::bad_format)?,
+        exptime: parse(exptime).ok_or_else(Reply::bad_format)?,
+        len: parse(len).ok_or_else(Reply::bad_format)?,
+        noreply,
+    })
+}
+
+// split_noreply takes the optional noreply off the end of the arguments
+fn split_noreply<'a, 'b>(args: &'a [&'b [u8]]) -> (&'a [&'b [u8]], bool) {
+    match args.split_last() {
+        Some((&b"noreply", args)) => (args, true),
+        _ => (args, false),
+    }
+}
+
+fn parse<T: str::FromStr>(arg: &[u8]) -> Option<T> {
+    str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// check_key refuses the keys memcached does, which are too long or hold
+// control characters
+fn check_key(key: &[u8]) -> std::result::Result<&[u8], Reply> {
+    if key.len() > MAX_KEY_LEN || key.iter().any(u8::is_ascii_control) {
+        return Err(Reply::bad_format());
+    }
+    Ok(key)
+}
+
+// execute runs a command other than a storage command or quit, and returns
+// its reply along with whether the client asked not to be sent it
+fn execute<E: KvsEngine>(items: &E, command: &[u8], args: &[&[u8]]) -> (Reply, bool) {
+    let (args, noreply) = split_noreply(args);
+    let reply = match (command, args) {
+        (b"get", [_, ..]) => get(items, args, false),
+        (b"gets", [_, ..]) => get(items, args, true),
+        (b"delete", [key]) | (b"delete", [key, b"0"]) => delete(items, key),
+        (b"delete", [_, _]) => Ok(Reply::client_error(
+            "bad command line format.  Usage: delete <key> [noreply]",
+        )),
+        (b"incr", [key, delta]) => incr(items, key, delta, true),
+        (b"decr", [key, delta]) => incr(items, key, delta, false),
+        (b"flush_all", []) => flush_all(items, 0),
+        (b"flush_all", [delay]) => match parse(delay) {
+            Some(delay) => flush_all(items, delay),
+            None => Ok(Reply::bad_format()),
+        },
+        (b"version", []) => Ok(Reply::Version),
+        _ => Ok(Reply::unknown_command()),
+    };
+    (reply.unwrap_or_else(Reply::from), noreply)
+}
+
+// Current is what a key holds: the value it is stored as, if any, and the
+// item that value is if it is a live one
+struct Current {
+    raw: Option<Vec<u8>>,
+    item: Option<Item>,
+}
+
+impl Current {
+    // is_expired tells whether the key holds an item that has expired
+    fn is_expired(&self) -> bool {
+        self.item.is_none() && self.raw.as_deref().and_then(Item::decode).is_some()
+    }
+}
+
+fn load<E: KvsEngine>(items: &E, key: &[u8]) -> Result<Current> {
+    let raw = items.get_bytes(key.to_vec())?;
+    let now = now_millis();
+    let item = raw
+        .as_deref()
+        .and_then(Item::decode)
+        .filter(|item| !item.is_expired(now));
+    Ok(Current { raw, item })
+}
+
+// swap replaces what current found at key with new, unless it has changed
+// since
+fn swap<E: KvsEngine>(items: &E, key: &[u8], current: Current, new: Option<&Item>) -> Result<bool> {
+    let outcome = items.compare_and_swap_bytes(key.to_vec(), current.raw, new.map(Item::encode))?;
+    Ok(outcome.is_swapped())
+}
+
+fn get<E: KvsEngine>(items: &E, keys: &[&[u8]], with_cas: bool) -> Result<Reply> {
+    let mut found = Vec::new();
+    for &key in keys {
+        if let Err(reply) = check_key(key) {
+            return Ok(reply);
+        }
+        let current = load(items, key)?;
+        match current.item.clone() {
+            Some(item) => found.push((key.to_vec(), item)),
+            // it does not matter if it has been written again meanwhile
+            None if current.is_expired() => {
+                swap(items, key, current, None)?;
+            }
+            None => {}
+        }
+    }
+    Ok(Reply::Values {
+        items: found,
+        with_cas,
+    })
+}
+
+fn store<E: KvsEngine>(items: &E, storage: Storage, data: Vec<u8>) -> Reply {
+    let Storage {
+        mode,
+        key,
+        flags,
+        exptime,
+        ..
+    } = storage;
+    let item = Item::new(flags, expires_at(exptime), data);
+    let stored = || -> Result<Reply> {
+        if let Mode::Set = mode {
+            set(items, key, &item)?;
+            return Ok(Reply::Status("STORED"));
+        }
+
+        loop {
+            let current = load(items, &key)?;
+            let refused = match (mode, &current.item) {
+                (Mode::Add, Some(_)) => Some("NOT_STORED"),
+                (Mode::Replace, None) => Some("NOT_STORED"),
+                (Mode::Cas(_), None) => Some("NOT_FOUND"),
+                (Mode::Cas(unique), Some(found)) if found.cas != unique => Some("EXISTS"),
+                _ => None,
+            };
+            if let Some(status) = refused {
+                return Ok(Reply::Status(status));
+            }
+            if swap(items, &key, current, Some(&item))? {
+                return Ok(Reply::Status("STORED"));
+            }
+        }
+    };
+    stored().unwrap_or_else(Reply::from)
+}
+
+// set writes item with a ttl that matches its expiry, or removes the key if
+// the item has expired already
+fn set<E: KvsEngine>(items: &E, key: Vec<u8>, item: &Item) -> Result<()> {
+    if item.expires == 0 {
+        return items.set_bytes(key, item.encode());
+    }
+    match item.expires.checked_sub(now_millis()) {
+        Some(ttl) if ttl > 0 => {
+            items.set_bytes_with_ttl(key, item.encode(), Duration::from_millis(ttl))
+        }
+        _ => match items.remove_bytes(key) {
+            Err(KVError::KeyNoExist) => Ok(()),
+            result => result,
+        },
+    }
+}
+
+fn delete<E: KvsEngine>(items: &E, key: &[u8]) -> Result<Reply> {
+    if let Err(reply) = check_key(key) {
+        return Ok(reply);
+    }
+    loop {
+        let current = load(items, key)?;
+        if current.item.is_none() {
+            return Ok(Reply::Status("NOT_FOUND"));
+        }
+        if swap(items, key, current, None)? {
+            return Ok(Reply::Status("DELETED"));
+        }
+    }
+}
+
+// incr adds delta to the decimal counter an item holds, wrapping around
+// past the largest u64, or subtracts it down to no less than 0. The item
+// keeps its flags and expiry.
+fn incr<E: KvsEngine>(items: &E, key: &[u8], delta: &[u8], up: bool) -> Result<Reply> {
+    if let Err(reply) = check_key(key) {
+        return Ok(reply);
+    }
+    let delta: u64 = match parse(delta) {
+        Some(delta) => delta,
+        None => return Ok(Reply::client_error("invalid numeric delta argument")),
+    };
+    loop {
+        let current = load(items, key)?;
+        let item = match &current.item {
+            Some(item) => item,
+            None => return Ok(Reply::Status("NOT_FOUND")),
+        };
+        let counter: u64 = match parse(&item.data) {
+            Some(counter) => counter,
+            None => {
+                return Ok(Reply::client_error(
+                    "cannot increment or decrement non-numeric value",
+                ))
+            }
+        };
+        let counter = if up {
+            counter.wrapping_add(delta)
+        } else {
+            counter.saturating_sub(delta)
+        };
+
+        let new = Item::new(item.flags, item.expires, counter.to_string().into_bytes());
+        if swap(items, key, current, Some(&new))? {
+            return Ok(Reply::Counter(counter));
+        }
+    }
+}
+
+// flush_all removes every item, either right away or after delay seconds
+fn flush_all<E: KvsEngine>(items: &E, delay: i64) -> Result<Reply> {
+    if delay <= 0 {
+        flush(items)?;
+    } else {
+        let items = items.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(delay as u64));
+            // there is no one left to report a failure to
+            let _ = flush(&items);
+        });
+    }
+    Ok(Reply::Status("OK"))
+}
+
+fn flush<E: KvsEngine>(items: &E) -> Result<()> {
+    let keys: Vec<Vec<u8>> = items
+        .scan_bytes(.., None)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    for keys in keys.chunks(FLUSH_BATCH_LEN) {
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove_bytes(key.clone());
+        }
+        items.write_batch(batch)?;
+    }
+    Ok(())
+}
+
+// expires_at turns the exptime of a command into the expiry of an item: 0
+// never expires, a negative one has expired already, one of up to 30 days
+// is a number of seconds from now and any other is a Unix time
+fn expires_at(exptime: i64) -> u64 {
+    match exptime {
+        0 => 0,
+        ..=-1 => 1,
+        1..=MAX_RELATIVE_EXPTIME => expiry::expires_at(Duration::from_secs(exptime as u64)),
+        _ => (exptime as u64).saturating_mul(1000),
+    }
+}
+
+fn next_cas() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    LAST_CAS.fetch_max(now, Ordering::SeqCst);
+    LAST_CAS.fetch_add(1, Ordering::SeqCst) + 1
+}
@@ -1,5 +1,8 @@
-use crate::common::{Engine, Methods};
+use crate::common::{Engine, Methods, Protocol};
+use crate::server::ServerLimits;
+use crate::{CompactionTrigger, Durability, KvStoreOptions};
 use clap::{self, Parser};
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::Kvs;
@@ -16,6 +19,9 @@ pub mod client_parser {
     pub struct Cli {
         #[clap(subcommand)]
         pub params: Methods,
+        /// Work on the keys of this namespace instead of the default ones
+        #[arg(short, long, global = true)]
+        pub namespace: Option<String>,
     }
 
     impl Cli {
@@ -39,11 +45,111 @@ pub mod server_parser {
         pub addr: String,
         #[arg(value_enum, short, long, default_value_t = super::DEFAULT_ENGINE)]
         pub engine: Engine,
+        /// The protocol to speak to clients: resp for Redis clients, http for
+        /// the HTTP/JSON gateway, memcached for memcached clients
+        #[arg(value_enum, long, default_value_t = Protocol::Kvs)]
+        pub protocol: Protocol,
+        /// Compact the kvs logs once this many bytes are stale
+        #[arg(long, conflicts_with = "compaction_ratio")]
+        pub compaction_threshold: Option<u64>,
+        /// Compact the kvs logs once this fraction of them is stale
+        #[arg(long)]
+        pub compaction_ratio: Option<f64>,
+        /// Start a new kvs log file once the active one reaches this many bytes
+        #[arg(long)]
+        pub max_file_size: Option<u64>,
+        #[arg(long)]
+        pub read_buffer_size: Option<usize>,
+        #[arg(long)]
+        pub write_buffer_size: Option<usize>,
+        /// Fail instead of creating a missing database directory
+        #[arg(long)]
+        pub no_create_dir: bool,
+        /// When writes are synced to disk: always, group-commit, interval:<ms> or never
+        #[arg(long, value_parser = parse_durability)]
+        pub durability: Option<Durability>,
+        /// Most connections served at once, clients beyond it are told the server is busy
+        #[arg(long)]
+        pub max_connections: Option<usize>,
+        /// Seconds a connection may wait between requests before it is closed, 0 for ever
+        #[arg(long)]
+        pub idle_timeout: Option<u64>,
+        /// Seconds to wait for more of a request that has begun to arrive, 0 for ever
+        #[arg(long)]
+        pub read_timeout: Option<u64>,
+        /// Seconds to wait for a client to take more of a response, 0 for ever
+        #[arg(long)]
+        pub write_timeout: Option<u64>,
+        /// Largest request to read, in bytes
+        #[arg(long)]
+        pub max_request_size: Option<usize>,
+        /// Most pairs to answer a scan with at once
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        pub max_scan_page: Option<u64>,
+    }
+
+    fn parse_durability(s: &str) -> Result<Durability, String> {
+        s.parse().map_err(|_| {
+            format!(
+                "invalid durability `{}`, expected always, group-commit, interval:<ms> or never",
+                s
+            )
+        })
     }
 
     impl Cli {
         pub fn parse_cli() -> Self {
             Self::parse()
         }
+
+        // kvs_options collects the flags that tune the kvs engine
+        pub fn kvs_options(&self) -> KvStoreOptions {
+            let mut options = KvStoreOptions::new().create_if_missing(!self.no_create_dir);
+
+            if let Some(bytes) = self.compaction_threshold {
+                options = options.compaction_trigger(CompactionTrigger::StaleBytes(bytes));
+            }
+            if let Some(ratio) = self.compaction_ratio {
+                options = options.compaction_trigger(CompactionTrigger::StaleRatio(ratio));
+            }
+            if let Some(bytes) = self.max_file_size {
+                options = options.max_file_size(bytes);
+            }
+            if let Some(bytes) = self.read_buffer_size {
+                options = options.read_buffer_size(bytes);
+            }
+            if let Some(bytes) = self.write_buffer_size {
+                options = options.write_buffer_size(bytes);
+            }
+            if let Some(durability) = self.durability {
+                options = options.durability(durability);
+            }
+            options
+        }
+
+        // server_limits collects the flags that bound what clients are given
+        pub fn server_limits(&self) -> ServerLimits {
+            let mut limits = ServerLimits::default();
+
+            if let Some(connections) = self.max_connections {
+                limits.max_connections = connections;
+            }
+            if let Some(secs) = self.idle_timeout {
+                limits.idle_timeout = Duration::from_secs(secs);
+            }
+            if let Some(secs) = self.read_timeout {
+                limits.read_timeout = Duration::from_secs(secs);
+            }
+            if let Some(secs) = self.write_timeout {
+                limits.write_timeout = Duration::from_secs(secs);
+            }
+            if let Some(bytes) = self.max_request_size {
+                limits.max_request_size = bytes;
+            }
+            if let Some(pairs) = self.max_scan_page {
+                limits.max_scan_page = pairs as usize;
+            }
+            limits
+        }
     }
 }
@@ -0,0 +1,523 @@
+// the Redis serialization protocol (RESP2), which `kvs-server --protocol
+// resp` speaks so that Redis clients and tools can talk to the store
+
+use crate::{
+    connection::{self, ConnectionReader, ConnectionWriter, LineError},
+    engines::{expiry, scan, KvsEngine},
+    error::{KVError, Result},
+    server::is_hang_up,
+    CasOutcome, WriteBatch,
+};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    ops::Bound,
+    str,
+    time::Duration,
+};
+
+// longest line of a command
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// most arguments a command may have
+const MAX_ARGS: usize = 64 * 1024;
+
+// arguments room is made for up front, as the count a command claims is
+// not to be trusted before they arrive
+const PREALLOCATED_ARGS: usize = 64;
+
+// keys a SCAN looks at when it is not given a COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// the Redis version reported by INFO, whose commands are the ones served
+const REDIS_VERSION: &str = "2.8.0";
+
+/// A reply to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Reply::Error(message.into())
+    }
+
+    fn wrong_arity(command: &str) -> Self {
+        Reply::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_lowercase()
+        ))
+    }
+
+    fn syntax_error() -> Self {
+        Reply::error("ERR syntax error")
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            // a message may not span lines
+            Reply::Error(message) => write!(writer, "-{}\r\n", message.replace(['\r', '\n'], " ")),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write(writer))
+            }
+        }
+    }
+}
+
+// engine errors are reported as generic errors
+impl From<KVError> for Reply {
+    fn from(e: KVError) -> Self {
+        Reply::error(format!("ERR {}", e))
+    }
+}
+
+// serve answers the commands of a connection until the client hangs up,
+// goes idle or sends QUIT. Commands may be pipelined, their replies are sent
+// together once every command that has arrived is answered. Bulk strings
+// longer than max_bulk_len are refused.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: &E,
+    mut reader: BufReader<ConnectionReader>,
+    mut writer: BufWriter<ConnectionWriter>,
+    max_bulk_len: usize,
+) -> Result<()> {
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+
+        let args = match read_command(&mut reader, max_bulk_len) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if is_hang_up(&e) => return Ok(()),
+            // the stream can no longer be followed, so the connection ends
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::error(format!("ERR Protocol error: {}", e)).write(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        if command == "QUIT" {
+            Reply::ok().write(&mut writer)?;
+            writer.flush()?;
+            return Ok(());
+        }
+        execute(engine, &command, &args[1..]).write(&mut writer)?;
+    }
+}
+
+// busy tells a client the server has no room for it, the way Redis does
+pub(crate) fn busy<W: Write>(writer: &mut W) -> io::Result<()> {
+    Reply::error("ERR max number of clients reached").write(writer)?;
+    writer.flush()
+}
+
+// read_command reads the next command, either as an array of bulk strings
+// or as an inline command of words separated by spaces. Returns None once
+// the client has hung up.
+fn read_command<R: BufRead>(
+    reader: &mut R,
+    max_bulk_len: usize,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..], MAX_ARGS, "invalid multibulk length")?;
+    let mut args = Vec::with_capacity(count.unwrap_or(0).min(PREALLOCATED_ARGS));
+    for _ in 0..count.unwrap_or(0) {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..line.len().min(1)])
+            )));
+        }
+        let len = parse_len(&line[1..], max_bulk_len, "invalid bulk length")?
+            .ok_or_else(|| protocol_error("invalid bulk length"))?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// read_line reads a line of a command, see connection::read_line
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    connection::read_line(reader, MAX_LINE_LEN).map_err(|e| match e {
+        LineError::TooLong => protocol_error("too big inline request"),
+        LineError::Io(e) => e,
+    })
+}
+
+// parse_len reads the length of an array or bulk string, which is None for
+// a null one
+fn parse_len(digits: &[u8], max: usize, error: &str) -> io::Result<Option<usize>> {
+    let len: i64 = str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(|| protocol_error(error))?;
+    match len {
+        ..=-1 => Ok(None),
+        len if len as u64 > max as u64 => Err(protocol_error(error)),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+fn protocol_error(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed mid command",
+    )
+}
+
+// execute runs a command given by its upper case name
+fn execute<E: KvsEngine>(engine: &E, command: &str, args: &[Vec<u8>]) -> Reply {
+    let reply = match command {
+        "PING" => match args {
+            [] => Ok(Reply::Simple("PONG")),
+            [message] => Ok(Reply::Bulk(Some(message.clone()))),
+            _ => Ok(Reply::wrong_arity(command)),
+        },
+        "GET" => match args {
+            [key] => engine.get_bytes(key.clone()).map(Reply::Bulk),
+            _ => Ok(Reply::wrong_arity(command)),
+        },
+        "SET" => match args {
+            [key, value, options @ ..] => set(engine, key, value, options),
+            _ => Ok(Reply::wrong_arity(command)),
+        },
+        "DEL" if !args.is_empty() => del(engine, args),
+        "EXISTS" if !args.is_empty() => args
+            .iter()
+            .try_fold(0, |found, key| {
+                Ok(found + engine.get_bytes(key.clone())?.is_some() as i64)
+            })
+            .map(Reply::Integer),
+        "MGET" if !args.is_empty() => args
+            .iter()
+            .map(|key| engine.get_bytes(key.clone()).map(Reply::Bulk))
+            .collect::<Result<_>>()
+            .map(Reply::Array),
+        "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => {
+            let mut batch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                batch.set_bytes(pair[0].clone(), pair[1].clone());
+            }
+            engine.write_batch(batch).map(|_| Reply::ok())
+        }
+        "INFO" if args.len() <= 1 => Ok(info()),
+        "SCAN" if !args.is_empty() => scan(engine, &args[0], &args[1..]),
+        "DEL" | "EXISTS" | "MGET" | "MSET" | "INFO" | "SCAN" => Ok(Reply::wrong_arity(command)),
+        _ => Ok(unknown_command(command, args)),
+    };
+    reply.unwrap_or_else(Reply::from)
+}
+
+fn unknown_command(command: &str, args: &[Vec<u8>]) -> Reply {
+    let args: String = args
+        .iter()
+        .map(|arg| format!("'{}' ", String::from_utf8_lossy(arg)))
+        .collect();
+    Reply::error(format!(
+        "ERR unknown command '{}', with args beginning with: {}",
+        command.to_lowercase(),
+        args
+    ))
+}
+
+// set supports the EX and PX options, and the NX and XX ones without them,
+// as conditional writes cannot set an expiry time
+fn set<E: KvsEngine>(engine: &E, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+    let mut ttl = None;
+    let mut condition = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                let amount = match options.next().and_then(|amount| parse_integer(amount)) {
+                    Some(amount) => amount,
+                    None => return Ok(Reply::error("ERR value is not an integer or out of range")),
+                };
+                // like Redis, expiry times that would not fit in milliseconds
+                // since the epoch are refused rather than wrapped
+                let millis = match unit {
+                    b"EX" => amount.checked_mul(1000),
+                    _ => Some(amount),
+                };
+                let now = expiry::now_millis() as i64;
+                match millis.filter(|&millis| millis > 0 && millis.checked_add(now).is_some()) {
+                    Some(millis) => ttl = Some(Duration::from_millis(millis as u64)),
+                    None => return Ok(Reply::error("ERR invalid expire time in 'set' command")),
+                }
+            }
+            condition_name @ (b"NX" | b"XX") if condition.is_none() => {
+                condition = Some(condition_name == b"NX");
+            }
+            _ => return Ok(Reply::syntax_error()),
+        }
+    }
+
+    let (key, value) = (key.to_vec(), value.to_vec());
+    match (condition, ttl) {
+        (None, None) => engine.set_bytes(key, value)?,
+        (None, Some(ttl)) => engine.set_bytes_with_ttl(key, value, ttl)?,
+        (Some(_), Some(_)) => {
+            return Ok(Reply::error(
+                "ERR NX and XX cannot be combined with EX or PX by this server",
+            ))
+        }
+        // NX
+        (Some(true), None) => {
+            if let CasOutcome::Current(_) = engine.compare_and_swap_bytes(key, None, Some(value))? {
+                return Ok(Reply::Bulk(None));
+            }
+        }
+        // XX, which retries until the key it found is still there when it
+        // is replaced
+        (Some(false), None) => loop {
+            let current = match engine.get_bytes(key.clone())? {
+                Some(current) => current,
+                None => return Ok(Reply::Bulk(None)),
+            };
+            let outcome =
+                engine.compare_and_swap_bytes(key.clone(), Some(current), Some(value.clone()))?;
+            if let CasOutcome::Swapped = outcome {
+                break;
+            }
+        },
+    }
+    Ok(Reply::ok())
+}
+
+fn del<E: KvsEngine>(engine: &E, keys: &[Vec<u8>]) -> Result<Reply> {
+    let mut removed = 0;
+    for key in keys {
+        match engine.remove_bytes(key.clone()) {
+            Ok(()) => removed += 1,
+            Err(KVError::KeyNoExist) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Reply::Integer(removed))
+}
+
+fn info() -> Reply {
+    let info = format!(
+        "# Server\r\nredis_version:{}\r\nkvs_version:{}\r\nredis_mode:standalone\r\nprocess_id:{}\r\n",
+        REDIS_VERSION,
+        env!("CARGO_PKG_VERSION"),
+        std::process::id()
+    );
+    Reply::Bulk(Some(info.into_bytes()))
+}
+
+// scan walks the keys in order. The cursor holds the last key that earlier
+// calls have walked past, so every call picks up right after it however the
+// keys before it have changed meanwhile.
+fn scan<E: KvsEngine>(engine: &E, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply> {
+    let after = match decode_cursor(cursor) {
+        Some(after) => after,
+        None => return Ok(Reply::error("ERR invalid cursor")),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_uppercase().as_slice(), option.get(1)) {
+            (b"MATCH", Some(matching)) => pattern = Some(matching.as_slice()),
+            (b"COUNT", Some(n)) => match parse_integer(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::syntax_error()),
+            },
+            _ => return Ok(Reply::syntax_error()),
+        }
+    }
+
+    // only the keys starting with the literal start of the pattern can match
+    let prefix = pattern.map_or(&[][..], literal_prefix);
+    let (lower, upper) = scan::prefix_range(prefix);
+    let lower = match after {
+        Some(after) if after.as_slice() >= prefix => Bound::Excluded(after),
+        _ => lower,
+    };
+    let walked: Vec<Vec<u8>> = engine
+        .scan_bytes((lower, upper), Some(count))?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+
+    let next = match walked.last() {
+        Some(last) if walked.len() == count => encode_cursor(last),
+        _ => b"0".to_vec(),
+    };
+    let keys = walked
+        .into_iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| Reply::Bulk(Some(key)))
+        .collect();
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next)),
+        Reply::Array(keys),
+    ]))
+}
+
+// encode_cursor writes the key a scan stopped at as a '1' followed by the
+// key in hex, which keeps it apart from the cursor "0" that starts a scan
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    let mut cursor = b"1".to_vec();
+    for b in key {
+        cursor.extend_from_slice(format!("{:02x}", b).as_bytes());
+    }
+    cursor
+}
+
+// decode_cursor returns the key a scan resumes after, which is None for the
+// cursor "0" that starts one, or fails when the cursor was not handed out
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Vec<u8>>> {
+    match cursor {
+        b"0" => Some(None),
+        [b'1', hex @ ..] if hex.len() % 2 == 0 && hex.iter().all(u8::is_ascii_hexdigit) => hex
+            .chunks(2)
+            .map(|digits| u8::from_str_radix(str::from_utf8(digits).ok()?, 16).ok())
+            .collect::<Option<_>>()
+            .map(Some),
+        _ => None,
+    }
+}
+
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// literal_prefix returns the start of a glob pattern that has no special
+// characters
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| b"*?[\\".contains(b))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// glob_match matches key against a Redis glob pattern, which supports '*',
+// '?', character classes such as [a-z] or [^abc], and '\' escapes. On a
+// mismatch only the last '*' seen is made to swallow one more byte, since
+// any match the earlier ones could still find is covered by it. That bounds
+// the work by the length of the pattern times the length of the key.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where to resume after the last '*', in the pattern and the key
+    let mut star = None;
+    while p < pattern.len() || k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(len) = key.get(k).and_then(|&b| match_one(&pattern[p..], b)) {
+            p += len;
+            k += 1;
+            continue;
+        }
+        match star {
+            Some((star_p, star_k)) if star_k < key.len() => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+// match_one tells how long the element at the start of pattern is if it
+// matches b. It is never given a '*'.
+fn match_one(pattern: &[u8], b: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', class @ ..] => match parse_class(class) {
+            Some((matches, rest)) => matches(b).then_some(pattern.len() - rest.len()),
+            // a class that is never closed stands for itself
+            None => (b == b'[').then_some(1),
+        },
+        [b'\\', escaped, ..] => (b == *escaped).then_some(2),
+        [literal, ..] => (b == *literal).then_some(1),
+    }
+}
+
+// parse_class reads a character class up to its closing ']', returning a
+// test for the bytes it matches and the rest of the pattern
+fn parse_class(class: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negated, class) = match class {
+        [b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let end = class.iter().position(|&b| b == b']')?;
+    let (members, rest) = (&class[..end], &class[end + 1..]);
+
+    let matches = move |b: u8| {
+        let mut found = false;
+        let mut i = 0;
+        while i < members.len() {
+            match members[i..] {
+                [b'\\', escaped, ..] => {
+                    found |= b == escaped;
+                    i += 2;
+                }
+                [low, b'-', high, ..] => {
+                    found |= (low.min(high)..=low.max(high)).contains(&b);
+                    i += 3;
+                }
+                [member, ..] => {
+                    found |= b == member;
+                    i += 1;
+                }
+                [] => unreachable!(),
+            }
+        }
+        found != negated
+    };
+    Some((matches, rest))
+}
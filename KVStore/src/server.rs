@@ -1,26 +1,96 @@
 use crate::{
-    common::Request,
-    common::{GetResponse, RmResponse, SetResponse},
+    common::{
+        AdminResponse, BeginResponse, Bytes, CasResponse, ErrResponse, GetResponse, IncrResponse,
+        NamespacesResponse, RmResponse, ScanResponse, SetResponse, TtlResponse, TxnResponse,
+        WatchResponse,
+    },
+    common::{Protocol, Request, WireFormat},
+    connection::{self, ConnectionReader, ConnectionWriter},
     engines::KvsEngine,
-    error::Result,
+    error::{KVError, Result},
+    frame::{self, Frame, Opcode},
+    http, memcached, resp,
     thread_pool::*,
+    CasOutcome, Scan, Transaction, Watcher,
 };
-use serde_json::Deserializer;
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering::SeqCst},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+        mpsc::RecvTimeoutError,
+        Arc, Condvar, Mutex,
     },
+    thread,
+    time::Duration,
 };
 
+// connections that send no request for this long are closed
+pub(crate) const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how often a watch without changes checks whether its client is still there
+pub(crate) const WATCH_HANGUP_CHECK: Duration = Duration::from_millis(500);
+
+// pairs a scan is answered with at once by default
+const DEFAULT_MAX_SCAN_PAGE: usize = 1000;
+
+// how long a shutdown waits for the connections still being served
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+// how long a refused kvs client is given to show whether it speaks frames or
+// the legacy protocol, and how many are waited on at once. Clients beyond
+// that are refused by closing their connection without a reply.
+const REFUSAL_WAIT: Duration = Duration::from_secs(1);
+const MAX_REFUSALS: usize = 64;
+
+// ServerLimits bounds what a Server gives to its clients. A zero timeout
+// waits forever.
+#[derive(Debug, Clone, Copy)]
+pub struct ServerLimits {
+    // connections served or waiting for the pool at once. Clients that
+    // connect beyond it are told the server is busy in its protocol.
+    pub max_connections: usize,
+    // how long a connection may wait between requests before it is closed
+    pub idle_timeout: Duration,
+    // how long the server waits for more of a request it has begun to receive
+    pub read_timeout: Duration,
+    // how long the server waits for a client to take more of a response
+    pub write_timeout: Duration,
+    // the largest request frame the server reads, in bytes. Legacy requests
+    // are held to it too, while the text protocols apply it to each value a
+    // request carries.
+    pub max_request_size: usize,
+    // the most pairs a scan is answered with at once, at least 1. Longer
+    // scans are answered a page at a time, which clients resume.
+    pub max_scan_page: usize,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            idle_timeout: CONNECTION_IDLE_TIMEOUT,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            max_request_size: frame::MAX_FRAME_LEN as usize,
+            max_scan_page: DEFAULT_MAX_SCAN_PAGE,
+        }
+    }
+}
+
 // Server is a runable server instance with pluggale engine
 pub struct Server<E: KvsEngine, P: ThreadPool> {
     engine: E,
     listener: TcpListener,
     pool: P,
     killed: Arc<AtomicBool>,
+    protocol: Protocol,
+    connections: Arc<Connections>,
+    refusals: Arc<AtomicUsize>,
+    shutdown_grace: Duration,
+    limits: ServerLimits,
 }
 
 // Server is a runable server instance with pluggale engine
@@ -35,10 +105,55 @@ impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
             listener,
             pool,
             killed,
+            protocol: Protocol::Kvs,
+            connections: Arc::new(Connections::default()),
+            refusals: Arc::new(AtomicUsize::new(0)),
+            shutdown_grace: SHUTDOWN_GRACE_PERIOD,
+            limits: ServerLimits::default(),
         })
     }
 
-    // run starts to listen a port and response any requests from client side
+    // with_protocol makes the server speak protocol instead of its own
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    // with_limits bounds the connections, the time and the request sizes
+    // the server gives to its clients
+    pub fn with_limits(mut self, limits: ServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // with_shutdown_grace sets how long a shutdown waits for the connections
+    // still being served before the engine is closed under them
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    // shutdown_handle returns a handle that shuts the server down from any
+    // thread. It shares the killed flag the server was created with.
+    pub fn shutdown_handle(&self) -> Result<ShutdownHandle> {
+        let mut addr = self.listener.local_addr()?;
+        // a server listening on every address is woken up through loopback
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+
+        Ok(ShutdownHandle {
+            killed: Arc::clone(&self.killed),
+            addr,
+        })
+    }
+
+    // run starts to listen a port and response any requests from client
+    // side, until the server is shut down. It then drains the connections it
+    // is still serving and closes the engine, see drain.
     pub fn run(&mut self) -> Result<()> {
         let listener = self.listener.try_clone()?;
 
@@ -48,11 +163,28 @@ impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
             }
 
             let engine = self.engine.clone();
+            let protocol = self.protocol;
+            let limits = self.limits;
 
-            match stream {
-                Ok(stream) => {
+            // connections beyond the limit are refused here, so that the
+            // pool never has more of them waiting than the limit allows
+            let stream = match stream {
+                Ok(stream) if self.connections.count() >= limits.max_connections => {
+                    self.refuse(stream);
+                    continue;
+                }
+                stream => stream,
+            };
+
+            let tracked = stream.and_then(|stream| {
+                let tracked = self.connections.track(&stream)?;
+                Ok((stream, tracked))
+            });
+            match tracked {
+                Ok((stream, tracked)) => {
                     self.pool.spawn(move || {
-                        if let Err(e) = request_handler(engine, stream) {
+                        let handled = request_handler(engine, stream, tracked, protocol, limits);
+                        if let Err(e) = handled {
                             eprintln!("Error in request handling: {}", e);
                         }
                     });
@@ -63,46 +195,715 @@ impl<E: KvsEngine, P: ThreadPool> Server<E, P> {
                 }
             }
         }
-        Ok(())
+        self.drain()
+    }
+
+    // refuse tells a client the server has no room for it. A kvs client is
+    // answered in the protocol it opens with, which is waited for on a
+    // thread of its own so that the accept loop goes on.
+    fn refuse(&self, stream: TcpStream) {
+        if self.protocol != Protocol::Kvs {
+            if let Err(e) = refuse_busy(stream, self.protocol) {
+                eprintln!("connection error: {}", e);
+            }
+            return;
+        }
+
+        if self.refusals.fetch_add(1, SeqCst) >= MAX_REFUSALS {
+            self.refusals.fetch_sub(1, SeqCst);
+            return;
+        }
+        let refusals = Arc::clone(&self.refusals);
+        thread::spawn(move || {
+            if let Err(e) = refuse_busy(stream, Protocol::Kvs) {
+                eprintln!("connection error: {}", e);
+            }
+            refusals.fetch_sub(1, SeqCst);
+        });
+    }
+
+    // drain stops reading requests from the connections still being served
+    // and waits for their handlers to answer what was already sent, for up
+    // to the grace period, which aborts the transactions they left open. The
+    // engine is then flushed. It is closed once the server is dropped, unless
+    // handlers that outlived the grace period still hold it.
+    fn drain(&self) -> Result<()> {
+        let left = self.connections.close(self.shutdown_grace);
+        if left > 0 {
+            eprintln!("shutting down with {} connections still open", left);
+        }
+        self.engine.flush()
+    }
+}
+
+// ShutdownHandle shuts down the Server it was taken from. The accept loop is
+// woken up right away, rather than at the next connection.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    killed: Arc<AtomicBool>,
+    addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    // shutdown makes the server stop accepting connections, after which its
+    // run method drains the ones it serves and returns
+    pub fn shutdown(&self) {
+        self.killed.store(true, SeqCst);
+        // the server only looks at killed once it accepts a connection, and
+        // it may have stopped already, which leaves nothing to wake up
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+// Connections keeps a handle to every connection being served, so that a
+// shutdown can stop reading requests from them and wait for their handlers
+#[derive(Default)]
+struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, TcpStream>>,
+    done: Condvar,
+}
+
+impl Connections {
+    fn count(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    fn track(self: &Arc<Self>, stream: &TcpStream) -> io::Result<Tracked> {
+        let id = self.next_id.fetch_add(1, SeqCst);
+        self.open.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(Tracked {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    // close shuts the reading half of every connection, which handlers see
+    // as the client hanging up once they have answered what it had sent. It
+    // returns how many are still open when grace is over.
+    fn close(&self, grace: Duration) -> usize {
+        let open = self.open.lock().unwrap();
+        for stream in open.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let (open, _) = self
+            .done
+            .wait_timeout_while(open, grace, |open| !open.is_empty())
+            .unwrap();
+        open.len()
+    }
+}
+
+// Tracked is a connection in Connections, which is forgotten once it is dropped
+struct Tracked {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+        self.connections.done.notify_all();
+    }
+}
+
+/// OpenTransactions holds the transactions a connection has begun but not
+/// yet committed or aborted, by id, along with the engine of the namespace
+/// each of them was begun in. Every connection has its own, so ids only name
+/// the transactions of the client that began them, and whatever it left open
+/// is aborted when the connection closes and its OpenTransactions is dropped.
+pub(crate) struct OpenTransactions<E> {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, (Transaction, E)>>,
+}
+
+impl<E> Default for OpenTransactions<E> {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<E: KvsEngine> OpenTransactions<E> {
+    fn begin(&self, engine: &E) -> Result<u64> {
+        let tx = engine.begin()?;
+        let id = self.next_id.fetch_add(1, SeqCst) + 1;
+        self.open.lock().unwrap().insert(id, (tx, engine.clone()));
+        Ok(id)
+    }
+
+    // with runs f on transaction id and keeps the transaction open
+    fn with<T, F>(&self, id: u64, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction) -> Result<T>,
+    {
+        // the transaction is taken out of the map, so that the lock is not
+        // held while it reads from the engine
+        let (mut tx, engine) = self.take(id)?;
+        let result = f(&mut tx);
+        self.open.lock().unwrap().insert(id, (tx, engine));
+        result
+    }
+
+    fn take(&self, id: u64) -> Result<(Transaction, E)> {
+        match self.open.lock().unwrap().remove(&id) {
+            Some(open) => Ok(open),
+            None => Err(KVError::NoTransaction { id }),
+        }
+    }
+}
+
+// request_handler answers the requests of a connection until the client
+// hangs up or goes idle. Clients may send several requests before reading
+// the responses, which are then answered in order.
+//
+// Connections that open with the magic bytes of the framed protocol speak
+// it, any other connection is taken to be a legacy one, whose requests and
+// responses follow each other without framing.
+fn request_handler<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    tracked: Tracked,
+    protocol: Protocol,
+    limits: ServerLimits,
+) -> Result<()> {
+    let (reader, writer) = connection::split(stream, &limits)?;
+    let mut reader = BufReader::new(reader);
+    let writer = BufWriter::new(writer);
+    let max_len = limits.max_request_size;
+    match protocol {
+        Protocol::Kvs => {}
+        Protocol::Resp => return resp::serve(&engine, reader, writer, max_len),
+        Protocol::Http => return http::serve(&engine, reader, writer, max_len),
+        Protocol::Memcached => return memcached::serve(&engine, reader, writer, max_len),
+    }
+
+    match next_request(&mut reader) {
+        Ok(Some(first)) if first == frame::MAGIC[0] => {
+            serve_frames(&engine, reader, writer, tracked, &limits)
+        }
+        Ok(Some(_)) => serve_legacy(&engine, reader, writer, tracked, &limits),
+        Ok(None) => Ok(()),
+        Err(e) if is_hang_up(&e) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// refuse_busy tells a client the server has no room for it, in the protocol
+// it speaks. Only kvs clients are waited on to tell whether they are framed,
+// the text protocols are answered right away.
+fn refuse_busy(mut stream: TcpStream, protocol: Protocol) -> Result<()> {
+    match protocol {
+        Protocol::Kvs => return refuse_kvs_busy(stream),
+        Protocol::Resp => resp::busy(&mut stream)?,
+        Protocol::Http => http::busy(&mut stream)?,
+        Protocol::Memcached => memcached::busy(&mut stream)?,
+    }
+    Ok(())
+}
+
+// refuse_kvs_busy answers a framed client with a busy handshake, and a legacy
+// one with an error response in the format of its request. Clients that say
+// nothing in time are refused by closing their connection.
+fn refuse_kvs_busy(mut stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(REFUSAL_WAIT))?;
+    let mut first = [0; 1];
+    match stream.read(&mut first) {
+        Ok(0) => return Ok(()),
+        Ok(_) => {}
+        Err(e) if is_hang_up(&e) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    if first[0] == frame::MAGIC[0] {
+        frame::refuse_busy(&mut stream)?;
+    } else {
+        let busy = ErrResponse::Err(KVError::ServerBusy.to_string());
+        WireFormat::detect(first[0]).encode(&mut stream, &busy)?;
+    }
+    close_unread(&stream)
+}
+
+// close_unread closes a connection after the reply to a request it did not
+// read all of. Closing a socket with data left unread resets the connection,
+// which could discard the reply before the client gets to it, so the rest is
+// read and dropped until the client hangs up or stops sending.
+fn close_unread(mut stream: &TcpStream) -> Result<()> {
+    stream.shutdown(Shutdown::Write)?;
+    match io::copy(&mut stream, &mut io::sink()) {
+        Err(e) if !is_hang_up(&e) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// serve_frames answers the request frames of a connection, after agreeing
+// on the protocol version with the client. Frames that are not requests, or
+// whose request cannot be decoded, are answered with an error frame, and so
+// are frames longer than the request size limit, which are skipped without
+// being read.
+fn serve_frames<E: KvsEngine>(
+    engine: &E,
+    mut reader: BufReader<ConnectionReader>,
+    mut writer: BufWriter<ConnectionWriter>,
+    tracked: Tracked,
+    limits: &ServerLimits,
+) -> Result<()> {
+    frame::server_handshake(&mut reader, &mut writer)?;
+
+    let transactions = OpenTransactions::default();
+    loop {
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        match reader.fill_buf() {
+            Ok([]) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if is_hang_up(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let frame = match Frame::read_limited(&mut reader, limits.max_request_size) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(e @ KVError::RequestTooLarge { id, .. }) => {
+                Frame::error(id, &e.to_string()).write(&mut writer)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let req = match decode_request(&frame) {
+            Ok(req) => req,
+            Err(error) => {
+                error.write(&mut writer)?;
+                continue;
+            }
+        };
+
+        let mut response = Vec::new();
+        let watcher = respond(
+            engine,
+            &transactions,
+            req,
+            WireFormat::Cbor,
+            limits.max_scan_page,
+            &mut response,
+        )?;
+        Frame::new(Opcode::Response, frame.id, response).write(&mut writer)?;
+
+        if let Some(watcher) = watcher {
+            // every change is a response to the watch request
+            let id = frame.id;
+            spawn_stream(watcher, writer, tracked, move |writer, change| {
+                let mut payload = Vec::new();
+                WireFormat::Cbor.encode(&mut payload, &change)?;
+                Frame::new(Opcode::Response, id, payload).write(writer)
+            })?;
+            return Ok(());
+        }
+    }
+}
+
+// decode_request returns the request a frame carries, or the error frame it
+// is to be answered with if it does not carry one
+pub(crate) fn decode_request(frame: &Frame) -> std::result::Result<Request, Frame> {
+    match frame.opcode {
+        Opcode::Request => WireFormat::Cbor
+            .decode(&frame.payload[..])
+            .map_err(|_| Frame::error(frame.id, "Error: could not decode the request")),
+        Opcode::Unknown(opcode) => Err(Frame::error(
+            frame.id,
+            &format!("Error: unknown opcode {}", opcode),
+        )),
+        opcode => Err(Frame::error(
+            frame.id,
+            &format!("Error: {:?} frames are not requests", opcode),
+        )),
     }
 }
 
-fn request_handler<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
-    let mut writer = BufWriter::new(stream);
+// serve_legacy answers the requests of a connection that does not use
+// frames. They are read in JSON or CBOR, whichever each of them comes in.
+// Without a length to skip them by, requests over the size limit are
+// answered with an error response and end the connection.
+fn serve_legacy<E: KvsEngine>(
+    engine: &E,
+    mut reader: BufReader<ConnectionReader>,
+    mut writer: BufWriter<ConnectionWriter>,
+    tracked: Tracked,
+    limits: &ServerLimits,
+) -> Result<()> {
+    let max_len = limits.max_request_size;
+    let transactions = OpenTransactions::default();
+    loop {
+        // the responses to requests that arrived together are sent together,
+        // before waiting for more
+        if reader.buffer().iter().all(u8::is_ascii_whitespace) {
+            writer.flush()?;
+        }
 
-    let mut reader_decode = reader.into_iter::<Request>();
+        let format = match next_request(&mut reader) {
+            Ok(Some(first)) => WireFormat::detect(first),
+            Ok(None) => return Ok(()),
+            Err(e) if is_hang_up(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut limited = (&mut reader).take(max_len as u64);
+        let req: Request = match format.decode(&mut limited) {
+            Ok(req) => req,
+            Err(_) if limited.limit() == 0 => {
+                let message = format!("Error: request is over the limit of {} bytes", max_len);
+                format.encode(&mut writer, &ErrResponse::Err(message))?;
+                writer.flush()?;
+                return close_unread(writer.get_ref().get_ref());
+            }
+            Err(e) => return Err(e),
+        };
 
-    let req = reader_decode
-        .next()
-        .expect("client request should not be empty!")?;
+        let max_scan = limits.max_scan_page;
+        if let Some(watcher) = respond(engine, &transactions, req, format, max_scan, &mut writer)? {
+            spawn_stream(watcher, writer, tracked, move |writer, change| {
+                format.encode(writer, &change)
+            })?;
+            return Ok(());
+        }
+    }
+}
 
-    let serialized = match req {
+// next_request waits for the next request on a connection and returns its
+// first byte, or None once the client has hung up. Whitespace left between
+// JSON requests is skipped, no CBOR request starts with any.
+fn next_request(reader: &mut BufReader<ConnectionReader>) -> io::Result<Option<u8>> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        match buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => {
+                let first = buffer[start];
+                reader.consume(start);
+                return Ok(Some(first));
+            }
+            None => {
+                let skipped = buffer.len();
+                reader.consume(skipped);
+            }
+        }
+    }
+}
+
+// is_hang_up tells whether a connection failed between requests because
+// the client went away or stayed silent for too long
+pub(crate) fn is_hang_up(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+// respond runs req on engine and writes the response in format. A Watch
+// request is answered with the watcher, whose changes the connection is
+// then given over to.
+pub(crate) fn respond<E: KvsEngine, W: Write>(
+    engine: &E,
+    transactions: &OpenTransactions<E>,
+    req: Request,
+    format: WireFormat,
+    max_scan: usize,
+    writer: &mut W,
+) -> Result<Option<Watcher>> {
+    match req {
         Request::Get { key } => {
-            let get_res = match engine.get(key) {
-                Ok(content) => GetResponse::Ok(content),
+            let get_res = match engine.get_bytes(key.into()) {
+                Ok(content) => GetResponse::Ok(content.map(Bytes)),
                 Err(e) => GetResponse::Err(e.to_string()),
             };
-            serde_json::to_string_pretty(&get_res)?
+            format.encode(&mut *writer, &get_res)?;
         }
-        Request::Set { key, value } => {
-            let set_res = match engine.set(key, value) {
+        Request::Set { key, value, ttl_ms } => {
+            let written = match ttl_ms {
+                Some(ttl) => {
+                    engine.set_bytes_with_ttl(key.into(), value.into(), Duration::from_millis(ttl))
+                }
+                None => engine.set_bytes(key.into(), value.into()),
+            };
+            let set_res = match written {
                 Ok(_) => SetResponse::Ok(),
                 Err(e) => SetResponse::Err(e.to_string()),
             };
-            serde_json::to_string_pretty(&set_res)?
+            format.encode(&mut *writer, &set_res)?;
+        }
+        Request::Ttl { key } => {
+            let ttl_res = match engine.ttl_bytes(key.into()) {
+                Ok(ttl) => TtlResponse::Ok(ttl.map(|ttl| ttl.as_millis() as u64)),
+                Err(e) => TtlResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &ttl_res)?;
         }
         Request::Remove { key } => {
-            let rm_res = match engine.remove(key) {
+            let rm_res = match engine.remove_bytes(key.into()) {
+                Ok(_) => RmResponse::Ok(),
+                Err(e) => RmResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &rm_res)?;
+        }
+        Request::Scan {
+            start,
+            end,
+            limit,
+            reverse,
+        } => {
+            let start = start.map_or(Bound::Unbounded, |key| Bound::Included(key.0));
+            let end = end.map_or(Bound::Unbounded, |key| Bound::Excluded(key.0));
+            let page = engine.scan_bytes((start, end), Some(scan_page(limit, max_scan)));
+            let scan_res = scan_response(page, reverse, limit, max_scan);
+            format.encode(&mut *writer, &scan_res)?;
+        }
+        Request::ScanPrefix {
+            prefix,
+            limit,
+            reverse,
+        } => {
+            let page = engine.scan_prefix_bytes(&prefix.0, Some(scan_page(limit, max_scan)));
+            let scan_res = scan_response(page, reverse, limit, max_scan);
+            format.encode(&mut *writer, &scan_res)?;
+        }
+        Request::SetIfAbsent { key, value } => {
+            let outcome = engine.compare_and_swap_bytes(key.into(), None, Some(value.into()));
+            format.encode(&mut *writer, &cas_response(outcome))?;
+        }
+        Request::SetIfEquals {
+            key,
+            expected,
+            value,
+        } => {
+            let outcome = engine.compare_and_swap_bytes(
+                key.into(),
+                Some(expected.into()),
+                Some(value.into()),
+            );
+            format.encode(&mut *writer, &cas_response(outcome))?;
+        }
+        Request::RemoveIfEquals { key, expected } => {
+            let outcome = engine.compare_and_swap_bytes(key.into(), Some(expected.into()), None);
+            format.encode(&mut *writer, &cas_response(outcome))?;
+        }
+        Request::Incr { key, delta } => {
+            let incr_res = match engine.incr_bytes(key.into(), delta) {
+                Ok(value) => IncrResponse::Ok(value),
+                Err(e) => IncrResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &incr_res)?;
+        }
+        Request::Merge {
+            key,
+            operator,
+            operand,
+        } => {
+            let merge_res = match engine.merge_bytes(key.into(), &operator, operand.into()) {
+                Ok(_) => SetResponse::Ok(),
+                Err(e) => SetResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &merge_res)?;
+        }
+        Request::Watch(watched) => match engine.watch(watched.into()) {
+            Ok(watcher) => {
+                format.encode(&mut *writer, &WatchResponse::Watching())?;
+                return Ok(Some(watcher));
+            }
+            Err(e) => format.encode(&mut *writer, &WatchResponse::Err(e.to_string()))?,
+        },
+        Request::Begin => {
+            let begin_res = match transactions.begin(engine) {
+                Ok(id) => BeginResponse::Ok(id),
+                Err(e) => BeginResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &begin_res)?;
+        }
+        Request::TxGet { tx, key } => {
+            let get_res = match transactions.with(tx, |tx| tx.get_bytes(key.into())) {
+                Ok(content) => GetResponse::Ok(content.map(Bytes)),
+                Err(e) => GetResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &get_res)?;
+        }
+        Request::TxSet { tx, key, value } => {
+            let set_res = match transactions.with(tx, |tx| tx.set_bytes(key.into(), value.into())) {
+                Ok(_) => SetResponse::Ok(),
+                Err(e) => SetResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &set_res)?;
+        }
+        Request::TxRemove { tx, key } => {
+            let rm_res = match transactions.with(tx, |tx| tx.remove_bytes(key.into())) {
                 Ok(_) => RmResponse::Ok(),
                 Err(e) => RmResponse::Err(e.to_string()),
             };
-            serde_json::to_string_pretty(&rm_res)?
+            format.encode(&mut *writer, &rm_res)?;
+        }
+        Request::Commit { tx } => {
+            let commit_res = match transactions
+                .take(tx)
+                .and_then(|(tx, engine)| engine.commit(tx))
+            {
+                Ok(_) => TxnResponse::Ok(),
+                Err(e) => TxnResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &commit_res)?;
+        }
+        Request::Abort { tx } => {
+            let abort_res = match transactions.take(tx) {
+                Ok(_) => TxnResponse::Ok(),
+                Err(e) => TxnResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &abort_res)?;
+        }
+        Request::InNamespace { namespace, request } => match engine.open_namespace(&namespace) {
+            Ok(engine) => {
+                return respond(&engine, transactions, *request, format, max_scan, writer)
+            }
+            Err(e) => format.encode(&mut *writer, &ErrResponse::Err(e.to_string()))?,
+        },
+        Request::CreateNamespace { name } => {
+            let create_res = match engine.create_namespace(&name) {
+                Ok(_) => AdminResponse::Ok(),
+                Err(e) => AdminResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &create_res)?;
+        }
+        Request::ListNamespaces => {
+            let list_res = match engine.namespaces() {
+                Ok(names) => NamespacesResponse::Ok(names),
+                Err(e) => NamespacesResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &list_res)?;
         }
-    };
+        Request::DropNamespace { name } => {
+            let drop_res = match engine.drop_namespace(&name) {
+                Ok(_) => AdminResponse::Ok(),
+                Err(e) => AdminResponse::Err(e.to_string()),
+            };
+            format.encode(&mut *writer, &drop_res)?;
+        }
+    }
 
-    writer.write(serialized.as_bytes())?;
-    writer.flush()?;
+    Ok(None)
+}
 
+// spawn_stream hands the connection over to the changes the watcher sees,
+// which send writes out. A watch lasts for as long as its client likes, so
+// it gets a thread of its own instead of holding on to one of the pool. The
+// connection stays tracked until the stream ends, so that it counts against
+// the connection limit and is closed by a shutdown.
+fn spawn_stream<F>(
+    watcher: Watcher,
+    mut writer: BufWriter<ConnectionWriter>,
+    tracked: Tracked,
+    send: F,
+) -> Result<()>
+where
+    F: FnMut(&mut BufWriter<ConnectionWriter>, WatchResponse) -> Result<()> + Send + 'static,
+{
+    writer.flush()?;
+    thread::spawn(move || {
+        let _tracked = tracked;
+        if let Err(e) = stream_changes(watcher, writer, send) {
+            eprintln!("Error in streaming changes: {}", e);
+        }
+    });
     Ok(())
 }
+
+// stream_changes sends every change the watcher sees to its client, until
+// the client hangs up or the engine is dropped
+fn stream_changes<F>(
+    watcher: Watcher,
+    mut writer: BufWriter<ConnectionWriter>,
+    mut send: F,
+) -> Result<()>
+where
+    F: FnMut(&mut BufWriter<ConnectionWriter>, WatchResponse) -> Result<()>,
+{
+    loop {
+        match watcher.next_timeout(WATCH_HANGUP_CHECK) {
+            Ok(change) => {
+                send(&mut writer, WatchResponse::from(change))?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if hung_up(writer.get_ref().get_ref()) {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+// hung_up tells whether the other end of stream has closed or reset the
+// connection, without waiting for it to send anything
+fn hung_up(stream: &TcpStream) -> bool {
+    let peeked = stream
+        .set_nonblocking(true)
+        .and_then(|_| stream.peek(&mut [0; 1]));
+    let _ = stream.set_nonblocking(false);
+    match peeked {
+        Ok(read) => read == 0,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    }
+}
+
+// scan_page returns the limit a scan asking for limit pairs is run with,
+// which holds it to max_page pairs
+fn scan_page(limit: Option<usize>, max_page: usize) -> usize {
+    let max_page = max_page.max(1);
+    limit.map_or(max_page, |limit| limit.min(max_page))
+}
+
+// scan_response answers a scan run with the limit scan_page gave it. A scan
+// that stopped at max_page pairs may have more to go, and is answered with
+// a partial page.
+fn scan_response(
+    scan: Result<Scan<Vec<u8>>>,
+    reverse: bool,
+    limit: Option<usize>,
+    max_page: usize,
+) -> ScanResponse {
+    let pairs: Result<Vec<_>> = scan.and_then(|scan| {
+        if reverse {
+            scan.rev().collect()
+        } else {
+            scan.collect()
+        }
+    });
+
+    match pairs {
+        Ok(pairs) => {
+            let max_page = max_page.max(1);
+            let full = pairs.len() == max_page && limit.is_none_or(|limit| limit > max_page);
+            let pairs = pairs
+                .into_iter()
+                .map(|(key, value)| (Bytes(key), Bytes(value)))
+                .collect();
+            if full {
+                ScanResponse::Partial(pairs)
+            } else {
+                ScanResponse::Ok(pairs)
+            }
+        }
+        Err(e) => ScanResponse::Err(e.to_string()),
+    }
+}
+
+fn cas_response(outcome: Result<CasOutcome<Vec<u8>>>) -> CasResponse {
+    match outcome {
+        Ok(CasOutcome::Swapped) => CasResponse::Swapped(),
+        Ok(CasOutcome::Current(value)) => CasResponse::Current(value.map(Bytes)),
+        Err(e) => CasResponse::Err(e.to_string()),
+    }
+}
@@ -16,6 +16,6 @@ impl ThreadPool for NaiveThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(|| job());
+        thread::spawn(job);
     }
 }
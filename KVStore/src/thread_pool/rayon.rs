@@ -23,6 +23,6 @@ impl ThreadPool for RayonThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(job);
-        self.pool.spawn(|| job())
+        self.pool.spawn(job)
     }
 }
@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CasOutcome, Change, ChangeOp, CompactionTrigger, Durability, KVError, KvStore, KvStoreOptions,
+    KvsEngine, KvsSnapshot, MergeOperator, Result, SledKvsEngine, Transaction, WatchFilter,
+    Watcher, WriteBatch,
+};
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +216,1682 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should replay logs written in the legacy pretty-printed JSON format
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = concat!(
+        "{\n  \"Set\": {\n    \"key\": \"key1\",\n    \"value\": \"value1\"\n  }\n}",
+        "{\n  \"Set\": {\n    \"key\": \"key2\",\n    \"value\": \"value2\"\n  }\n}",
+        "{\n  \"Remove\": {\n    \"key\": \"key2\"\n  }\n}",
+    );
+    fs::write(temp_dir.path().join("1.log"), legacy).expect("unable to write legacy log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check both formats are replayed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Each record should only cost a fixed-size header on top of its key and value
+#[test]
+fn binary_log_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let len: u64 = fs::read_dir(temp_dir.path())
+        .expect("unable to list the store directory")
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+
+    // file header + set record + remove record
+    assert_eq!(len, 5 + (13 + 4 + 6) + (13 + 4));
+
+    Ok(())
+}
+
+// Flip a single byte inside the value of the first record in a log file
+fn flip_first_value_byte(path: &Path) {
+    let mut content = fs::read(path).expect("unable to read log file");
+    // file header (5) + record header (13) + "key1"
+    content[5 + 13 + 4] ^= 0x01;
+    fs::write(path, content).expect("unable to write log file");
+}
+
+// Should refuse to open a store whose log has a corrupted record
+#[test]
+fn detect_corruption_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    flip_first_value_byte(&temp_dir.path().join("1.log"));
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KVError::Corruption { file, offset }) => {
+            assert!(file.ends_with("1.log"));
+            assert_eq!(offset, 5);
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    Ok(())
+}
+
+// Should report a corrupted record when reading it back
+#[test]
+fn detect_corruption_on_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    flip_first_value_byte(&temp_dir.path().join("1.log"));
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KVError::Corruption { offset: 5, .. })
+    ));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Cut the last `len` bytes off a log file
+fn truncate_log(path: &Path, len: u64) {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .expect("unable to open log file");
+    let size = file.metadata().expect("unable to stat log file").len();
+    file.set_len(size - len)
+        .expect("unable to truncate log file");
+}
+
+// Should drop a partially written record at the end of the newest log
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    truncate_log(&log, 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        fs::metadata(&log).expect("log was removed").len(),
+        5 + 13 + 4 + 6
+    );
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should drop a partially written object at the end of a legacy JSON log
+#[test]
+fn recover_torn_legacy_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = concat!(
+        "{\n  \"Set\": {\n    \"key\": \"key1\",\n    \"value\": \"value1\"\n  }\n}",
+        "{\n  \"Set\": {\n    \"key\": \"key2\",\n    \"val",
+    );
+    fs::write(temp_dir.path().join("1.log"), legacy).expect("unable to write legacy log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should refuse to open when an older generation ends in a torn record
+#[test]
+fn torn_record_in_old_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    truncate_log(&temp_dir.path().join("1.log"), 3);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// Should refuse to open when a record in the middle of the newest log is
+// corrupted, whether its length now runs past the end of the log or the
+// last record fails its checksum
+#[test]
+fn corruption_in_newest_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let original = fs::read(&log).expect("unable to read log file");
+
+    // the high byte of the key length of the first record
+    let mut content = original.clone();
+    content[5 + 8] = 0x01;
+    fs::write(&log, &content).expect("unable to write log file");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVError::Corruption { offset: 5, .. })
+    ));
+    assert_eq!(
+        fs::metadata(&log).expect("log was removed").len(),
+        original.len() as u64
+    );
+
+    // the last byte of the value of the last record
+    let mut content = original;
+    *content.last_mut().unwrap() ^= 0x01;
+    fs::write(&log, &content).expect("unable to write log file");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// Should start over a newest log whose file header was torn
+#[test]
+fn recover_torn_log_header() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("1.log"), b"KVS").expect("unable to write log file");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Overwrite the same keys until a compaction leaves a hint file behind
+fn compact_with_hint(store: &KvStore, dir: &Path) -> Result<()> {
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if hint_files(dir).next().is_some() {
+            return Ok(());
+        }
+    }
+    panic!("No compaction detected");
+}
+
+fn hint_files(dir: &Path) -> impl Iterator<Item = std::path::PathBuf> {
+    fs::read_dir(dir)
+        .expect("unable to list the store directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+}
+
+// Should load compacted generations from their hint files
+#[test]
+fn reopen_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compact_with_hint(&store, temp_dir.path())?;
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+    let expected = store.get("key2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, expected);
+
+    Ok(())
+}
+
+// Should replay the log when its hint file is damaged
+#[test]
+fn reopen_with_stale_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compact_with_hint(&store, temp_dir.path())?;
+    let expected = store.get("key2".to_owned())?;
+    drop(store);
+
+    for hint in hint_files(temp_dir.path()) {
+        fs::write(hint, b"garbage").expect("unable to overwrite hint file");
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, expected);
+
+    Ok(())
+}
+
+// Should discard generations left over from an interrupted compaction cleanup
+#[test]
+fn remove_leftover_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compact_with_hint(&store, temp_dir.path())?;
+    drop(store);
+
+    // a pre-compaction generation that still holds a since removed key
+    let leftover = r#"{"Set":{"key":"removed","value":"stale"}}"#;
+    fs::write(temp_dir.path().join("0.log"), leftover).expect("unable to write log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("removed".to_owned())?, None);
+    assert!(!temp_dir.path().join("0.log").exists());
+
+    Ok(())
+}
+
+// Should ignore a hint file whose compacted log was never put in place
+#[test]
+fn ignore_hint_without_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    compact_with_hint(&store, temp_dir.path())?;
+    store.set("key0".to_owned(), "after".to_owned())?;
+    drop(store);
+
+    // a compaction that stopped between writing its hint and renaming its log
+    let hint = hint_files(temp_dir.path()).next().unwrap();
+    let orphan = temp_dir.path().join("1000000.hint");
+    fs::copy(hint, &orphan).expect("unable to copy hint file");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert!(!orphan.exists());
+
+    Ok(())
+}
+
+// Writers overwriting keys while compactions run in the background should
+// never have their newer values replaced by compacted copies
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in (thread_id..1000).step_by(8) {
+                    store
+                        .set(format!("key{}", key_id), format!("{}-{}", key_id, iter))
+                        .unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(hint_files(temp_dir.path()).next().is_some());
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-199", key_id))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-199", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should compact as soon as the configured amount of data is stale
+#[test]
+fn compaction_threshold_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(1024))
+        .open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    // compaction finishes in the background
+    for _ in 0..100 {
+        if hint_files(temp_dir.path()).next().is_some() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should compact once the configured fraction of the logs is stale
+#[test]
+fn compaction_ratio_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleRatio(0.5))
+        .open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if hint_files(temp_dir.path()).next().is_some() {
+            return Ok(());
+        }
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should start a new generation whenever the active log is full
+#[test]
+fn max_file_size_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .read_buffer_size(64)
+        .write_buffer_size(64);
+    let store = options.open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let logs = fs::read_dir(temp_dir.path())
+        .expect("unable to list the store directory")
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(logs > 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should refuse to open a missing directory unless asked to create it
+#[test]
+fn create_if_missing_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("missing");
+
+    let options = KvStoreOptions::new().create_if_missing(false);
+    assert!(matches!(
+        options.open(&path),
+        Err(KVError::MissingDirectory { .. })
+    ));
+    assert!(!path.exists());
+
+    KvStoreOptions::new().open(&path)?;
+    assert!(path.is_dir());
+    options.open(&path)?;
+
+    Ok(())
+}
+
+// Should parse the durability names used by kvs-server
+#[test]
+fn parse_durability() {
+    assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+    assert_eq!(
+        "group-commit".parse::<Durability>().unwrap(),
+        Durability::GroupCommit
+    );
+    assert_eq!(
+        "interval:250".parse::<Durability>().unwrap(),
+        Durability::Interval(Duration::from_millis(250))
+    );
+    assert_eq!("never".parse::<Durability>().unwrap(), Durability::Never);
+    assert!("interval:".parse::<Durability>().is_err());
+    assert!("interval:0".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+
+    let interval = Durability::Interval(Duration::from_millis(250));
+    assert_eq!(
+        interval.to_string().parse::<Durability>().unwrap(),
+        interval
+    );
+}
+
+// concurrent_durable_writes sets keys from several threads at once and
+// checks that every write is readable after reopening
+fn concurrent_durable_writes<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for key_id in 0..50 {
+                    store
+                        .set(
+                            format!("key{}-{}", thread_id, key_id),
+                            format!("value{}", key_id),
+                        )
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn durability_modes() -> Result<()> {
+    for durability in [
+        Durability::Always,
+        Durability::GroupCommit,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Interval(Duration::ZERO),
+        Durability::Never,
+    ] {
+        let options = KvStoreOptions::new()
+            .durability(durability)
+            .max_file_size(4 * 1024);
+        concurrent_durable_writes(|path| options.open(path))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sled_durability_modes() -> Result<()> {
+    for durability in [
+        Durability::Always,
+        Durability::GroupCommit,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Interval(Duration::ZERO),
+    ] {
+        concurrent_durable_writes(|path| SledKvsEngine::open_with_durability(path, durability))?;
+    }
+    Ok(())
+}
+
+// write_batch_then_reopen applies a batch mixing sets and removes, including
+// the removal of a key that does not exist, and checks it after reopening
+fn write_batch_then_reopen<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value3".to_owned())
+        .remove("key2".to_owned())
+        .set("key3".to_owned(), "value4".to_owned())
+        .remove("missing".to_owned());
+    assert_eq!(batch.len(), 4);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+
+    for _ in 0..2 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    }
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    write_batch_then_reopen(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    write_batch_then_reopen(|path| SledKvsEngine::open(path))
+}
+
+// Should discard a batch whose commit record never reached the log, whether
+// the log ends cleanly before the commit record or in the middle of a record
+#[test]
+fn discard_uncommitted_batch() -> Result<()> {
+    // the commit record is an empty record of 4 + 13 bytes
+    for cut in [17, 20] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key1".to_owned(), "value2".to_owned())
+            .set("key2".to_owned(), "value2".to_owned());
+        store.write_batch(batch)?;
+        drop(store);
+
+        truncate_log(&temp_dir.path().join("1.log"), cut);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Readers should never observe half of a batch
+#[test]
+fn concurrent_reads_during_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("left".to_owned(), "0".to_owned())?;
+    store.set("right".to_owned(), "0".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 1..500 {
+                let mut batch = WriteBatch::new();
+                batch
+                    .set("left".to_owned(), i.to_string())
+                    .set("right".to_owned(), i.to_string());
+                store.write_batch(batch).unwrap();
+            }
+        })
+    };
+
+    while !writer.is_finished() {
+        // once left shows a batch, right must show it too, even though
+        // the batch writes left first
+        let left: u32 = store.get("left".to_owned())?.unwrap().parse().unwrap();
+        let right: u32 = store.get("right".to_owned())?.unwrap().parse().unwrap();
+        assert!(right >= left);
+    }
+    writer.join().unwrap();
+
+    Ok(())
+}
+
+fn keys(scan: impl Iterator<Item = Result<(String, String)>>) -> Result<Vec<String>> {
+    scan.map(|pair| pair.map(|(key, _)| key)).collect()
+}
+
+// scan_ranges fills a store with keys in random order and checks ordered
+// scans over ranges and prefixes in both directions
+fn scan_ranges<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for key_id in [7, 3, 9, 1, 5, 0, 8, 2, 6, 4] {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.set(format!("other{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let expected =
+        |ids: &[u32]| -> Vec<String> { ids.iter().map(|id| format!("key{}", id)).collect() };
+
+    let all: Vec<(String, String)> = store.scan_prefix("key", None)?.collect::<Result<_>>()?;
+    assert_eq!(all.len(), 9);
+    assert_eq!(all[0], ("key0".to_owned(), "value0".to_owned()));
+
+    assert_eq!(
+        keys(store.scan("key2".to_owned().."key7".to_owned(), None)?)?,
+        expected(&[2, 3, 4, 6])
+    );
+    assert_eq!(
+        keys(store.scan("key2".to_owned()..="key7".to_owned(), Some(3))?)?,
+        expected(&[2, 3, 4])
+    );
+    assert_eq!(
+        keys(
+            store
+                .scan("key2".to_owned()..="key7".to_owned(), Some(3))?
+                .rev()
+        )?,
+        expected(&[7, 6, 4])
+    );
+    assert_eq!(
+        keys(store.scan_prefix("key", Some(2))?.rev())?,
+        expected(&[9, 8])
+    );
+    assert_eq!(keys(store.scan("other".to_owned().., None)?)?.len(), 10);
+    assert!(keys(store.scan("key7".to_owned().."key2".to_owned(), None)?)?.is_empty());
+    assert!(keys(store.scan_prefix("missing", None)?)?.is_empty());
+
+    // both ends meet in the middle without returning a key twice
+    let mut scan = store.scan_prefix("key", None)?;
+    let mut seen = Vec::new();
+    while let (Some(front), back) = (scan.next(), scan.next_back()) {
+        seen.push(front?.0);
+        seen.extend(back.transpose()?.map(|(key, _)| key));
+    }
+    seen.sort();
+    assert_eq!(seen, expected(&[0, 1, 2, 3, 4, 6, 7, 8, 9]));
+
+    // a sled iterator keeps its database open
+    drop(scan);
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(
+        keys(store.scan_prefix("key", Some(3))?.rev())?,
+        expected(&[9, 8, 7])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    scan_ranges(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_scan() -> Result<()> {
+    scan_ranges(|path| SledKvsEngine::open(path))
+}
+
+// Should keep scanning over values that compaction moves to a new generation
+#[test]
+fn scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{:04}", key_id), "0".to_owned())?;
+    }
+
+    let scanner = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..20 {
+                let count = store
+                    .scan_prefix("key", None)?
+                    .collect::<Result<Vec<_>>>()?
+                    .len();
+                assert_eq!(count, 1000);
+            }
+            Ok(())
+        })
+    };
+
+    for iter in 1..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{:04}", key_id), iter.to_string())?;
+        }
+        if scanner.is_finished() {
+            break;
+        }
+    }
+    scanner.join().unwrap()
+}
+
+// snapshot_isolation checks that a snapshot keeps showing the keys as they
+// were when it was taken
+fn snapshot_isolation<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(
+        keys(snapshot.scan_prefix("key", None)?.rev())?,
+        vec!["key2".to_owned(), "key1".to_owned()]
+    );
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        keys(store.snapshot()?.scan_prefix("key", None)?)?,
+        vec!["key1".to_owned(), "key3".to_owned()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_isolation(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_isolation(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Should keep the generations a snapshot reads from until it is dropped
+#[test]
+fn snapshot_pins_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "original".to_owned())?;
+    }
+
+    let snapshot = store.snapshot()?;
+    let scan = snapshot.scan_prefix("key", None)?;
+    drop(snapshot);
+
+    compact_with_hint(&store, temp_dir.path())?;
+    let first_log = temp_dir.path().join("1.log");
+    assert!(first_log.exists());
+
+    let values: Vec<(String, String)> = scan.collect::<Result<_>>()?;
+    assert_eq!(values.len(), 1000);
+    assert!(values.iter().all(|(_, value)| value == "original"));
+    for _ in 0..100 {
+        if !first_log.exists() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("compacted generation was kept after the snapshot was dropped");
+}
+
+// transactions checks commits, conflicts between concurrent transactions and
+// plain writes, and aborts
+fn transactions<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    let sum = store.transaction(|tx| {
+        let a: u32 = tx.get("a".to_owned())?.unwrap().parse().unwrap();
+        let b: u32 = tx.get("b".to_owned())?.unwrap().parse().unwrap();
+        tx.set("sum".to_owned(), (a + b).to_string())?;
+        tx.remove("a".to_owned())?;
+        assert_eq!(tx.get("sum".to_owned())?, Some("3".to_owned()));
+        assert_eq!(tx.get("a".to_owned())?, None);
+        assert!(matches!(
+            tx.remove("missing".to_owned()),
+            Err(KVError::KeyNoExist)
+        ));
+        Ok(a + b)
+    })?;
+    assert_eq!(sum, 3);
+    assert_eq!(store.get("sum".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("a".to_owned())?, None);
+
+    // a key read by the transaction changes before it commits
+    let mut tx = store.begin()?;
+    tx.get("b".to_owned())?;
+    tx.set("c".to_owned(), "6".to_owned())?;
+    store.set("b".to_owned(), "5".to_owned())?;
+    assert!(matches!(
+        store.commit(tx),
+        Err(KVError::TransactionConflict)
+    ));
+    assert_eq!(store.get("c".to_owned())?, None);
+
+    // the first of two transactions writing the same key wins
+    let mut first = store.begin()?;
+    let mut second = store.begin()?;
+    first.set("c".to_owned(), "first".to_owned())?;
+    second.set("c".to_owned(), "second".to_owned())?;
+    store.commit(first)?;
+    assert!(matches!(
+        store.commit(second),
+        Err(KVError::TransactionConflict)
+    ));
+    assert_eq!(store.get("c".to_owned())?, Some("first".to_owned()));
+
+    // reads never mix states from before and after a concurrent write
+    let mut tx = store.begin()?;
+    tx.get("b".to_owned())?;
+    store.set("b".to_owned(), "7".to_owned())?;
+    assert!(matches!(
+        tx.get("c".to_owned()),
+        Err(KVError::TransactionConflict)
+    ));
+
+    // a failing transaction writes nothing
+    let result: Result<()> = store.transaction(|tx| {
+        tx.set("d".to_owned(), "8".to_owned())?;
+        Err(KVError::KeyNoExist)
+    });
+    assert!(result.is_err());
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    // an aborted transaction writes nothing
+    let mut tx: Transaction = store.begin()?;
+    tx.set("d".to_owned(), "9".to_owned())?;
+    drop(tx);
+    assert_eq!(store.get("d".to_owned())?, None);
+
+    Ok(())
+}
+
+// concurrent_increments has several threads increment one counter through
+// transactions, retrying on conflicts, and checks that no increment is lost
+fn concurrent_increments<E: KvsEngine>(store: E) -> Result<()> {
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let result = store.transaction(|tx| {
+                            let count: u32 =
+                                tx.get("counter".to_owned())?.unwrap().parse().unwrap();
+                            tx.set("counter".to_owned(), (count + 1).to_string())
+                        });
+                        match result {
+                            Ok(()) => break,
+                            Err(KVError::TransactionConflict) => continue,
+                            Err(e) => panic!("transaction failed: {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions(KvStore::open(temp_dir.path())?)?;
+    drop(temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transactions(SledKvsEngine::open(temp_dir.path())?)?;
+    drop(temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_increments(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// conditional_writes checks that conditional writes only apply when the key
+// holds the expected value, and report the value found otherwise
+fn conditional_writes<E: KvsEngine>(store: E) -> Result<()> {
+    assert_eq!(
+        store.set_if_absent("key".to_owned(), "1".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        store.set_if_absent("key".to_owned(), "2".to_owned())?,
+        CasOutcome::Current(Some("1".to_owned()))
+    );
+
+    assert_eq!(
+        store.set_if_equals("key".to_owned(), "2".to_owned(), "3".to_owned())?,
+        CasOutcome::Current(Some("1".to_owned()))
+    );
+    assert_eq!(
+        store.set_if_equals("key".to_owned(), "1".to_owned(), "3".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(store.get("key".to_owned())?, Some("3".to_owned()));
+
+    assert_eq!(
+        store.remove_if_equals("key".to_owned(), "1".to_owned())?,
+        CasOutcome::Current(Some("3".to_owned()))
+    );
+    assert!(store
+        .remove_if_equals("key".to_owned(), "3".to_owned())?
+        .is_swapped());
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    assert_eq!(
+        store.set_if_equals("key".to_owned(), "3".to_owned(), "4".to_owned())?,
+        CasOutcome::Current(None)
+    );
+    assert_eq!(
+        store.remove_if_equals("key".to_owned(), "3".to_owned())?,
+        CasOutcome::Current(None)
+    );
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    Ok(())
+}
+
+// contended_lock has several threads take turns holding a lock built on
+// set_if_absent and remove_if_equals, and checks no two hold it at once
+fn contended_lock<E: KvsEngine>(store: E) -> Result<()> {
+    let handles: Vec<_> = (0..4)
+        .map(|id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let owner = format!("owner-{}", id);
+                for _ in 0..25 {
+                    while !store
+                        .set_if_absent("lock".to_owned(), owner.clone())?
+                        .is_swapped()
+                    {
+                        thread::yield_now();
+                    }
+
+                    let count: u32 = store
+                        .get("count".to_owned())?
+                        .unwrap_or_default()
+                        .parse()
+                        .unwrap_or(0);
+                    store.set("count".to_owned(), (count + 1).to_string())?;
+
+                    assert!(store
+                        .remove_if_equals("lock".to_owned(), owner.clone())?
+                        .is_swapped());
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    assert_eq!(store.get("count".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(KvStore::open(temp_dir.path())?)?;
+    drop(temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    contended_lock(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(SledKvsEngine::open(temp_dir.path())?)?;
+    drop(temp_dir);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    contended_lock(SledKvsEngine::open_with_durability(
+        temp_dir.path(),
+        Durability::Never,
+    )?)
+}
+
+// expiring_keys checks that keys written with a ttl read as absent once it
+// has passed, also after the store is reopened
+fn expiring_keys<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl("long".to_owned(), "2".to_owned(), Duration::from_secs(600))?;
+    // a ttl too long to count in milliseconds does not wrap around
+    store.set_with_ttl("huge".to_owned(), "0".to_owned(), Duration::MAX)?;
+    store.set_with_ttl(
+        "made-permanent".to_owned(),
+        "3".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("made-permanent".to_owned(), "4".to_owned())?;
+    store.set("permanent".to_owned(), "5".to_owned())?;
+
+    let ttl = store.ttl("short".to_owned())?.expect("short should expire");
+    assert!(ttl > Duration::ZERO && ttl <= Duration::from_millis(200));
+    assert_eq!(store.ttl("made-permanent".to_owned())?, None);
+    assert_eq!(store.ttl("permanent".to_owned())?, None);
+    assert!(matches!(
+        store.ttl("missing".to_owned()),
+        Err(KVError::KeyNoExist)
+    ));
+    assert_eq!(store.get("short".to_owned())?, Some("1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get("short".to_owned())?, None);
+        assert!(matches!(
+            store.ttl("short".to_owned()),
+            Err(KVError::KeyNoExist)
+        ));
+        assert_eq!(store.get("long".to_owned())?, Some("2".to_owned()));
+        assert!(store.ttl("long".to_owned())?.unwrap() > Duration::from_secs(500));
+        assert_eq!(store.get("huge".to_owned())?, Some("0".to_owned()));
+        assert_eq!(
+            store.get("made-permanent".to_owned())?,
+            Some("4".to_owned())
+        );
+        assert_eq!(
+            keys(store.scan(.., None)?)?,
+            ["huge", "long", "made-permanent", "permanent"]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    assert!(matches!(
+        store.remove("short".to_owned()),
+        Err(KVError::KeyNoExist)
+    ));
+    assert_eq!(store.snapshot()?.get("short".to_owned())?, None);
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    check(&store)?;
+
+    // an expired key can be written again like a missing one
+    assert_eq!(
+        store.set_if_absent("short".to_owned(), "6".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(store.ttl("short".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn expiry() -> Result<()> {
+    expiring_keys(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_expiry() -> Result<()> {
+    expiring_keys(|path| SledKvsEngine::open(path))
+}
+
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // files that a compaction removes while they are listed are skipped
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+
+    // keys that are still live when a compaction starts are copied along,
+    // so the trigger is kept low enough for them to be compacted away again
+    // once they expire
+    let store = KvStoreOptions::new()
+        .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+        .sweep_interval(Duration::from_millis(50))
+        .open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id);
+        store.set_with_ttl(key, "x".repeat(256), Duration::from_millis(100))?;
+    }
+    store.set("permanent".to_owned(), "value".to_owned())?;
+    let written = dir_size();
+
+    // the sweeper writes tombstones for the expired keys, which lets the
+    // compaction drop them from the logs
+    let mut attempts = 0;
+    while dir_size() > written / 10 {
+        attempts += 1;
+        assert!(attempts < 100, "expired keys were never compacted away");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(keys(store.scan(.., None)?)?, ["permanent"]);
+    Ok(())
+}
+
+// binary_keys_and_values stores keys and values that are not UTF-8, reads
+// them back as bytes and checks that reading them as strings fails
+fn binary_keys_and_values<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    let image = (0..=255u8).cycle().take(4096).collect::<Vec<u8>>();
+    store.set_bytes(vec![0xff, 0x00], image.clone())?;
+    store.set_bytes(vec![0xff, 0xff, 0x01], vec![0x80])?;
+    store.set_bytes(b"text".to_vec(), vec![0xc3, 0x28])?;
+    store.set_bytes(vec![0x00], vec![])?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set_bytes(vec![0xfe], vec![0xfe])
+        .remove_bytes(vec![0x00]);
+    store.write_batch(batch)?;
+    assert_eq!(
+        store.compare_and_swap_bytes(vec![0xfe], Some(vec![0xfe]), Some(vec![0xfd]))?,
+        CasOutcome::Swapped
+    );
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get_bytes(vec![0xff, 0x00])?, Some(image.clone()));
+        assert_eq!(store.get_bytes(vec![0xfe])?, Some(vec![0xfd]));
+        assert_eq!(store.get_bytes(vec![0x00])?, None);
+        assert!(matches!(
+            store.get("text".to_owned()),
+            Err(KVError::InvalidUtf8)
+        ));
+
+        let pairs = store.scan_prefix_bytes(&[0xff], None)?;
+        let keys = pairs
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, [vec![0xff, 0x00], vec![0xff, 0xff, 0x01]]);
+        let pairs = store.scan_prefix_bytes(&[0xff, 0xff], None)?;
+        assert_eq!(pairs.count(), 1);
+        assert!(store.scan(.., None)?.any(|pair| pair.is_err()));
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    check(&store)?;
+
+    let snapshot = store.snapshot()?;
+    store.remove_bytes(vec![0xff, 0x00])?;
+    assert_eq!(store.get_bytes(vec![0xff, 0x00])?, None);
+    assert_eq!(snapshot.get_bytes(vec![0xff, 0x00])?, Some(image));
+
+    let mut tx = store.begin()?;
+    assert_eq!(tx.get_bytes(vec![0xfe])?, Some(vec![0xfd]));
+    tx.set_bytes(vec![0xfe], vec![0x00, 0xfe])?;
+    store.commit(tx)?;
+    assert_eq!(store.get_bytes(vec![0xfe])?, Some(vec![0x00, 0xfe]));
+
+    Ok(())
+}
+
+#[test]
+fn binary_data() -> Result<()> {
+    binary_keys_and_values(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_binary_data() -> Result<()> {
+    binary_keys_and_values(|path| SledKvsEngine::open(path))
+}
+
+// Longest keeps the longer of the value and the operand
+struct Longest;
+
+impl MergeOperator for Longest {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        match existing {
+            Some(existing) if existing.len() >= operand.len() => Ok(existing.to_vec()),
+            _ => Ok(operand.to_vec()),
+        }
+    }
+}
+
+// counters_and_merges checks counters and the built-in merge operators, along
+// with a "longest" operator that open has to register
+fn counters_and_merges<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    assert_eq!(store.incr("hits".to_owned(), 1)?, 1);
+    assert_eq!(store.incr("hits".to_owned(), 41)?, 42);
+    assert_eq!(store.decr("hits".to_owned(), 50)?, -8);
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KVError::Overflow)
+    ));
+    assert!(matches!(
+        store.decr("hits".to_owned(), i64::MIN),
+        Err(KVError::Overflow)
+    ));
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KVError::NotAnInteger)
+    ));
+    store.set_with_ttl(
+        "session".to_owned(),
+        "5".to_owned(),
+        Duration::from_secs(600),
+    )?;
+    assert_eq!(store.incr("session".to_owned(), 1)?, 6);
+
+    store.merge("log".to_owned(), "append", "a".to_owned())?;
+    store.merge("log".to_owned(), "append", "b".to_owned())?;
+    store.set("peak".to_owned(), "10".to_owned())?;
+    store.merge("peak".to_owned(), "max", "7".to_owned())?;
+    store.merge("peak".to_owned(), "max", "12".to_owned())?;
+    store.merge("tags".to_owned(), "set-union", "red\nblue".to_owned())?;
+    store.merge("tags".to_owned(), "set-union", "green\nred".to_owned())?;
+    store.merge("word".to_owned(), "longest", "kv".to_owned())?;
+    store.merge("word".to_owned(), "longest", "store".to_owned())?;
+    store.merge("word".to_owned(), "longest", "db".to_owned())?;
+    assert!(matches!(
+        store.merge("peak".to_owned(), "max", "high".to_owned()),
+        Err(KVError::NotAnInteger)
+    ));
+    assert!(matches!(
+        store.merge("log".to_owned(), "reverse", "c".to_owned()),
+        Err(KVError::UnknownMergeOperator { .. })
+    ));
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(store.get("hits".to_owned())?, Some("-8".to_owned()));
+        assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+        assert_eq!(store.get("log".to_owned())?, Some("ab".to_owned()));
+        assert_eq!(store.get("peak".to_owned())?, Some("12".to_owned()));
+        assert_eq!(
+            store.get("tags".to_owned())?,
+            Some("blue\ngreen\nred".to_owned())
+        );
+        assert_eq!(store.get("word".to_owned())?, Some("store".to_owned()));
+        // counters are plain values that last until they are written again
+        assert_eq!(store.ttl("session".to_owned())?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = open(temp_dir.path())?;
+    check(&store)?;
+
+    // merging onto a key that expired starts over from nothing
+    store.set_with_ttl(
+        "log".to_owned(),
+        "old".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.merge("log".to_owned(), "append", "x".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("log".to_owned())?, None);
+    store.merge("log".to_owned(), "append", "new".to_owned())?;
+    assert_eq!(store.get("log".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.ttl("log".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn merge() -> Result<()> {
+    counters_and_merges(|path| {
+        KvStoreOptions::new()
+            .merge_operator("longest", Longest)
+            .open(path)
+    })
+}
+
+#[test]
+fn sled_merge() -> Result<()> {
+    counters_and_merges(|path| {
+        Ok(SledKvsEngine::open(path)?.with_merge_operator("longest", Longest))
+    })
+}
+
+// Merge operands are kept in the log until a compaction folds them in, so
+// long runs of them have to survive compactions and reopening
+#[test]
+fn merge_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreOptions::new()
+            .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    for i in 0..2000 {
+        store.merge(format!("list{}", i % 10), "append", "x".to_owned())?;
+        store.incr("count".to_owned(), 1)?;
+        store.merge("peak".to_owned(), "max", i.to_string())?;
+        store.set(format!("filler{}", i % 10), "y".repeat(64))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..10 {
+            assert_eq!(store.get(format!("list{}", i))?, Some("x".repeat(200)));
+        }
+        assert_eq!(store.get("count".to_owned())?, Some("2000".to_owned()));
+        assert_eq!(store.get("peak".to_owned())?, Some("1999".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    let snapshot = store.snapshot()?;
+    compact_with_hint(&store, temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(snapshot.get("list0".to_owned())?, Some("x".repeat(200)));
+
+    drop(snapshot);
+    drop(store);
+    check(&open()?)
+}
+
+// watch_changes checks that watchers are told about every kind of write to
+// the keys they watch, in the order the writes were made
+fn watch_changes<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("config/old".to_owned(), "0".to_owned())?;
+
+    let mut key_watcher = store.watch(WatchFilter::Key(b"config/port".to_vec()))?;
+    let mut prefix_watcher = store.watch(WatchFilter::Prefix(b"config/".to_vec()))?;
+
+    store.set("config/port".to_owned(), "80".to_owned())?;
+    store.set("other".to_owned(), "1".to_owned())?;
+    store.remove("config/old".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("config/host".to_owned(), "localhost".to_owned())
+        .set("config/port".to_owned(), "8080".to_owned());
+    store.write_batch(batch)?;
+    store.set_if_equals("config/port".to_owned(), "80".to_owned(), "81".to_owned())?;
+    store.merge("config/host".to_owned(), "append", ":8080".to_owned())?;
+    store.transaction(|tx| tx.remove("config/port".to_owned()))?;
+    store.set_with_ttl(
+        "config/session".to_owned(),
+        "token".to_owned(),
+        Duration::from_millis(100),
+    )?;
+
+    let change = |key: &str, value: Option<&str>| {
+        (
+            key.as_bytes().to_vec(),
+            if value.is_some() {
+                ChangeOp::Set
+            } else {
+                ChangeOp::Remove
+            },
+            value.map(|value| value.as_bytes().to_vec()),
+        )
+    };
+    let take = |watcher: &mut Watcher, n: usize| -> Vec<Change> {
+        (0..n)
+            .map(|_| {
+                watcher
+                    .next_timeout(Duration::from_secs(5))
+                    .expect("missing change")
+            })
+            .collect()
+    };
+
+    let changes = take(&mut key_watcher, 3);
+    assert_eq!(
+        changes
+            .iter()
+            .map(|c| (c.key.clone(), c.op, c.value.clone()))
+            .collect::<Vec<_>>(),
+        [
+            change("config/port", Some("80")),
+            change("config/port", Some("8080")),
+            change("config/port", None),
+        ]
+    );
+
+    // the expired session is reported once the sweep removes it
+    let changes = take(&mut prefix_watcher, 8);
+    assert_eq!(
+        changes
+            .iter()
+            .map(|c| (c.key.clone(), c.op, c.value.clone()))
+            .collect::<Vec<_>>(),
+        [
+            change("config/port", Some("80")),
+            change("config/old", None),
+            change("config/host", Some("localhost")),
+            change("config/port", Some("8080")),
+            change("config/host", Some("localhost:8080")),
+            change("config/port", None),
+            change("config/session", Some("token")),
+            change("config/session", None),
+        ]
+    );
+    assert!(changes.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    // watchers end with the engine
+    drop(store);
+    assert_eq!(key_watcher.next(), None);
+    Ok(())
+}
+
+#[test]
+fn watch() -> Result<()> {
+    watch_changes(|path| {
+        KvStoreOptions::new()
+            .sweep_interval(Duration::from_millis(50))
+            .open(path)
+    })
+}
+
+#[test]
+fn sled_watch() -> Result<()> {
+    watch_changes(|path| SledKvsEngine::open(path))
+}
+
+// namespaces_are_isolated checks that namespaces keep their keys apart from
+// each other and from the default namespace, and survive reopening
+fn namespaces_are_isolated<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, Vec::<String>::new());
+
+    store.create_namespace("users")?;
+    store.create_namespace("orders")?;
+    assert!(matches!(
+        store.create_namespace("users"),
+        Err(KVError::NamespaceExists { .. })
+    ));
+    for name in ["", ".hidden", "a/b", &"x".repeat(65)] {
+        assert!(matches!(
+            store.create_namespace(name),
+            Err(KVError::InvalidNamespace { .. })
+        ));
+    }
+    assert!(matches!(
+        store.open_namespace("missing"),
+        Err(KVError::NoNamespace { .. })
+    ));
+    assert_eq!(store.namespaces()?, ["orders", "users"]);
+
+    let users = store.open_namespace("users")?;
+    let orders = store.open_namespace("orders")?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    orders.set("other".to_owned(), "order".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, None);
+    assert_eq!(
+        users.scan_prefix("", None)?.collect::<Result<Vec<_>>>()?,
+        [("key".to_owned(), "user".to_owned())]
+    );
+
+    // handles opened later see the same keys
+    store.open_namespace("users")?.remove("key".to_owned())?;
+    assert_eq!(users.get("key".to_owned())?, None);
+    users.set("key".to_owned(), "user".to_owned())?;
+
+    drop((users, orders, store));
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.namespaces()?, ["orders", "users"]);
+    let users = store.open_namespace("users")?;
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+
+    // dropping deletes the keys and turns away the handles still around
+    store.drop_namespace("users")?;
+    assert!(matches!(
+        users.get("key".to_owned()),
+        Err(KVError::NoNamespace { .. })
+    ));
+    assert!(matches!(
+        store.drop_namespace("users"),
+        Err(KVError::NoNamespace { .. })
+    ));
+    assert_eq!(store.namespaces()?, ["orders"]);
+    store.create_namespace("users")?;
+    assert_eq!(store.open_namespace("users")?.get("key".to_owned())?, None);
+    assert_eq!(
+        store.open_namespace("orders")?.get("other".to_owned())?,
+        Some("order".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn namespaces() -> Result<()> {
+    namespaces_are_isolated(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    namespaces_are_isolated(|path| SledKvsEngine::open(path))
+}
+
+// Every namespace of a KvStore has logs of its own, which are compacted
+// without touching those of the other namespaces
+#[test]
+fn compact_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreOptions::new()
+            .compaction_trigger(CompactionTrigger::StaleBytes(16 * 1024))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    store.create_namespace("busy")?;
+    let busy = store.open_namespace("busy")?;
+
+    let namespace_dir = temp_dir.path().join("namespaces").join("busy");
+    compact_with_hint(&busy, &namespace_dir)?;
+    assert_eq!(hint_files(temp_dir.path()).count(), 0);
+    let expected = busy.get("key500".to_owned())?;
+    assert!(expected.is_some());
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("key500".to_owned())?, None);
+
+    drop((busy, store));
+    let store = open()?;
+    let busy = store.open_namespace("busy")?;
+    assert_eq!(busy.get("key500".to_owned())?, expected);
+    assert_eq!(busy.get("key".to_owned())?, None);
+    Ok(())
+}
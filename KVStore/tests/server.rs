@@ -0,0 +1,1359 @@
+use kvs::{
+    async_client::AsyncClient,
+    async_server::AsyncServer,
+    client::Client,
+    common::{Bytes, GetResponse, IncrResponse, Protocol, Request, SetResponse, WireFormat},
+    frame::{self, Frame, Opcode},
+    server::{Server, ServerLimits, ShutdownHandle},
+    thread_pool::SharedQueueThreadPool,
+    AsyncKvsEngine, CasOutcome, ChangeOp, KVError, KvStore, KvsEngine, Result, ThreadPool,
+    WatchFilter,
+};
+use serde::Deserialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// TestServer runs a kvs server on a background thread until it is dropped
+struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    handle: Option<JoinHandle<()>>,
+    _temp_dir: TempDir,
+}
+
+impl TestServer {
+    fn start(addr: &str) -> Result<Self> {
+        Self::start_with_protocol(addr, Protocol::Kvs)
+    }
+
+    fn start_with_protocol(addr: &str, protocol: Protocol) -> Result<Self> {
+        Self::start_with(addr, protocol, ServerLimits::default())
+    }
+
+    fn start_with(addr: &str, protocol: Protocol, limits: ServerLimits) -> Result<Self> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr: SocketAddr = addr.parse()?;
+        let engine = KvStore::open(temp_dir.path())?;
+        let pool = SharedQueueThreadPool::new(4)?;
+        let killed = Arc::new(AtomicBool::new(false));
+
+        let mut server = Server::new(engine, addr, pool, killed)?
+            .with_protocol(protocol)
+            .with_limits(limits);
+        let shutdown = server.shutdown_handle()?;
+        let handle = thread::spawn(move || server.run().expect("unable to run the server"));
+
+        Ok(Self {
+            addr,
+            shutdown,
+            handle: Some(handle),
+            _temp_dir: temp_dir,
+        })
+    }
+
+    fn client(&self) -> Client {
+        Client::new(self.addr).expect("unable to connect to the server")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+#[test]
+fn remote_transaction() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4100")?;
+    server.client().set("a".to_owned(), "1".to_owned())?;
+
+    let mut client = server.client();
+    let tx = client.begin()?;
+    assert_eq!(client.tx_get(tx, "a".to_owned())?, Some("1".to_owned()));
+    client.tx_set(tx, "b".to_owned(), "2".to_owned())?;
+    client.tx_remove(tx, "a".to_owned())?;
+    assert_eq!(client.tx_get(tx, "b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(
+        server.client().get("b".to_owned())?,
+        "Error: Key not found!"
+    );
+    client.commit(tx)?;
+
+    assert_eq!(server.client().get("b".to_owned())?, "2");
+    assert_eq!(
+        server.client().get("a".to_owned())?,
+        "Error: Key not found!"
+    );
+
+    // a committed transaction is gone
+    assert!(client.commit(tx).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn remote_transaction_conflict_and_abort() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4101")?;
+    server.client().set("a".to_owned(), "1".to_owned())?;
+
+    let mut client = server.client();
+    let tx = client.begin()?;
+    client.tx_get(tx, "a".to_owned())?;
+    server.client().set("a".to_owned(), "2".to_owned())?;
+    client.tx_set(tx, "a".to_owned(), "3".to_owned())?;
+    assert!(matches!(
+        client.commit(tx),
+        Err(KVError::TransactionConflict)
+    ));
+    assert_eq!(server.client().get("a".to_owned())?, "2");
+
+    let tx = client.begin()?;
+    client.tx_set(tx, "a".to_owned(), "4".to_owned())?;
+    client.abort(tx)?;
+    assert!(client.tx_get(tx, "a".to_owned()).is_err());
+    assert_eq!(server.client().get("a".to_owned())?, "2");
+
+    // transactions belong to the connection that began them
+    let tx = client.begin()?;
+    client.tx_get(tx, "a".to_owned())?;
+    assert!(server
+        .client()
+        .tx_set(tx, "a".to_owned(), "5".to_owned())
+        .is_err());
+    assert!(server.client().commit(tx).is_err());
+    client.commit(tx)?;
+
+    Ok(())
+}
+
+#[test]
+fn remote_compare_and_swap() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4102")?;
+
+    assert_eq!(
+        server
+            .client()
+            .set_if_absent("a".to_owned(), "1".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        server
+            .client()
+            .set_if_absent("a".to_owned(), "2".to_owned())?,
+        CasOutcome::Current(Some("1".to_owned()))
+    );
+    assert_eq!(
+        server
+            .client()
+            .set_if_equals("a".to_owned(), "1".to_owned(), "2".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        server
+            .client()
+            .remove_if_equals("a".to_owned(), "1".to_owned())?,
+        CasOutcome::Current(Some("2".to_owned()))
+    );
+    assert_eq!(
+        server
+            .client()
+            .remove_if_equals("a".to_owned(), "2".to_owned())?,
+        CasOutcome::Swapped
+    );
+    assert_eq!(
+        server.client().get("a".to_owned())?,
+        "Error: Key not found!"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn remote_binary_data() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4103")?;
+
+    let value = (0..=255u8).rev().collect::<Vec<u8>>();
+    server.client().set_bytes(vec![0xff, 0x00], value.clone())?;
+    assert_eq!(server.client().get_bytes(vec![0xff, 0x00])?, Some(value));
+    assert_eq!(server.client().get_bytes(vec![0xfe])?, None);
+
+    server.client().remove_bytes(vec![0xff, 0x00])?;
+    assert_eq!(server.client().get_bytes(vec![0xff, 0x00])?, None);
+
+    Ok(())
+}
+
+// clients of older versions speak JSON, which the server still answers in
+#[test]
+fn legacy_json_client() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4104")?;
+    server.client().set("key".to_owned(), "value".to_owned())?;
+
+    let mut stream = TcpStream::connect(server.addr)?;
+    stream.write_all(br#"{"Get": {"key": "key"}}"#)?;
+    let mut response = serde_json::Deserializer::from_reader(stream);
+    assert_eq!(
+        serde_json::Value::deserialize(&mut response)?,
+        serde_json::json!({"Ok": "value"})
+    );
+
+    Ok(())
+}
+
+#[test]
+fn remote_counters_and_merges() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4105")?;
+
+    assert_eq!(server.client().incr("hits".to_owned(), 5)?, 5);
+    assert_eq!(server.client().incr("hits".to_owned(), -7)?, -2);
+    server
+        .client()
+        .set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        server.client().incr("max".to_owned(), 1),
+        Err(KVError::Overflow)
+    ));
+
+    server
+        .client()
+        .merge("log".to_owned(), "append", "a".to_owned())?;
+    server
+        .client()
+        .merge("log".to_owned(), "append", "b".to_owned())?;
+    assert_eq!(server.client().get("log".to_owned())?, "ab");
+    assert!(server
+        .client()
+        .merge("log".to_owned(), "reverse", "c".to_owned())
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn remote_watch() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4106")?;
+    let mut changes = server
+        .client()
+        .watch(WatchFilter::Prefix(b"config/".to_vec()))?;
+
+    server
+        .client()
+        .set("config/port".to_owned(), "80".to_owned())?;
+    server.client().set("other".to_owned(), "1".to_owned())?;
+    server.client().remove("config/port".to_owned())?;
+
+    let change = changes.next().expect("missing change")?;
+    assert_eq!(
+        (change.key, change.op, change.value),
+        (b"config/port".to_vec(), ChangeOp::Set, Some(b"80".to_vec()))
+    );
+    let removed = changes.next().expect("missing change")?;
+    assert_eq!(removed.op, ChangeOp::Remove);
+    assert!(removed.seq > change.seq);
+
+    Ok(())
+}
+
+#[test]
+fn remote_namespaces() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4107")?;
+    server.client().create_namespace("users".to_owned())?;
+    assert!(server
+        .client()
+        .create_namespace("users".to_owned())
+        .is_err());
+    assert_eq!(server.client().namespaces()?, ["users"]);
+
+    let users = || server.client().with_namespace("users");
+    server
+        .client()
+        .set("key".to_owned(), "default".to_owned())?;
+    users().set("key".to_owned(), "user".to_owned())?;
+    assert_eq!(users().get("key".to_owned())?, "user");
+    assert_eq!(server.client().get("key".to_owned())?, "default");
+    assert_eq!(users().incr("count".to_owned(), 2)?, 2);
+
+    // transactions commit to the namespace they were begun in
+    let mut client = users();
+    let tx = client.begin()?;
+    client.tx_set(tx, "txkey".to_owned(), "1".to_owned())?;
+    client.commit(tx)?;
+    assert_eq!(users().get("txkey".to_owned())?, "1");
+    assert_eq!(
+        server.client().get("txkey".to_owned())?,
+        KVError::KeyNoExist.to_string()
+    );
+
+    server.client().drop_namespace("users".to_owned())?;
+    assert!(users().get("key".to_owned()).is_err());
+    assert!(server
+        .client()
+        .with_namespace("missing")
+        .set("key".to_owned(), "value".to_owned())
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn persistent_connections() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4108")?;
+
+    // clients that hang up without a request leave the server running
+    drop(TcpStream::connect(server.addr)?);
+    TcpStream::connect(server.addr)?.shutdown(Shutdown::Write)?;
+
+    let mut client = server.client();
+    for i in 0..100 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+    assert_eq!(client.get("key42".to_owned())?, "42");
+    let tx = client.begin()?;
+    client.tx_set(tx, "key0".to_owned(), "tx".to_owned())?;
+    client.commit(tx)?;
+    assert_eq!(client.get("key0".to_owned())?, "tx");
+
+    // requests sent together are answered in order
+    let mut stream = TcpStream::connect(server.addr)?;
+    let mut requests = Vec::new();
+    for request in [
+        Request::Set {
+            key: Bytes::from("piped".to_owned()),
+            value: Bytes::from("1".to_owned()),
+            ttl_ms: None,
+        },
+        Request::Incr {
+            key: Bytes::from("piped".to_owned()),
+            delta: 2,
+        },
+        Request::Get {
+            key: Bytes::from("piped".to_owned()),
+        },
+    ] {
+        WireFormat::Cbor.encode(&mut requests, &request)?;
+    }
+    stream.write_all(&requests)?;
+    let mut responses = BufReader::new(stream);
+    assert!(matches!(
+        WireFormat::Cbor.decode(&mut responses)?,
+        SetResponse::Ok()
+    ));
+    assert!(matches!(
+        WireFormat::Cbor.decode(&mut responses)?,
+        IncrResponse::Ok(3)
+    ));
+    match WireFormat::Cbor.decode(&mut responses)? {
+        GetResponse::Ok(value) => assert_eq!(value, Some(Bytes::from("3".to_owned()))),
+        GetResponse::Err(e) => panic!("unexpected error {}", e),
+    }
+
+    // and so are JSON requests
+    let mut stream = TcpStream::connect(server.addr)?;
+    stream.write_all(b"{\"Get\": {\"key\": \"key1\"}}\n{\"Get\": {\"key\": \"key2\"}}\n")?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(stream).into_iter::<serde_json::Value>();
+    assert_eq!(responses.next().unwrap()?, serde_json::json!({"Ok": "1"}));
+    assert_eq!(responses.next().unwrap()?, serde_json::json!({"Ok": "2"}));
+
+    Ok(())
+}
+
+// request_frame encodes request into a frame of the binary protocol
+fn request_frame(id: u32, request: &Request) -> Result<Frame> {
+    let mut payload = Vec::new();
+    WireFormat::Cbor.encode(&mut payload, request)?;
+    Ok(Frame::new(Opcode::Request, id, payload))
+}
+
+// framed_connection opens a connection that offers protocol version
+// version, and returns it along with the server's answer
+fn framed_connection(addr: SocketAddr, version: u16) -> Result<(TcpStream, [u8; 6])> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&frame::MAGIC)?;
+    stream.write_all(&version.to_be_bytes())?;
+    let mut answer = [0; 6];
+    stream.read_exact(&mut answer)?;
+    Ok((stream, answer))
+}
+
+#[test]
+fn framed_protocol() -> Result<()> {
+    let server = TestServer::start("127.0.0.1:4109")?;
+
+    // newer clients are answered with the version the server speaks, and
+    // clients without any version it speaks are turned away
+    let (_, answer) = framed_connection(server.addr, 99)?;
+    assert_eq!(answer[..4], frame::MAGIC);
+    assert_eq!(u16::from_be_bytes([answer[4], answer[5]]), 1);
+    let (mut refused, answer) = framed_connection(server.addr, 0)?;
+    assert_eq!(u16::from_be_bytes([answer[4], answer[5]]), 0);
+    assert_eq!(refused.read(&mut [0; 1])?, 0);
+
+    let (stream, _) = framed_connection(server.addr, frame::PROTOCOL_VERSION)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    // pipelined requests are answered in order, under their own ids
+    let mut frames = Vec::new();
+    request_frame(
+        7,
+        &Request::Set {
+            key: Bytes(b"k\0ey".to_vec()),
+            value: Bytes(vec![0xff, 0x00]),
+            ttl_ms: None,
+        },
+    )?
+    .write(&mut frames)?;
+    request_frame(
+        8,
+        &Request::Get {
+            key: Bytes(b"k\0ey".to_vec()),
+        },
+    )?
+    .write(&mut frames)?;
+    writer.write_all(&frames)?;
+
+    let set = Frame::read(&mut reader)?.expect("missing response");
+    assert_eq!((set.opcode, set.id), (Opcode::Response, 7));
+    assert!(matches!(
+        WireFormat::Cbor.decode(&set.payload[..])?,
+        SetResponse::Ok()
+    ));
+    let get = Frame::read(&mut reader)?.expect("missing response");
+    assert_eq!((get.opcode, get.id), (Opcode::Response, 8));
+    match WireFormat::Cbor.decode(&get.payload[..])? {
+        GetResponse::Ok(value) => assert_eq!(value, Some(Bytes(vec![0xff, 0x00]))),
+        GetResponse::Err(e) => panic!("unexpected error {}", e),
+    }
+
+    // frames that are not requests get an error frame, and the connection
+    // stays usable
+    Frame::new(Opcode::Unknown(0x7f), 9, b"?".to_vec()).write(&mut writer)?;
+    Frame::new(Opcode::Request, 10, vec![0xff]).write(&mut writer)?;
+    Frame::new(Opcode::Response, 11, Vec::new()).write(&mut writer)?;
+    for id in 9..=11 {
+        let error = Frame::read(&mut reader)?.expect("missing error frame");
+        assert_eq!((error.opcode, error.id), (Opcode::Error, id));
+        if id == 9 {
+            assert_eq!(error.payload, b"Error: unknown opcode 127");
+        }
+    }
+    request_frame(12, &Request::ListNamespaces)?.write(&mut writer)?;
+    let list = Frame::read(&mut reader)?.expect("missing response");
+    assert_eq!((list.opcode, list.id), (Opcode::Response, 12));
+
+    Ok(())
+}
+
+// RespReply is a reply of a Redis server, as read by RespClient
+#[derive(Debug, PartialEq, Eq)]
+enum RespReply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespReply>),
+}
+
+fn bulk(value: &str) -> RespReply {
+    RespReply::Bulk(Some(value.as_bytes().to_vec()))
+}
+
+// RespClient is just enough of a Redis client to test the server with
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: SocketAddr) -> Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    fn send(&mut self, args: &[&[u8]]) -> Result<()> {
+        let mut command = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            command.extend(format!("${}\r\n", arg.len()).as_bytes());
+            command.extend(*arg);
+            command.extend(b"\r\n");
+        }
+        self.writer.write_all(&command)?;
+        Ok(())
+    }
+
+    fn command(&mut self, args: &[&str]) -> Result<RespReply> {
+        let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+        self.send(&args)?;
+        self.reply()
+    }
+
+    fn reply(&mut self) -> Result<RespReply> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        Ok(match kind {
+            "+" => RespReply::Simple(rest.to_owned()),
+            "-" => RespReply::Error(rest.to_owned()),
+            ":" => RespReply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => RespReply::Bulk(None),
+                len => {
+                    let mut value = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut value)?;
+                    value.truncate(len as usize);
+                    RespReply::Bulk(Some(value))
+                }
+            },
+            "*" => RespReply::Array(
+                (0..rest.parse::<usize>().unwrap())
+                    .map(|_| self.reply())
+                    .collect::<Result<_>>()?,
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+}
+
+#[test]
+fn resp_protocol() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4110", Protocol::Resp)?;
+    let mut redis = RespClient::connect(server.addr)?;
+    let ok = || RespReply::Simple("OK".to_owned());
+
+    assert_eq!(
+        redis.command(&["PING"])?,
+        RespReply::Simple("PONG".to_owned())
+    );
+    assert_eq!(redis.command(&["ping", "hi"])?, bulk("hi"));
+    assert_eq!(redis.command(&["SET", "name", "kvs"])?, ok());
+    assert_eq!(redis.command(&["GET", "name"])?, bulk("kvs"));
+    assert_eq!(redis.command(&["GET", "missing"])?, RespReply::Bulk(None));
+
+    // conditional and expiring writes
+    assert_eq!(
+        redis.command(&["SET", "name", "other", "NX"])?,
+        RespReply::Bulk(None)
+    );
+    assert_eq!(
+        redis.command(&["SET", "missing", "x", "XX"])?,
+        RespReply::Bulk(None)
+    );
+    assert_eq!(redis.command(&["SET", "name", "redis", "XX"])?, ok());
+    assert_eq!(
+        redis.command(&["SET", "session", "1", "PX", "100000"])?,
+        ok()
+    );
+    assert_eq!(
+        redis.command(&["SET", "session", "1", "EX", "0"])?,
+        RespReply::Error("ERR invalid expire time in 'set' command".to_owned())
+    );
+    // expiry times past what milliseconds since the epoch can hold
+    for (unit, amount) in [("EX", "18446744073709552"), ("PX", "9223372036854775807")] {
+        assert_eq!(
+            redis.command(&["SET", "session", "1", unit, amount])?,
+            RespReply::Error("ERR invalid expire time in 'set' command".to_owned())
+        );
+    }
+
+    // binary values go through as they are
+    redis.send(&[b"SET", b"bin", &[0, 0xff, b'\r', b'\n']])?;
+    assert_eq!(redis.reply()?, ok());
+    assert_eq!(
+        redis.command(&["GET", "bin"])?,
+        RespReply::Bulk(Some(vec![0, 0xff, b'\r', b'\n']))
+    );
+
+    assert_eq!(redis.command(&["MSET", "a", "1", "b", "2"])?, ok());
+    assert_eq!(
+        redis.command(&["MGET", "a", "nope", "b"])?,
+        RespReply::Array(vec![bulk("1"), RespReply::Bulk(None), bulk("2")])
+    );
+    assert_eq!(
+        redis.command(&["EXISTS", "a", "b", "nope", "a"])?,
+        RespReply::Integer(3)
+    );
+    assert_eq!(
+        redis.command(&["DEL", "a", "nope", "b"])?,
+        RespReply::Integer(2)
+    );
+    assert_eq!(redis.command(&["EXISTS", "a"])?, RespReply::Integer(0));
+
+    match redis.command(&["INFO"])? {
+        RespReply::Bulk(Some(info)) => {
+            assert!(String::from_utf8(info).unwrap().contains("redis_version:"))
+        }
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    }
+
+    // errors are replied, and the connection stays usable
+    assert_eq!(
+        redis.command(&["GET"])?,
+        RespReply::Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+    assert_eq!(
+        redis.command(&["FLUSHALL", "ASYNC"])?,
+        RespReply::Error(
+            "ERR unknown command 'flushall', with args beginning with: 'ASYNC' ".to_owned()
+        )
+    );
+    assert_eq!(
+        redis.command(&["SET", "k", "v", "KEEPTTL"])?,
+        RespReply::Error("ERR syntax error".to_owned())
+    );
+
+    // inline commands, and pipelined ones
+    redis
+        .writer
+        .write_all(b"PING\r\nGET name\r\nEXISTS name\r\n")?;
+    assert_eq!(redis.reply()?, RespReply::Simple("PONG".to_owned()));
+    assert_eq!(redis.reply()?, bulk("redis"));
+    assert_eq!(redis.reply()?, RespReply::Integer(1));
+
+    assert_eq!(redis.command(&["QUIT"])?, ok());
+    Ok(())
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4111", Protocol::Resp)?;
+    let mut redis = RespClient::connect(server.addr)?;
+    for i in 0..25 {
+        redis.command(&["SET", &format!("user:{:02}", i), "x"])?;
+        redis.command(&["SET", &format!("order:{:02}", i), "x"])?;
+    }
+
+    // walk the cursor until it comes back to 0
+    let mut scan = |pattern: &str| -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = "0".to_owned();
+        loop {
+            match redis.command(&["SCAN", &cursor, "MATCH", pattern, "COUNT", "7"])? {
+                RespReply::Array(reply) => match &reply[..] {
+                    [RespReply::Bulk(Some(next)), RespReply::Array(found)] => {
+                        for key in found {
+                            match key {
+                                RespReply::Bulk(Some(key)) => {
+                                    keys.push(String::from_utf8(key.clone()).unwrap())
+                                }
+                                key => panic!("unexpected key {:?}", key),
+                            }
+                        }
+                        cursor = String::from_utf8(next.clone()).unwrap();
+                    }
+                    reply => panic!("unexpected SCAN reply {:?}", reply),
+                },
+                reply => panic!("unexpected SCAN reply {:?}", reply),
+            }
+            if cursor == "0" {
+                return Ok(keys);
+            }
+        }
+    };
+
+    let users = scan("user:*")?;
+    assert_eq!(users.len(), 25);
+    assert!(users.iter().all(|key| key.starts_with("user:")));
+    assert_eq!(scan("*:1?")?.len(), 20);
+    assert_eq!(scan("order:[0-1][^0-8]")?, ["order:09", "order:19"]);
+    assert_eq!(scan("*")?.len(), 50);
+
+    // a pattern with many stars must not take exponential time
+    let mut other = RespClient::connect(server.addr)?;
+    let long = "a".repeat(100);
+    other.command(&["SET", &long, "x"])?;
+    assert_eq!(
+        scan(&format!("{}b", "*a".repeat(20)))?,
+        Vec::<String>::new()
+    );
+    assert_eq!(scan(&"*a".repeat(20))?, [long]);
+
+    // keys removed while walking do not make the walk skip others
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        match redis.command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "5"])? {
+            RespReply::Array(reply) => match &reply[..] {
+                [RespReply::Bulk(Some(next)), RespReply::Array(found)] => {
+                    for key in found {
+                        if let RespReply::Bulk(Some(key)) = key {
+                            let key = String::from_utf8(key.clone()).unwrap();
+                            other.command(&["DEL", &key])?;
+                            keys.push(key);
+                        }
+                    }
+                    cursor = String::from_utf8(next.clone()).unwrap();
+                }
+                reply => panic!("unexpected SCAN reply {:?}", reply),
+            },
+            reply => panic!("unexpected SCAN reply {:?}", reply),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 25);
+
+    assert_eq!(
+        redis.command(&["SCAN", "x"])?,
+        RespReply::Error("ERR invalid cursor".to_owned())
+    );
+    Ok(())
+}
+
+// HttpResponse is a response of the HTTP gateway, as read by read_http
+struct HttpResponse {
+    status: u16,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("response body is not JSON")
+    }
+}
+
+fn read_http(reader: &mut impl BufRead) -> Result<HttpResponse> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(len) = line.strip_prefix("Content-Length: ") {
+            content_length = len.parse().unwrap();
+        }
+        headers.push(line);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+// http sends a request over a connection of its own
+fn http(addr: SocketAddr, method: &str, target: &str, body: &str) -> Result<HttpResponse> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )?;
+    read_http(&mut BufReader::new(stream))
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4112", Protocol::Http)?;
+    let addr = server.addr;
+
+    let health = http(addr, "GET", "/health", "")?;
+    assert_eq!(health.status, 200);
+    assert_eq!(health.json(), serde_json::json!({"status": "ok"}));
+
+    assert_eq!(
+        http(addr, "PUT", "/v1/keys/user%3A1", r#"{"value": "alice"}"#)?.status,
+        204
+    );
+    assert_eq!(
+        http(
+            addr,
+            "PUT",
+            "/v1/keys/user:2",
+            r#"{"value": "bob", "ttl_ms": 100000}"#
+        )?
+        .status,
+        204
+    );
+    http(addr, "PUT", "/v1/keys/other", r#"{"value": "x"}"#)?;
+    let get = http(addr, "GET", "/v1/keys/user:1", "")?;
+    assert_eq!(get.status, 200);
+    assert!(get
+        .headers
+        .contains(&"Content-Type: application/json".to_owned()));
+    assert_eq!(
+        get.json(),
+        serde_json::json!({"key": "user:1", "value": "alice"})
+    );
+
+    // binary keys are percent-encoded, and values that are not UTF-8 come
+    // back as arrays of bytes
+    http(addr, "PUT", "/v1/keys/%00%FF", r#"{"value": [0, 255]}"#)?;
+    assert_eq!(
+        http(addr, "GET", "/v1/keys/%00%ff", "")?.json()["value"],
+        serde_json::json!([0, 255])
+    );
+
+    let scan = http(addr, "GET", "/v1/keys?prefix=user%3A&limit=1", "")?;
+    assert_eq!(scan.status, 200);
+    assert_eq!(
+        scan.json(),
+        serde_json::json!({"pairs": [{"key": "user:1", "value": "alice"}]})
+    );
+    assert_eq!(
+        http(addr, "GET", "/v1/keys?prefix=user:", "")?.json()["pairs"]
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+
+    assert_eq!(http(addr, "DELETE", "/v1/keys/user:1", "")?.status, 204);
+    let missing = http(addr, "GET", "/v1/keys/user:1", "")?;
+    assert_eq!(missing.status, 404);
+    assert_eq!(
+        missing.json(),
+        serde_json::json!({"error": "Error: Key not found!"})
+    );
+    assert_eq!(http(addr, "DELETE", "/v1/keys/user:1", "")?.status, 404);
+
+    assert_eq!(http(addr, "PUT", "/v1/keys/k", "not json")?.status, 400);
+    assert_eq!(http(addr, "GET", "/v1/keys?limit=many", "")?.status, 400);
+    assert_eq!(http(addr, "GET", "/v2/keys/k", "")?.status, 404);
+    let not_allowed = http(addr, "POST", "/v1/keys/k", "")?;
+    assert_eq!(not_allowed.status, 405);
+    assert!(not_allowed
+        .headers
+        .contains(&"Allow: GET, PUT, DELETE".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4113", Protocol::Http)?;
+    let mut stream = TcpStream::connect(server.addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // pipelined requests on one connection, the last of which closes it
+    stream.write_all(
+        b"PUT /v1/keys/a HTTP/1.1\r\nContent-Length: 13\r\n\r\n{\"value\":\"1\"}\
+          GET /v1/keys/a HTTP/1.1\r\n\r\n\
+          GET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )?;
+    assert_eq!(read_http(&mut reader)?.status, 204);
+    assert_eq!(read_http(&mut reader)?.json()["value"], "1");
+    let last = read_http(&mut reader)?;
+    assert_eq!(last.status, 200);
+    assert!(last.headers.contains(&"Connection: close".to_owned()));
+    assert_eq!(reader.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+// MemcacheClient is just enough of a memcached client to test the server
+// with
+struct MemcacheClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl MemcacheClient {
+    fn connect(addr: SocketAddr) -> Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    fn line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        Ok(line.trim_end_matches("\r\n").to_owned())
+    }
+
+    // command sends a command, and the data block of a storage command if
+    // it is given one, and returns the line it is answered with
+    fn command(&mut self, command: &str) -> Result<String> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        self.line()
+    }
+
+    // get returns the lines a get or gets is answered with, up to its END
+    fn get(&mut self, command: &str) -> Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        let mut lines = Vec::new();
+        loop {
+            match self.line()?.as_str() {
+                "END" => return Ok(lines),
+                line => lines.push(line.to_owned()),
+            }
+        }
+    }
+}
+
+#[test]
+fn memcached_protocol() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4114", Protocol::Memcached)?;
+    let mut memcache = MemcacheClient::connect(server.addr)?;
+
+    assert!(memcache.command("version")?.starts_with("VERSION "));
+    assert_eq!(memcache.command("set a 5 0 5\r\nhello")?, "STORED");
+    assert_eq!(memcache.get("get a")?, ["VALUE a 5 5", "hello"]);
+
+    assert_eq!(memcache.command("add a 0 0 1\r\nx")?, "NOT_STORED");
+    assert_eq!(memcache.command("add b 0 0 1\r\nx")?, "STORED");
+    assert_eq!(memcache.command("replace c 0 0 1\r\nx")?, "NOT_STORED");
+    assert_eq!(memcache.command("replace a 7 0 2\r\nhi")?, "STORED");
+    assert_eq!(
+        memcache.get("get a b c")?,
+        ["VALUE a 7 2", "hi", "VALUE b 0 1", "x"]
+    );
+
+    // cas only stores while the item has the unique gets returned
+    let gets = memcache.get("gets a")?;
+    let unique = gets[0].strip_prefix("VALUE a 7 2 ").unwrap().to_owned();
+    assert_eq!(memcache.command("cas a 0 0 3 1\r\nnew")?, "EXISTS");
+    let cas = format!("cas a 0 0 3 {}\r\nnew", unique);
+    assert_eq!(memcache.command(&cas)?, "STORED");
+    assert_eq!(memcache.command(&cas)?, "EXISTS");
+    assert_eq!(memcache.command("cas z 0 0 1 1\r\nx")?, "NOT_FOUND");
+    assert_eq!(memcache.get("get a")?, ["VALUE a 0 3", "new"]);
+
+    assert_eq!(memcache.command("set n 3 0 2\r\n10")?, "STORED");
+    assert_eq!(memcache.command("incr n 5")?, "15");
+    assert_eq!(memcache.command("decr n 100")?, "0");
+    assert_eq!(
+        memcache.command("incr n 18446744073709551615")?,
+        "18446744073709551615"
+    );
+    assert_eq!(memcache.command("incr n 1")?, "0");
+    assert_eq!(memcache.get("get n")?, ["VALUE n 3 1", "0"]);
+    assert_eq!(
+        memcache.command("incr a 1")?,
+        "CLIENT_ERROR cannot increment or decrement non-numeric value"
+    );
+    assert_eq!(memcache.command("incr missing 1")?, "NOT_FOUND");
+
+    assert_eq!(memcache.command("delete b")?, "DELETED");
+    assert_eq!(memcache.command("delete b")?, "NOT_FOUND");
+
+    // replies to noreply commands are left out
+    assert_eq!(
+        memcache.get("set q 0 0 1 noreply\r\nq\r\nincr n 1 noreply\r\nget q n")?,
+        ["VALUE q 0 1", "q", "VALUE n 3 1", "1"]
+    );
+
+    assert_eq!(memcache.command("stats")?, "ERROR");
+    let long_key = "k".repeat(251);
+    assert_eq!(
+        memcache.command(&format!("get {}", long_key))?,
+        "CLIENT_ERROR bad command line format"
+    );
+
+    assert_eq!(memcache.command("flush_all")?, "OK");
+    assert!(memcache.get("get a n q")?.is_empty());
+
+    memcache.writer.write_all(b"quit\r\n")?;
+    assert_eq!(memcache.reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+#[test]
+fn memcached_expiry() -> Result<()> {
+    let server = TestServer::start_with_protocol("127.0.0.1:4115", Protocol::Memcached)?;
+    let mut memcache = MemcacheClient::connect(server.addr)?;
+
+    assert_eq!(memcache.command("set short 0 1 1\r\nx")?, "STORED");
+    assert_eq!(memcache.command("add also-short 0 1 1\r\ny")?, "STORED");
+    assert_eq!(memcache.command("set long 0 100 1\r\nz")?, "STORED");
+    assert_eq!(memcache.get("get short also-short")?.len(), 4);
+
+    // a negative exptime expires the item right away
+    assert_eq!(memcache.command("set gone 0 -1 1\r\nx")?, "STORED");
+    assert_eq!(memcache.command("add gone-too 0 -1 1\r\nx")?, "STORED");
+    assert!(memcache.get("get gone gone-too")?.is_empty());
+
+    // an exptime too far out to count in milliseconds never comes
+    let far = format!("set far 0 {} 1\r\nf", i64::MAX);
+    assert_eq!(memcache.command(&far)?, "STORED");
+    assert_eq!(memcache.get("get far")?, ["VALUE far 0 1", "f"]);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(
+        memcache.get("get short also-short long")?,
+        ["VALUE long 0 1", "z"]
+    );
+    // expired items can be added again
+    assert_eq!(memcache.command("add also-short 0 0 1\r\nw")?, "STORED");
+    assert_eq!(memcache.command("replace short 0 0 1\r\nw")?, "NOT_STORED");
+
+    // incr keeps the expiry of an item
+    assert_eq!(memcache.command("set counter 0 1 1\r\n1")?, "STORED");
+    assert_eq!(memcache.command("incr counter 1")?, "2");
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(memcache.command("incr counter 1")?, "NOT_FOUND");
+
+    // a data block of the wrong length cannot be followed
+    assert_eq!(
+        memcache.command("set k 0 0 1\r\nxyz")?,
+        "CLIENT_ERROR bad data chunk"
+    );
+    assert_eq!(memcache.reader.read(&mut [0; 1])?, 0);
+
+    // the data of an item that is too large is skipped, unless its length
+    // is too large to be real
+    let mut memcache = MemcacheClient::connect(server.addr)?;
+    let big = format!(
+        "set big 0 0 {}\r\n{}",
+        2 * 1024 * 1024,
+        "x".repeat(2 * 1024 * 1024)
+    );
+    assert_eq!(
+        memcache.command(&big)?,
+        "SERVER_ERROR object too large for cache"
+    );
+    assert_eq!(memcache.command("set k 0 0 1\r\nx")?, "STORED");
+    let huge = format!("set k 0 0 {}", usize::MAX);
+    assert_eq!(memcache.command(&huge)?, "CLIENT_ERROR bad data chunk");
+    assert_eq!(memcache.reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_and_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+    let server = AsyncServer::new(engine, "127.0.0.1:4116".parse()?).await?;
+    let addr = server.local_addr()?;
+    let running = tokio::spawn(async move { server.run().await });
+
+    let mut client = AsyncClient::new(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, "value1");
+    assert_eq!(client.incr("counter".to_owned(), 5).await?, 5);
+    assert_eq!(
+        client
+            .set_if_absent("key1".to_owned(), "other".to_owned())
+            .await?,
+        CasOutcome::Current(Some("value1".to_owned()))
+    );
+    client.remove("key1".to_owned()).await?;
+    assert!(client.get_bytes(b"key1".to_vec()).await?.is_none());
+
+    let tx = client.begin().await?;
+    client.tx_set(tx, "a".to_owned(), "1".to_owned()).await?;
+    client.commit(tx).await?;
+    assert_eq!(
+        client.scan_prefix("a".to_owned(), None, false).await?.len(),
+        1
+    );
+
+    client.create_namespace("tenant".to_owned()).await?;
+    let mut tenant = AsyncClient::new(addr).await?.with_namespace("tenant");
+    tenant.set("a".to_owned(), "2".to_owned()).await?;
+    assert_eq!(client.get("a".to_owned()).await?, "1");
+
+    // requests of many clients are served at once
+    let counting = (0..8).map(|_| {
+        tokio::spawn(async move {
+            let mut client = AsyncClient::new(addr).await?;
+            for _ in 0..10 {
+                client.incr("shared".to_owned(), 1).await?;
+            }
+            Ok::<_, KVError>(())
+        })
+    });
+    for counted in counting.collect::<Vec<_>>() {
+        counted.await.unwrap()?;
+    }
+    assert_eq!(client.incr("shared".to_owned(), 0).await?, 80);
+
+    // the blocking client speaks to it too
+    let blocking = tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut client = Client::new(addr)?;
+        client.set("sync".to_owned(), "yes".to_owned())?;
+        client.get("sync".to_owned())
+    });
+    assert_eq!(blocking.await.unwrap()?, "yes");
+
+    let mut watcher = AsyncClient::new(addr)
+        .await?
+        .watch(WatchFilter::Prefix(b"w".to_vec()))
+        .await?;
+    client.set("w1".to_owned(), "x".to_owned()).await?;
+    let change = watcher.next().await.unwrap()?;
+    assert_eq!((change.key, change.op), (b"w1".to_vec(), ChangeOp::Set));
+
+    running.abort();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_server_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::new(KvStore::open(temp_dir.path())?);
+    let limits = ServerLimits {
+        read_timeout: Duration::from_millis(500),
+        max_request_size: 1024,
+        ..ServerLimits::default()
+    };
+    let server = AsyncServer::new(engine, "127.0.0.1:4122".parse()?)
+        .await?
+        .with_limits(limits);
+    let addr = server.local_addr()?;
+    let running = tokio::spawn(async move { server.run().await });
+
+    let checked = tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut client = Client::new(addr)?;
+        let refused = client.set("big".to_owned(), "v".repeat(2048));
+        assert!(
+            matches!(refused, Err(KVError::String(message)) if message.contains("over the limit"))
+        );
+        client.set("small".to_owned(), "v".to_owned())?;
+        assert_eq!(client.get("small".to_owned())?, "v");
+
+        // the length of a frame whose rest never comes
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream.try_clone()?;
+        frame::client_handshake(&mut reader, &mut writer)?;
+        writer.write_all(&[0, 0])?;
+        let started = Instant::now();
+        assert_eq!(reader.read(&mut [0; 1])?, 0);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        Ok(())
+    });
+    checked.await.unwrap()?;
+
+    running.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_engine_on_a_thread_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = AsyncKvsEngine::with_pool(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+
+    engine.set("key".to_owned(), "value".to_owned()).await?;
+    assert_eq!(
+        engine.get("key".to_owned()).await?,
+        Some("value".to_owned())
+    );
+    assert_eq!(engine.incr_bytes(b"n".to_vec(), 3).await?, 3);
+    let len = engine
+        .run(|engine| Ok(engine.scan_prefix("", None)?.count()))
+        .await?;
+    assert_eq!(len, 2);
+
+    // a call that panics fails instead of leaving its caller waiting
+    let panicked = engine
+        .run(|_| -> Result<()> { panic!("engine call") })
+        .await;
+    assert!(matches!(panicked, Err(KVError::EngineCallFailed)));
+    Ok(())
+}
+
+#[test]
+fn shutdown_drains_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4117".parse()?;
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut server = Server::new(engine, addr, pool, Arc::new(AtomicBool::new(false)))?
+        .with_shutdown_grace(Duration::from_secs(10));
+    let shutdown = server.shutdown_handle()?;
+    let handle = thread::spawn(move || server.run());
+
+    let mut idle = Client::new(addr)?;
+    idle.set("key".to_owned(), "value".to_owned())?;
+
+    // the connection left open is closed instead of waited on for the
+    // whole grace period
+    let started = Instant::now();
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(idle.get("key".to_owned()).is_err());
+    assert!(Client::new(addr).is_err());
+
+    let engine = KvStore::open(temp_dir.path())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn busy_beyond_max_connections() -> Result<()> {
+    let limits = ServerLimits {
+        max_connections: 1,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::start_with("127.0.0.1:4118", Protocol::Kvs, limits)?;
+    let mut first = server.client();
+    first.set("key".to_owned(), "value".to_owned())?;
+    assert!(matches!(Client::new(server.addr), Err(KVError::ServerBusy)));
+
+    // the connection is given back once its client hangs up
+    drop(first);
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok(connected) = Client::new(server.addr) {
+            client = Some(connected);
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut client = client.expect("the connection limit was not freed");
+    assert_eq!(client.get("key".to_owned())?, "value");
+
+    // legacy clients are refused in the format of their request
+    let mut legacy = TcpStream::connect(server.addr)?;
+    legacy.write_all(br#"{"Get": {"key": "key"}}"#)?;
+    let mut response = serde_json::Deserializer::from_reader(&legacy);
+    assert_eq!(
+        serde_json::Value::deserialize(&mut response)?,
+        serde_json::json!({"Err": "Error: the server is busy, try again later"})
+    );
+
+    let server = TestServer::start_with("127.0.0.1:4119", Protocol::Resp, limits)?;
+    let _first = TcpStream::connect(server.addr)?;
+    let mut refused = BufReader::new(TcpStream::connect(server.addr)?);
+    let mut line = String::new();
+    refused.read_line(&mut line)?;
+    assert_eq!(line, "-ERR max number of clients reached\r\n");
+    Ok(())
+}
+
+#[test]
+fn oversized_request_is_refused() -> Result<()> {
+    let limits = ServerLimits {
+        max_request_size: 1024,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::start_with("127.0.0.1:4120", Protocol::Kvs, limits)?;
+    let mut client = server.client();
+
+    let refused = client.set("big".to_owned(), "v".repeat(2048));
+    assert!(matches!(refused, Err(KVError::String(message)) if message.contains("over the limit")));
+
+    // the request was skipped, so the connection goes on
+    client.set("small".to_owned(), "v".to_owned())?;
+    assert_eq!(client.get("small".to_owned())?, "v");
+    assert_eq!(client.get_bytes(b"big".to_vec())?, None);
+
+    // legacy requests cannot be skipped, so they end the connection after
+    // an error response
+    let mut legacy = TcpStream::connect(server.addr)?;
+    let big = format!(
+        r#"{{"Set": {{"key": "big", "value": "{}"}}}}"#,
+        "v".repeat(2048)
+    );
+    legacy.write_all(big.as_bytes())?;
+    let mut response = serde_json::Deserializer::from_reader(&legacy);
+    assert_eq!(
+        serde_json::Value::deserialize(&mut response)?,
+        serde_json::json!({"Err": "Error: request is over the limit of 1024 bytes"})
+    );
+    assert_eq!(legacy.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+#[test]
+fn scans_are_paged() -> Result<()> {
+    let limits = ServerLimits {
+        max_scan_page: 3,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::start_with("127.0.0.1:4124", Protocol::Kvs, limits)?;
+    let mut client = server.client();
+    for i in 0..10 {
+        client.set(format!("key{}", i), i.to_string())?;
+    }
+    client.set("other".to_owned(), "x".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    let all: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+
+    // the client resumes every partial page until the scan is done
+    let forward = client.scan(Some("key".to_owned()), Some("l".to_owned()), None, false)?;
+    assert_eq!(keys(forward), all);
+    let backward = keys(client.scan_prefix("key".to_owned(), None, true)?);
+    assert_eq!(backward, all.iter().rev().cloned().collect::<Vec<_>>());
+    assert_eq!(keys(client.scan(None, None, None, false)?).len(), 11);
+
+    // limits span pages
+    let limited = keys(client.scan_prefix("key".to_owned(), Some(7), false)?);
+    assert_eq!(limited, all[..7]);
+    let limited = keys(client.scan_prefix("key".to_owned(), Some(4), true)?);
+    assert_eq!(limited, ["key9", "key8", "key7", "key6"]);
+    Ok(())
+}
+
+#[test]
+fn watches_are_tracked() -> Result<()> {
+    let limits = ServerLimits {
+        max_connections: 1,
+        ..ServerLimits::default()
+    };
+    let server = TestServer::start_with("127.0.0.1:4123", Protocol::Kvs, limits)?;
+    let mut changes = server.client().watch(WatchFilter::Prefix(Vec::new()))?;
+
+    // the watch holds the only connection until it ends
+    assert!(matches!(Client::new(server.addr), Err(KVError::ServerBusy)));
+
+    // a shutdown closes it instead of waiting for it
+    let started = Instant::now();
+    drop(server);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(!matches!(changes.next(), Some(Ok(_))));
+    Ok(())
+}
+
+#[test]
+fn stalled_request_times_out() -> Result<()> {
+    let limits = ServerLimits {
+        read_timeout: Duration::from_millis(500),
+        ..ServerLimits::default()
+    };
+    let server = TestServer::start_with("127.0.0.1:4121", Protocol::Kvs, limits)?;
+    let stream = TcpStream::connect(server.addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream.try_clone()?;
+    frame::client_handshake(&mut reader, &mut writer)?;
+
+    // waiting between requests is left to the idle timeout
+    thread::sleep(Duration::from_secs(1));
+
+    // the length of a frame whose rest never comes
+    writer.write_all(&[0, 0])?;
+    let started = Instant::now();
+    assert_eq!(reader.read(&mut [0; 1])?, 0);
+    assert!(started.elapsed() < Duration::from_secs(3));
+    Ok(())
+}